@@ -0,0 +1,175 @@
+use crate::{ioerr, perm::FsOwner, xattr::Xattrs};
+use std::io;
+
+pub const ACL_ACCESS: &[u8] = b"system.posix_acl_access";
+pub const ACL_DEFAULT: &[u8] = b"system.posix_acl_default";
+
+// Layout of ACLs stored in xattrs, as the kernel passes them
+const ACL_VERSION: u32 = 2;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+#[derive(Clone, Copy, Debug)]
+struct AclEntry {
+	tag: u16,
+	perm: u16,
+	// User or group ID, for ACL_USER and ACL_GROUP
+	id: u32,
+}
+
+/// A POSIX access control list.
+#[derive(Clone, Debug)]
+pub struct Acl(Vec<AclEntry>);
+
+impl Acl {
+	/// Decode an ACL, checking that it holds the entries every ACL
+	/// needs.
+	pub fn parse(buf: &[u8]) -> io::Result<Self> {
+		let (header, body) = buf.split_at_checked(HEADER_LEN).ok_or(ioerr!(libc::EINVAL))?;
+		if u32::from_le_bytes(header.try_into().unwrap()) != ACL_VERSION
+			|| body.len() % ENTRY_LEN != 0
+		{
+			return Err(ioerr!(libc::EINVAL));
+		}
+		let entries: Vec<AclEntry> = body
+			.chunks_exact(ENTRY_LEN)
+			.map(|e| AclEntry {
+				tag: u16::from_le_bytes([e[0], e[1]]),
+				perm: u16::from_le_bytes([e[2], e[3]]) & 0o7,
+				id: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+			})
+			.collect();
+
+		let count = |tag| entries.iter().filter(|e| e.tag == tag).count();
+		let named = count(ACL_USER) + count(ACL_GROUP);
+		let valid = entries.iter().all(|e| {
+			matches!(e.tag, ACL_USER_OBJ | ACL_USER | ACL_GROUP_OBJ | ACL_GROUP | ACL_MASK | ACL_OTHER)
+		}) && count(ACL_USER_OBJ) == 1
+			&& count(ACL_GROUP_OBJ) == 1
+			&& count(ACL_OTHER) == 1
+			&& count(ACL_MASK) == (named > 0) as usize;
+		if !valid {
+			return Err(ioerr!(libc::EINVAL));
+		}
+		Ok(Self(entries))
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(HEADER_LEN + self.0.len() * ENTRY_LEN);
+		buf.extend_from_slice(&ACL_VERSION.to_le_bytes());
+		for e in &self.0 {
+			buf.extend_from_slice(&e.tag.to_le_bytes());
+			buf.extend_from_slice(&e.perm.to_le_bytes());
+			buf.extend_from_slice(&e.id.to_le_bytes());
+		}
+		buf
+	}
+
+	fn perm(&self, tag: u16) -> u32 {
+		self.0.iter().find(|e| e.tag == tag).map_or(0, |e| e.perm as u32)
+	}
+
+	/// Whether the ACL says no more than the permission bits do.
+	pub fn is_minimal(&self) -> bool {
+		self.0.len() == 3
+	}
+
+	/// Permission bits matching the ACL. The group bits show the
+	/// mask, if any.
+	pub fn mode(&self) -> u32 {
+		let group = match self.0.iter().any(|e| e.tag == ACL_MASK) {
+			true => self.perm(ACL_MASK),
+			false => self.perm(ACL_GROUP_OBJ),
+		};
+		(self.perm(ACL_USER_OBJ) << 6) | (group << 3) | self.perm(ACL_OTHER)
+	}
+
+	/// Bring the ACL in line with new permission bits.
+	pub fn chmod(&mut self, mode: u32) {
+		let has_mask = self.0.iter().any(|e| e.tag == ACL_MASK);
+		for e in &mut self.0 {
+			let shift = match e.tag {
+				ACL_USER_OBJ => 6,
+				ACL_MASK => 3,
+				ACL_GROUP_OBJ if !has_mask => 3,
+				ACL_OTHER => 0,
+				_ => continue,
+			};
+			e.perm = ((mode >> shift) & 0o7) as u16;
+		}
+	}
+
+	/// Apply this default ACL of a directory to a new inode created
+	/// in it with `mode`. Returns the inode's access ACL, unless its
+	/// permission bits are enough, along with the bits themselves.
+	pub fn inherit(&self, mode: u32) -> (Option<Self>, u32) {
+		let mut acl = self.clone();
+		let mut mode = mode;
+		let has_mask = acl.0.iter().any(|e| e.tag == ACL_MASK);
+		for e in &mut acl.0 {
+			let shift = match e.tag {
+				ACL_USER_OBJ => 6,
+				ACL_MASK => 3,
+				ACL_GROUP_OBJ if !has_mask => 3,
+				ACL_OTHER => 0,
+				_ => continue,
+			};
+			e.perm &= ((mode >> shift) & 0o7) as u16;
+			mode &= !(0o7 << shift) | ((e.perm as u32) << shift);
+		}
+		let minimal = acl.is_minimal();
+		((!minimal).then_some(acl), mode)
+	}
+
+	/// Check the access in `mask` (`R_OK`, `W_OK`, `X_OK`) for a
+	/// user, following the POSIX algorithm: the owner entry, then
+	/// named users, then the owning and named groups, then others.
+	pub fn permits(&self, owner: FsOwner, uid: u32, gid: u32, mask: u32) -> bool {
+		let granted = |perm: u32| perm & mask == mask;
+		if uid == owner.uid {
+			return granted(self.perm(ACL_USER_OBJ));
+		}
+		let limit = match self.0.iter().any(|e| e.tag == ACL_MASK) {
+			true => self.perm(ACL_MASK),
+			false => 0o7,
+		};
+		if let Some(e) = self.0.iter().find(|e| e.tag == ACL_USER && e.id == uid) {
+			return granted(e.perm as u32 & limit);
+		}
+		let groups: Vec<u32> = self
+			.0
+			.iter()
+			.filter(|e| {
+				(e.tag == ACL_GROUP_OBJ && gid == owner.gid) || (e.tag == ACL_GROUP && e.id == gid)
+			})
+			.map(|e| e.perm as u32 & limit)
+			.collect();
+		if !groups.is_empty() {
+			return groups.into_iter().any(granted);
+		}
+		granted(self.perm(ACL_OTHER))
+	}
+
+	/// The ACL of the given kind (`ACL_ACCESS` or `ACL_DEFAULT`)
+	/// stored in `xattrs`, if any.
+	pub fn get(xattrs: &Xattrs, kind: &[u8]) -> io::Result<Option<Self>> {
+		xattrs.get(kind).ok().map(Self::parse).transpose()
+	}
+
+	/// The minimal ACL equivalent to permission bits.
+	pub fn from_mode(mode: u32) -> Self {
+		let entry = |tag, shift: u32| AclEntry {
+			tag,
+			perm: ((mode >> shift) & 0o7) as u16,
+			id: 0,
+		};
+		Self(vec![entry(ACL_USER_OBJ, 6), entry(ACL_GROUP_OBJ, 3), entry(ACL_OTHER, 0)])
+	}
+}
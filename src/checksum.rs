@@ -0,0 +1,58 @@
+/// Size of the blocks checksummed separately, so that a write only
+/// needs to rehash the blocks it touched.
+pub const SUM_BLOCK: usize = 4096;
+
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+const CRC32C_TABLE: [u32; 256] = {
+	let mut table = [0u32; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut j = 0;
+		while j < 8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+			j += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+	!data.iter().fold(!0u32, |crc, &b| {
+		CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+	})
+}
+
+/// Checksums of each block of a file's contents.
+#[derive(Clone, Debug, Default)]
+pub struct BlockSums(Vec<u32>);
+
+impl BlockSums {
+	fn blocks(data: &[u8], start: usize, end: usize) -> impl Iterator<Item = (usize, &[u8])> {
+		let end = end.min(data.len());
+		let first = start / SUM_BLOCK;
+		let last = end.div_ceil(SUM_BLOCK);
+		(first..last).map(move |i| {
+			let block = &data[i * SUM_BLOCK..((i + 1) * SUM_BLOCK).min(data.len())];
+			(i, block)
+		})
+	}
+
+	/// Recompute the checksums of the blocks of `data` overlapping
+	/// `start..end`, after those bytes changed or the file was
+	/// resized.
+	pub fn update(&mut self, data: &[u8], start: usize, end: usize) {
+		self.0.resize(data.len().div_ceil(SUM_BLOCK), 0);
+		for (i, block) in Self::blocks(data, start, end) {
+			self.0[i] = crc32c(block);
+		}
+	}
+
+	/// Check the blocks of `data` overlapping `start..end`.
+	pub fn verify(&self, data: &[u8], start: usize, end: usize) -> bool {
+		Self::blocks(data, start, end).all(|(i, block)| self.0.get(i) == Some(&crc32c(block)))
+	}
+}
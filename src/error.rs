@@ -6,6 +6,8 @@ use std::io;
 pub enum FsErr {
 	Transport(transport::Error),
 	Io(io::Error),
+	Opt(String),
+	Mountpoint(String),
 }
 
 impl From<transport::Error> for FsErr {
@@ -25,6 +27,8 @@ impl fmt::Display for FsErr {
 		match self {
 			Self::Transport(e) => write!(f, "Transport error: {}", e),
 			Self::Io(e) => write!(f, "I/O error: {}", e),
+			Self::Opt(e) => write!(f, "Invalid option: {}", e),
+			Self::Mountpoint(e) => write!(f, "Cannot mount on {}", e),
 		}
 	}
 }
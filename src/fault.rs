@@ -0,0 +1,53 @@
+//! Random failures of reads, writes and file creation, for testing how
+//! applications handle errors. Only built with the `fault-inject`
+//! feature.
+
+use crate::ioerr;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Fails a fraction of operations with a fixed errno. Failures are
+/// drawn from a seeded xorshift64* generator, so a given seed fails
+/// the same sequence of operations on every run, as long as they
+/// reach the filesystem in the same order.
+#[derive(Debug)]
+pub struct Faults {
+	// Fraction of operations to fail, between 0 and 1
+	rate: f64,
+	errno: i32,
+	state: AtomicU64,
+}
+
+impl Faults {
+	pub fn new(rate: f64, errno: i32, seed: u64) -> Self {
+		Self {
+			rate,
+			errno,
+			// The generator gets stuck at zero
+			state: AtomicU64::new(seed.max(1)),
+		}
+	}
+
+	fn next(&self) -> u64 {
+		let step = |mut x: u64| {
+			x ^= x >> 12;
+			x ^= x << 25;
+			x ^= x >> 27;
+			x
+		};
+		let prev = self.state
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x)))
+			.unwrap();
+		step(prev).wrapping_mul(0x2545f4914f6cdd1d)
+	}
+
+	/// Fail if this operation was picked to.
+	pub fn check(&self) -> io::Result<()> {
+		// Top 53 bits, as a uniform value in [0, 1)
+		let x = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+		if x < self.rate {
+			return Err(ioerr!(self.errno));
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,166 @@
+use crate::ioerr;
+use std::io;
+use std::sync::Arc;
+
+/// Files up to this size are stored inline, without a separate
+/// heap allocation.
+pub const INLINE_CAP: usize = 32;
+
+/// The contents of a regular file.
+#[derive(Clone, Debug)]
+pub enum FileData {
+	Inline { len: u8, buf: [u8; INLINE_CAP] },
+	Heap(Vec<u8>),
+	// Contents shared with other files with identical data.
+	// Copied to the heap on the first write.
+	Shared(Arc<[u8]>),
+}
+
+impl FileData {
+	pub const fn new() -> Self {
+		Self::Inline { len: 0, buf: [0; INLINE_CAP] }
+	}
+
+	pub fn len(&self) -> usize {
+		match self {
+			Self::Inline { len, .. } => *len as usize,
+			Self::Heap(v) => v.len(),
+			Self::Shared(d) => d.len(),
+		}
+	}
+
+	/// Bytes of memory used by the contents, with shared contents
+	/// split evenly among the files sharing them.
+	pub fn stored_len(&self) -> usize {
+		match self {
+			Self::Shared(d) => d.len().div_ceil(Arc::strong_count(d)),
+			_ => self.len(),
+		}
+	}
+
+	pub fn as_slice(&self) -> &[u8] {
+		match self {
+			Self::Inline { len, buf } => &buf[..*len as usize],
+			Self::Heap(v) => v,
+			Self::Shared(d) => d,
+		}
+	}
+
+	/// A copy of the contents that shares their storage, until either
+	/// side is written to. Contents on the heap are moved into shared
+	/// storage first.
+	pub fn share(&mut self) -> Self {
+		match self {
+			Self::Inline { .. } => self.clone(),
+			Self::Heap(v) => {
+				let shared: Arc<[u8]> = Arc::from(std::mem::take(v));
+				*self = Self::Shared(shared.clone());
+				Self::Shared(shared)
+			}
+			Self::Shared(d) => Self::Shared(d.clone()),
+		}
+	}
+
+	/// The contents behind an `Arc`, without copying them if they
+	/// already are.
+	pub fn to_arc(&self) -> Arc<[u8]> {
+		match self {
+			Self::Shared(d) => d.clone(),
+			_ => Arc::from(self.as_slice()),
+		}
+	}
+
+	fn copy_up(&mut self) -> io::Result<()> {
+		if let Self::Shared(d) = self {
+			let mut v = Vec::new();
+			v.try_reserve(d.len()).map_err(|_| ioerr!(OutOfMemory))?;
+			v.extend_from_slice(d);
+			*self = Self::Heap(v);
+		}
+		Ok(())
+	}
+
+	/// Make sure the file is at least `end` bytes long, zero-filling
+	/// any new space, and return its full contents.
+	pub fn try_grow(&mut self, end: usize) -> io::Result<&mut [u8]> {
+		// No allocation can be larger, so such files could never be
+		// stored; say so rather than report running out of memory
+		if end > isize::MAX as usize {
+			return Err(ioerr!(libc::EFBIG));
+		}
+		self.copy_up()?;
+		let len = self.len();
+		if end > len {
+			match self {
+				Self::Inline { len, buf } if end <= INLINE_CAP => {
+					buf[*len as usize..end].fill(0);
+					*len = end as u8;
+				}
+				Self::Inline { .. } => {
+					let mut v = Vec::new();
+					v.try_reserve(end).map_err(|_| ioerr!(OutOfMemory))?;
+					v.extend_from_slice(self.as_slice());
+					v.resize(end, 0);
+					*self = Self::Heap(v);
+				}
+				Self::Heap(v) => {
+					v.try_reserve(end - len).map_err(|_| ioerr!(OutOfMemory))?;
+					v.resize(end, 0);
+				}
+				Self::Shared(..) => unreachable!(),
+			}
+		}
+
+		Ok(match self {
+			Self::Inline { len, buf } => &mut buf[..*len as usize],
+			Self::Heap(v) => v,
+			Self::Shared(..) => unreachable!(),
+		})
+	}
+
+	/// Set the length of the contents, zero-filling any new space and
+	/// giving back memory no longer needed.
+	pub fn resize(&mut self, new_len: usize) -> io::Result<()> {
+		self.copy_up()?;
+		match self {
+			Self::Inline { len, buf } if new_len <= INLINE_CAP => {
+				let old_len = *len as usize;
+				if new_len > old_len {
+					buf[old_len..new_len].fill(0);
+				}
+				*len = new_len as u8;
+			}
+			Self::Heap(v) if new_len <= INLINE_CAP => {
+				let mut buf = [0; INLINE_CAP];
+				let kept = v.len().min(new_len);
+				buf[..kept].copy_from_slice(&v[..kept]);
+				*self = Self::Inline { len: new_len as u8, buf };
+			}
+			Self::Heap(v) if new_len < v.len() => {
+				v.truncate(new_len);
+				v.shrink_to_fit();
+			}
+			_ => {
+				self.try_grow(new_len)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl From<Vec<u8>> for FileData {
+	fn from(v: Vec<u8>) -> Self {
+		if v.len() > INLINE_CAP {
+			return Self::Heap(v);
+		}
+		let mut buf = [0; INLINE_CAP];
+		buf[..v.len()].copy_from_slice(&v);
+		Self::Inline { len: v.len() as u8, buf }
+	}
+}
+
+impl Default for FileData {
+	fn default() -> Self {
+		Self::new()
+	}
+}
@@ -1,8 +1,9 @@
-use crate::{ioerr, Inode};
+use crate::{file_data::FileData, ioerr, Inode};
 use std::io;
+use std::sync::Arc;
 
 #[repr(u32)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum FsType {
 	REG = libc::S_IFREG,
@@ -26,6 +27,9 @@ impl TryFrom<u32> for FsType {
 		match val & libc::S_IFMT {
 			m if m == Self::REG as u32 => Ok(Self::REG),
 			m if m == Self::DIR as u32 => Ok(Self::DIR),
+			m if m == Self::FIFO as u32 => Ok(Self::FIFO),
+			m if m == Self::SOCK as u32 => Ok(Self::SOCK),
+			m if m == Self::CHR as u32 => Ok(Self::CHR),
 			_ => {
 				log::error!("Unsupported file mode: {:o}", val & libc::S_IFMT);
 				Err(ioerr!(Unsupported))
@@ -34,10 +38,18 @@ impl TryFrom<u32> for FsType {
 	}
 }
 
+/// A directory entry: the child's inode and its name.
+pub(crate) type DirChild = (Inode, Arc<[u8]>);
+
 #[derive(Clone, Debug)]
 pub(crate) enum FsEntry {
-	File(Vec<u8>),
-	Dir(Vec<(Inode, Vec<u8>)>),
+	File(FileData),
+	Dir(Vec<DirChild>),
+	// Nodes with no contents, such as sockets or overlayfs
+	// whiteouts, which only need to exist at a path
+	Special(FsType),
+	// The target of a symbolic link
+	Symlink(Box<[u8]>),
 }
 
 impl FsEntry {
@@ -46,7 +58,7 @@ impl FsEntry {
 	}
 
 	pub fn file() -> Self {
-		Self::File(Vec::new())
+		Self::File(FileData::new())
 	}
 }
 
@@ -56,6 +68,7 @@ impl TryFrom<FsType> for FsEntry {
 		match mode {
 			FsType::REG => Ok(Self::file()),
 			FsType::DIR => Ok(Self::dir()),
+			FsType::FIFO | FsType::SOCK | FsType::CHR => Ok(Self::Special(mode)),
 			_ => Err(ioerr!(Unsupported)),
 		}
 	}
@@ -1,15 +1,15 @@
-use crate::ioerr;
+use crate::{file_data::FileData, ioerr};
 use std::io;
 use fuse_backend_rs::common::file_traits::FileReadWriteVolatile;
 use fuse_backend_rs::common::file_buf::FileVolatileSlice;
 
 pub struct FileWriter<'a> {
 	pos: usize,
-	data: &'a mut Vec<u8>,
+	data: &'a mut FileData,
 }
 
 impl<'a> FileWriter<'a> {
-	pub fn new(data: &'a mut Vec<u8>) -> Self {
+	pub fn new(data: &'a mut FileData) -> Self {
 		Self { pos: 0, data }
 	}
 }
@@ -30,33 +30,22 @@ impl FileReadWriteVolatile for FileWriter<'_> {
 			return Ok(0)
 		};
 
-		if end > self.data.capacity() {
-			self.data
-				.try_reserve(end)
-				.map_err(|_| ioerr!(OutOfMemory))?;
-		}
-
-		unsafe {
-			self.data
-				.as_mut_ptr()
-				.add(start)
-				.copy_from_nonoverlapping(
-					slice.as_ptr(),
-					slice.len()
-				);
-			self.data.set_len(end);
-		};
-		
+		let data = self.data.try_grow(end)?;
+		slice.as_volatile_slice().copy_to(&mut data[start..end]);
 		Ok(slice.len())
 	}
 
-	fn read_volatile(&mut self, _slice: FileVolatileSlice<'_>) -> io::Result<usize> {
-		todo!()
-	}
-	fn read_at_volatile(&mut self, _slice: FileVolatileSlice<'_>, _off: u64) -> io::Result<usize> {
-		todo!()
+	fn read_volatile(&mut self, slice: FileVolatileSlice<'_>) -> io::Result<usize> {
+		let res = self.read_at_volatile(slice, self.pos as u64);
+		if let Ok(n) = res {
+			self.pos += n;
+		}
+		res
 	}
 
+	fn read_at_volatile(&mut self, slice: FileVolatileSlice<'_>, off: u64) -> io::Result<usize> {
+		FileReader::new(self.data.as_slice()).read_at_volatile(slice, off)
+	}
 }
 
 pub struct FileReader<'a> {
@@ -94,11 +83,13 @@ impl FileReadWriteVolatile for FileReader<'_> {
 		Ok(data.len())
 	}
 
+	// Readers only borrow the contents, like a file opened read-only
+
 	fn write_volatile(&mut self, _slice: FileVolatileSlice<'_>) -> io::Result<usize> {
-		todo!()
+		Err(ioerr!(libc::EBADF))
 	}
 
 	fn write_at_volatile(&mut self, _slice: FileVolatileSlice<'_>, _off: u64) -> io::Result<usize> {
-		todo!();
+		Err(ioerr!(libc::EBADF))
 	}
 }
\ No newline at end of file
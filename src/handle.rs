@@ -0,0 +1,74 @@
+use crate::{ioerr, Inode};
+use slab::Slab;
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// An open file or directory.
+#[derive(Clone, Copy, Debug)]
+pub struct OpenFile {
+	pub ino: Inode,
+	pub dir: bool,
+}
+
+#[derive(Debug, Default)]
+struct Open {
+	files: Slab<OpenFile>,
+	// Number of handles on each inode that has any
+	per_inode: HashMap<Inode, usize>,
+}
+
+/// Open files, indexed by the handles given to the kernel.
+#[derive(Debug, Default)]
+pub struct Handles {
+	open: Mutex<Open>,
+	// Maximum number of handles open at once, if limited
+	max: Option<usize>,
+}
+
+impl Handles {
+	pub fn new(max: Option<usize>) -> Self {
+		Self {
+			open: Mutex::default(),
+			max,
+		}
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Open> {
+		self.open.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	// The kernel passes a handle of 0 when there is none, so hand
+	// out slab indices plus one
+	pub fn insert(&self, file: OpenFile) -> io::Result<u64> {
+		let mut open = self.lock();
+		if self.max.is_some_and(|max| open.files.len() >= max) {
+			return Err(ioerr!(libc::ENFILE));
+		}
+		*open.per_inode.entry(file.ino).or_default() += 1;
+		Ok(open.files.insert(file) as u64 + 1)
+	}
+
+	pub fn get(&self, handle: u64) -> io::Result<OpenFile> {
+		let idx = (handle as usize).checked_sub(1).ok_or(ioerr!(libc::EBADF))?;
+		self.lock().files.get(idx).copied().ok_or(ioerr!(libc::EBADF))
+	}
+
+	/// Whether any handle refers to `ino`.
+	pub fn is_open(&self, ino: Inode) -> bool {
+		self.lock().per_inode.contains_key(&ino)
+	}
+
+	pub fn remove(&self, handle: u64) -> io::Result<OpenFile> {
+		let idx = (handle as usize).checked_sub(1).ok_or(ioerr!(libc::EBADF))?;
+		let mut open = self.lock();
+		let file = open.files.try_remove(idx).ok_or(ioerr!(libc::EBADF))?;
+		if let Entry::Occupied(mut count) = open.per_inode.entry(file.ino) {
+			*count.get_mut() -= 1;
+			if *count.get() == 0 {
+				count.remove();
+			}
+		}
+		Ok(file)
+	}
+}
@@ -0,0 +1,193 @@
+//! The `.slabfs` directory in the root, if enabled, whose read-only
+//! files describe the running filesystem. Their contents are made up
+//! on every read. None of these nodes take a slot in the inode table:
+//! their inode numbers sit at the top of the range, far above any
+//! slot, and requests for them are answered from here.
+
+use crate::{FsFiles, Inode, SlabFsBuilder, IO_BLOCK, ST_DEV};
+use core::time::Duration;
+use fuse_backend_rs::abi::fuse_abi::stat64;
+use fuse_backend_rs::api::filesystem::{DirEntry, Entry};
+use std::fmt::Write;
+use std::io;
+
+/// Name of the directory in the root.
+pub const DIR_NAME: &[u8] = b".slabfs";
+
+// Inode number of the directory, followed by those of its files
+const BASE: u64 = u64::MAX - 3;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Node {
+	Dir,
+	// Inode counts, as printed by --stats
+	Stats,
+	// Settings the filesystem was built with
+	Config,
+	// Version and compiled-in features
+	Version,
+}
+
+const FILES: [Node; 3] = [Node::Stats, Node::Config, Node::Version];
+
+impl Node {
+	pub fn from_ino(ino: Inode) -> Option<Self> {
+		match u64::from(ino).checked_sub(BASE)? {
+			0 => Some(Self::Dir),
+			1 => Some(Self::Stats),
+			2 => Some(Self::Config),
+			3 => Some(Self::Version),
+			_ => None,
+		}
+	}
+
+	pub fn ino(self) -> Inode {
+		Inode::from(BASE + self as u64)
+	}
+
+	fn name(self) -> &'static [u8] {
+		match self {
+			Self::Dir => DIR_NAME,
+			Self::Stats => b"stats",
+			Self::Config => b"config",
+			Self::Version => b"version",
+		}
+	}
+
+	/// The node named `name` in the directory `parent`, if any.
+	pub fn lookup(parent: Inode, name: &[u8]) -> Option<Self> {
+		if parent == Inode::ROOT {
+			return (name == DIR_NAME).then_some(Self::Dir);
+		}
+		if Self::from_ino(parent) != Some(Self::Dir) {
+			return None;
+		}
+		FILES.into_iter().find(|node| node.name() == name)
+	}
+
+	/// Permission bits, which are the same for everyone.
+	pub fn perm(self) -> u32 {
+		match self {
+			Self::Dir => 0o555,
+			_ => 0o444,
+		}
+	}
+
+	pub fn stat64(self, size: usize) -> stat64 {
+		let mut stat: stat64 = unsafe { std::mem::zeroed() };
+		stat.st_dev = ST_DEV;
+		stat.st_ino = self.ino().into();
+		stat.st_mode = self.perm() | match self {
+			Self::Dir => libc::S_IFDIR,
+			_ => libc::S_IFREG,
+		};
+		stat.st_nlink = if self == Self::Dir { 2 } else { 1 };
+		stat.st_size = size as i64;
+		stat.st_blksize = IO_BLOCK as i64;
+		stat
+	}
+
+	/// Sizes change all the time, so the kernel must not cache them.
+	pub fn get_entry(self, size: usize) -> Entry {
+		Entry {
+			inode: self.ino().into(),
+			generation: 0,
+			attr: self.stat64(size),
+			attr_flags: 0,
+			attr_timeout: Duration::ZERO,
+			entry_timeout: Duration::ZERO,
+		}
+	}
+
+	/// What reading the file gives right now.
+	pub fn contents(self, files: &FsFiles, config: &str) -> Vec<u8> {
+		match self {
+			Self::Dir => Vec::new(),
+			Self::Stats => stats(files).into_bytes(),
+			Self::Config => config.as_bytes().to_vec(),
+			Self::Version => version().into_bytes(),
+		}
+	}
+}
+
+/// List the files of the directory from `offset` on.
+pub fn readdir(
+	offset: u64,
+	add_entry: &mut dyn FnMut(DirEntry<'_>) -> io::Result<usize>,
+) -> io::Result<()> {
+	for (i, node) in FILES.into_iter().enumerate().skip(offset as usize) {
+		let dir_entry = DirEntry {
+			ino: node.ino().into(),
+			offset: (i as u64) + 1,
+			type_: libc::DT_REG as u32,
+			name: node.name(),
+		};
+		if add_entry(dir_entry)? == 0 {
+			break;
+		}
+	}
+	Ok(())
+}
+
+fn stats(files: &FsFiles) -> String {
+	let stats = files.stats();
+	let mut out = String::new();
+	writeln!(out, "inodes: {}", files.used()).unwrap();
+	match files.max_inodes {
+		Some(max) => writeln!(out, "max_inodes: {}", max).unwrap(),
+		None => writeln!(out, "max_inodes: unlimited").unwrap(),
+	}
+	writeln!(out, "capacity: {}", stats.capacity).unwrap();
+	writeln!(out, "occupied: {}", stats.occupied).unwrap();
+	writeln!(out, "vacant: {}", stats.vacant).unwrap();
+	writeln!(out, "peak: {}", stats.peak).unwrap();
+	writeln!(out, "dedup_saved: {}", stats.dedup_saved).unwrap();
+	writeln!(out, "created: {}", stats.created).unwrap();
+	writeln!(out, "removed: {}", stats.removed).unwrap();
+	out
+}
+
+/// Describe the settings of `cfg`, for the `config` file.
+pub fn config(cfg: &SlabFsBuilder) -> String {
+	let limit = |n: Option<usize>| n.map_or("unlimited".to_string(), |n| n.to_string());
+	let mut out = String::new();
+	writeln!(out, "initial_inodes: {}", cfg.initial_inodes).unwrap();
+	writeln!(out, "max_inodes: {}", limit(cfg.max_inodes)).unwrap();
+	writeln!(out, "max_open_files: {}", limit(cfg.max_open_files)).unwrap();
+	writeln!(out, "name_cache_entries: {}", cfg.name_cache.unwrap_or(0)).unwrap();
+	writeln!(out, "intern_names: {}", cfg.intern_names).unwrap();
+	writeln!(out, "dedup: {}", cfg.dedup).unwrap();
+	writeln!(out, "constant_time_names: {}", cfg.constant_time_names).unwrap();
+	writeln!(out, "verify_reads: {}", cfg.verify_reads).unwrap();
+	writeln!(out, "splice: {}", cfg.splice).unwrap();
+	writeln!(out, "all_xattr_namespaces: {}", cfg.all_xattr_namespaces).unwrap();
+	match cfg.journal.as_deref() {
+		Some(path) => writeln!(out, "journal: {}", path.display()).unwrap(),
+		None => writeln!(out, "journal: none").unwrap(),
+	}
+	writeln!(out, "coalesce_writes: {}", cfg.coalesce_writes).unwrap();
+	#[cfg(feature = "fault-inject")]
+	match cfg.faults {
+		Some((rate, errno, seed)) => {
+			writeln!(out, "fault_inject: {},{},{}", rate, errno, seed).unwrap();
+		}
+		None => writeln!(out, "fault_inject: none").unwrap(),
+	}
+	out
+}
+
+fn version() -> String {
+	let features: Vec<&str> = [
+		("metrics", cfg!(feature = "metrics")),
+		("tokio", cfg!(feature = "tokio")),
+		("fault-inject", cfg!(feature = "fault-inject")),
+	]
+	.into_iter()
+	.filter_map(|(name, on)| on.then_some(name))
+	.collect();
+	format!(
+		"version: {}\nfeatures: {}\n",
+		env!("CARGO_PKG_VERSION"),
+		if features.is_empty() { "none".to_string() } else { features.join(" ") },
+	)
+}
@@ -1,13 +1,25 @@
-use crate::{ioerr, FsEntry, FsOwner, FsPerm, FsType, ST_DEV, TIMEOUT_SECS};
+use crate::{ioerr, acl::{Acl, ACL_ACCESS}, checksum::BlockSums, xattr::Xattrs, file_data::FileData, name::Name, file_entry::DirChild, FsEntry, FsOwner, FsPerm, FsType, IO_BLOCK, PATH_MAX, ST_BLOCK, ST_DEV, TIMEOUT_SECS};
 use fuse_backend_rs::api::filesystem::{Context, DirEntry, Entry};
 use fuse_backend_rs::abi::fuse_abi::{CreateIn, stat64};
-use std::ffi::{CStr, CString};
+use std::ffi::CStr;
 use std::io;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct Inode(u64);
 
+impl Inode {
+	/// The root directory. Slot 0 is reserved, as inode 0 is not
+	/// valid for FUSE.
+	pub const ROOT: Self = Self(1);
+
+	/// Slot 0 or the root, which live as long as the filesystem.
+	pub fn is_reserved(self) -> bool {
+		self.0 <= Self::ROOT.0
+	}
+}
+
 impl From<u64> for Inode {
 	fn from(v: u64) -> Self {
 		Self(v)
@@ -20,9 +32,13 @@ impl From<usize> for Inode {
 	}
 }
 
+// Inode numbers are slab indices. One that does not fit in a usize
+// (only possible on 32-bit targets) cannot name an inode, so map it
+// to an index no slab can hold, rather than truncating it to the
+// index of some other inode.
 impl From<Inode> for usize {
 	fn from(v: Inode) -> Self {
-		v.0 as usize
+		usize::try_from(v.0).unwrap_or(usize::MAX)
 	}
 }
 
@@ -37,59 +53,134 @@ pub struct InodeInfo {
 	// Use an atomic so refcount updates do not need the
 	// write lock
 	refs: AtomicU64,
-	// Store the name as a Vec instead of CString so that
-	// we can create a truly empty structure without allocating.
-	name: Vec<u8>,
+	// Not a CString, so that we can create a truly empty
+	// structure without allocating.
+	name: Name,
 	pub perm: FsPerm,
 	pub owner: FsOwner,
+	// The directory containing this inode. The root is its own parent.
+	pub parent: Inode,
 	entry: FsEntry,
+	// Set once removed from its parent directory
+	unlinked: bool,
+	// Checksums of the file contents, if reads are verified
+	sums: Option<Box<BlockSums>>,
+	pub xattrs: Xattrs,
+	// Tells apart inodes that reused the same number
+	pub generation: u64,
 }
 
 impl InodeInfo {
-	pub fn create(name: &CStr, ctx: &Context, args: CreateIn) -> io::Result<Self> {
+	pub fn create(
+		parent: Inode,
+		name: &CStr,
+		ctx: &Context,
+		args: CreateIn,
+	) -> io::Result<Self> {
 		let perm = FsPerm::try_from(args.mode)?;
 		let mode = FsType::try_from(args.mode)?;
 		let owner = FsOwner::new(ctx.uid, ctx.gid);
 		let entry = FsEntry::try_from(mode)?;
 		Ok(Self {
 			refs: 1.into(),
-			name: name.to_bytes().to_vec(),
+			name: Name::try_from(name)?,
 			perm,
 			owner,
+			parent,
 			entry,
+			unlinked: false,
+			sums: None,
+			xattrs: Xattrs::default(),
+			generation: 0,
+		})
+	}
+
+	pub fn symlink(
+		parent: Inode,
+		name: &CStr,
+		ctx: &Context,
+		target: &[u8],
+	) -> io::Result<Self> {
+		if target.len() >= PATH_MAX {
+			return Err(ioerr!(libc::ENAMETOOLONG));
+		}
+		Ok(Self {
+			refs: 1.into(),
+			name: Name::try_from(name)?,
+			perm: FsPerm::symlink(),
+			owner: FsOwner::new(ctx.uid, ctx.gid),
+			parent,
+			entry: FsEntry::Symlink(target.into()),
+			unlinked: false,
+			sums: None,
+			xattrs: Xattrs::default(),
+			generation: 0,
 		})
 	}
 
 	#[allow(dead_code)]
 	pub fn file(name: &str) -> io::Result<Self> {
-		let name = CString::new(name)?.into_bytes();
+		let name = Name::try_from(name)?;
 		Ok(Self {
 			refs: 1.into(),
 			name,
 			perm: FsPerm::file(),
 			owner: FsOwner::default(),
+			parent: Inode::ROOT,
 			entry: FsEntry::file(),
+			unlinked: false,
+			sums: None,
+			xattrs: Xattrs::default(),
+			generation: 0,
 		})
 	}
 
+	#[allow(dead_code)]
 	pub fn dir(name: &str) -> io::Result<Self> {
-		let name = CString::new(name)?.into_bytes();
+		let name = Name::try_from(name)?;
 		Ok(Self {
 			refs: 1.into(),
 			name,
 			perm: FsPerm::dir(),
 			owner: FsOwner::default(),
+			parent: Inode::ROOT,
 			entry: FsEntry::dir(),
+			unlinked: false,
+			sums: None,
+			xattrs: Xattrs::default(),
+			generation: 0,
 		})
 	}
 
+	/// The root directory. It is never listed as a child, so it
+	/// needs no name.
+	pub fn root() -> Self {
+		Self {
+			refs: 1.into(),
+			name: Name::empty(),
+			perm: FsPerm::dir(),
+			owner: FsOwner::default(),
+			parent: Inode::ROOT,
+			entry: FsEntry::dir(),
+			unlinked: false,
+			sums: None,
+			xattrs: Xattrs::default(),
+			generation: 0,
+		}
+	}
+
 	pub fn empty() -> Self {
 		Self {
 			refs: 0.into(),
-			name: Vec::new(),
+			name: Name::empty(),
 			perm: FsPerm::file(),
 			owner: FsOwner::default(),
+			parent: Inode::ROOT,
 			entry: FsEntry::file(),
+			unlinked: false,
+			sums: None,
+			xattrs: Xattrs::default(),
+			generation: 0,
 		}
 	}
 
@@ -101,15 +192,53 @@ impl InodeInfo {
 		Ok(())
 	}
 
+	pub fn refs(&self) -> u64 {
+		self.refs.load(Ordering::Acquire)
+	}
+
+	/// Drop `count` references, returning whether none are left. A
+	/// kernel that forgets more than it looked up leaves none.
 	pub fn refsub(&self, count: u64) -> io::Result<bool> {
-		let r = self.refs.fetch_sub(count, Ordering::Release);
+		let r = self.refs
+			.fetch_update(Ordering::Release, Ordering::Acquire, |r| Some(r.saturating_sub(count)))
+			.unwrap_or_else(|r| r);
 		Ok(r <= count)
 	}
 
-	fn file_type(&self) -> FsType {
+	/// Share the storage of the name with `name`, the entry for this
+	/// inode in its directory.
+	pub fn share_name(&mut self, name: &Arc<[u8]>) {
+		self.name.share(name);
+	}
+
+	pub fn shared_name(&self) -> &Arc<[u8]> {
+		self.name.shared()
+	}
+
+	/// The same inode, but not yet looked up by the kernel.
+	pub fn without_refs(mut self) -> Self {
+		self.refs = AtomicU64::new(0);
+		self
+	}
+
+	pub fn unlink(&mut self) {
+		self.unlinked = true;
+	}
+
+	pub fn is_unlinked(&self) -> bool {
+		self.unlinked
+	}
+
+	pub fn name(&self) -> &[u8] {
+		self.name.as_bytes()
+	}
+
+	pub fn file_type(&self) -> FsType {
 		match self.entry {
 			FsEntry::File(..) => FsType::REG,
 			FsEntry::Dir(..) => FsType::DIR,
+			FsEntry::Special(t) => t,
+			FsEntry::Symlink(..) => FsType::LNK,
 		}
 	}
 
@@ -118,32 +247,49 @@ impl InodeInfo {
 	}
 
 	fn st_size(&self) -> i64 {
-		match &self.entry {
-			FsEntry::File(d) => d.len() as i64,
-			FsEntry::Dir(..) => 0i64,
-		}
+		let len = match &self.entry {
+			FsEntry::File(d) => d.len(),
+			FsEntry::Symlink(target) => target.len(),
+			FsEntry::Dir(..) | FsEntry::Special(..) => 0,
+		};
+		st_size(len)
 	}
 
 	fn st_blocks(&self) -> i64 {
-		self.st_size() / 512
+		match &self.entry {
+			FsEntry::File(d) => d.stored_len().div_ceil(ST_BLOCK) as i64,
+			FsEntry::Symlink(target) => target.len().div_ceil(ST_BLOCK) as i64,
+			FsEntry::Dir(..) | FsEntry::Special(..) => 0i64,
+		}
 	}
 
 	fn st_rdev(&self) -> u64 {
 		0
 	}
 
+	// Directories count their own "." entry besides the one in their
+	// parent. Subdirectories, whose ".." would add to the count, are
+	// not counted, so every directory has two links, as when empty.
+	fn nlink(&self) -> u64 {
+		match (self.unlinked, &self.entry) {
+			(true, _) => 0,
+			(false, FsEntry::Dir(..)) => 2,
+			(false, _) => 1,
+		}
+	}
+
 	#[inline(always)]
 	pub fn stat64(&self, ino: Inode) -> stat64 {
 		let mut stat: stat64 = unsafe { std::mem::zeroed() };
 		stat.st_dev = ST_DEV;
 		stat.st_ino = ino.into();
 		stat.st_mode = self.st_mode();
-		stat.st_nlink = 0;
+		stat.st_nlink = self.nlink();
 		stat.st_uid = self.owner.uid;
 		stat.st_gid = self.owner.gid;
 		stat.st_rdev = self.st_rdev();
 		stat.st_size = self.st_size();
-		stat.st_blksize = 16384;
+		stat.st_blksize = IO_BLOCK as i64;
 		stat.st_blocks = self.st_blocks();
 		stat.st_atime = 0;
 		stat.st_atime_nsec = 0;
@@ -158,7 +304,7 @@ impl InodeInfo {
 	pub fn get_entry(&self, ino: Inode) -> Entry {
 		Entry {
 			inode: ino.into(),
-			generation: 0,
+			generation: self.generation,
 			attr: self.stat64(ino),
 			attr_flags: 0,
 			attr_timeout: TIMEOUT_SECS,
@@ -166,43 +312,135 @@ impl InodeInfo {
 		}
 	}
 
-	pub fn get_direntry(&self, ino: Inode, off: u64) -> DirEntry {
+	pub fn get_direntry(&self, ino: Inode, off: u64) -> DirEntry<'_> {
+		debug_assert_ne!(ino, Inode::ROOT, "Root listed as a child");
 		DirEntry {
 			ino: ino.into(),
 			offset: off,
-			type_: 0,
-			name: &self.name,
+			// DT_* values are the file type bits of the mode
+			type_: (self.st_mode() & libc::S_IFMT) >> 12,
+			name: self.name.as_bytes(),
 		}
 	}
 
-	pub fn add_child(&mut self, ino: Inode, name: &CStr) -> io::Result<()> {
-		match &mut self.entry {
-			FsEntry::Dir(ref mut ch) => {
-				ch.push((ino, name.to_bytes().to_vec()));
-				Ok(())
-			},
-			_ => Err(ioerr!(NotFound)),
+	pub fn add_child(&mut self, ino: Inode, name: Arc<[u8]>) -> io::Result<()> {
+		self.children_mut()?.push((ino, name));
+		Ok(())
+	}
+
+	pub fn is_dir(&self) -> bool {
+		matches!(self.entry, FsEntry::Dir(..))
+	}
+
+	/// Whether a user may access the inode as asked in `mask` (`R_OK`,
+	/// `W_OK`, `X_OK`), going by its ACL if it has one and by its
+	/// permission bits otherwise.
+	pub fn permits(&self, uid: u32, gid: u32, mask: u32) -> io::Result<bool> {
+		let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32;
+		let mode = self.perm.bits();
+		if uid == 0 {
+			// Root may execute anything executable by someone
+			return Ok(mask & libc::X_OK as u32 == 0 || self.is_dir() || mode & 0o111 != 0);
 		}
+		Ok(Acl::get(&self.xattrs, ACL_ACCESS)?
+			.unwrap_or_else(|| Acl::from_mode(mode))
+			.permits(self.owner, uid, gid, mask))
 	}
 
-	pub fn children(&self) -> io::Result<&[(Inode, Vec<u8>)]> {
+	pub fn children(&self) -> io::Result<&[DirChild]> {
 		match &self.entry {
 			FsEntry::Dir(ch) => Ok(ch),
-			_ => Err(ioerr!(NotFound)),
+			_ => Err(ioerr!(libc::ENOTDIR)),
 		}
 	}
 
-	pub fn children_mut(&mut self) -> io::Result<&mut Vec<(Inode, Vec<u8>)>> {
+	pub fn children_mut(&mut self) -> io::Result<&mut Vec<DirChild>> {
 		match &mut self.entry {
 			FsEntry::Dir(ref mut ch) => Ok(ch),
-			_ => Err(ioerr!(NotFound)),
+			_ => Err(ioerr!(libc::ENOTDIR)),
+		}
+	}
+
+	/// Start keeping checksums of the contents of a regular file.
+	pub fn track_sums(&mut self) {
+		if let FsEntry::File(d) = &self.entry {
+			let mut sums = BlockSums::default();
+			sums.update(d.as_slice(), 0, d.len());
+			self.sums = Some(Box::new(sums));
+		}
+	}
+
+	/// Update the checksums of the file contents after bytes in
+	/// `start..end` changed.
+	pub fn update_sums(&mut self, start: usize, end: usize) {
+		if let (Some(sums), FsEntry::File(d)) = (self.sums.as_mut(), &self.entry) {
+			sums.update(d.as_slice(), start, end);
+		}
+	}
+
+	/// Check the file contents in `start..end` against their
+	/// checksums, if any.
+	pub fn verify_sums(&self, start: usize, end: usize) -> bool {
+		match (self.sums.as_ref(), &self.entry) {
+			(Some(sums), FsEntry::File(d)) => sums.verify(d.as_slice(), start, end),
+			_ => true,
+		}
+	}
+
+	pub fn symlink_target(&self) -> io::Result<&[u8]> {
+		match &self.entry {
+			FsEntry::Symlink(target) => Ok(target),
+			_ => Err(ioerr!(libc::EINVAL)),
+		}
+	}
+
+	pub fn file_contents(&self) -> io::Result<&[u8]> {
+		match &self.entry {
+			FsEntry::File(d) => Ok(d.as_slice()),
+			FsEntry::Dir(..) => Err(ioerr!(libc::EISDIR)),
+			FsEntry::Special(..) | FsEntry::Symlink(..) => Err(ioerr!(libc::EINVAL)),
+		}
+	}
+
+	/// The contents of a regular file or the target of a symbolic
+	/// link, sharing the storage of shared contents.
+	pub fn shared_contents(&self) -> Option<Arc<[u8]>> {
+		match &self.entry {
+			FsEntry::File(d) => Some(d.to_arc()),
+			FsEntry::Symlink(target) => Some(Arc::from(&target[..])),
+			FsEntry::Dir(..) | FsEntry::Special(..) => None,
 		}
 	}
 
-	pub fn file_data(&mut self) -> io::Result<&mut Vec<u8>> {
+	pub fn file_data(&mut self) -> io::Result<&mut FileData> {
 		match &mut self.entry {
 			FsEntry::File(ref mut d) => Ok(d),
-			_ => Err(ioerr!(NotFound)),
+			FsEntry::Dir(..) => Err(ioerr!(libc::EISDIR)),
+			FsEntry::Special(..) | FsEntry::Symlink(..) => Err(ioerr!(libc::EINVAL)),
+		}
+	}
+}
+
+/// Files cannot grow past isize::MAX, but should a larger length ever
+/// get here, report the largest size st_size can hold rather than a
+/// negative one.
+fn st_size(len: usize) -> i64 {
+	i64::try_from(len).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn huge_lengths_clamp_st_size() {
+		assert_eq!(st_size(0), 0);
+		assert_eq!(st_size(4096), 4096);
+		#[cfg(target_pointer_width = "64")]
+		{
+			assert_eq!(st_size(i64::MAX as usize), i64::MAX);
+			assert_eq!(st_size(i64::MAX as usize + 1), i64::MAX);
+			assert_eq!(st_size(usize::MAX), i64::MAX);
 		}
 	}
 }
@@ -0,0 +1,172 @@
+use fuse_backend_rs::api::filesystem::IoctlData;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+const SLABFS_IOC_MAGIC: u32 = b's' as u32;
+
+// Equivalent to the kernel's _IOR() macro
+const fn ior(nr: u32, size: usize) -> u32 {
+	(2 << 30) | ((size as u32) << 16) | (SLABFS_IOC_MAGIC << 8) | nr
+}
+
+pub const SLABFS_IOC_STATS: u32 = ior(1, core::mem::size_of::<FsStats>());
+pub const SLABFS_IOC_LATENCY: u32 = ior(2, core::mem::size_of::<LatencyStats>());
+pub const SLABFS_IOC_DUMP_TREE: u32 = ior(3, core::mem::size_of::<TreeDump>());
+
+/// Largest tree description returned by `SLABFS_IOC_DUMP_TREE`. The
+/// size of an ioctl argument must fit in 14 bits.
+pub const TREE_DUMP_MAX: usize = 16 * 1024 - 32;
+
+/// Filesystem stats, as reported by `SLABFS_IOC_STATS`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsStats {
+	pub capacity: u64,
+	pub occupied: u64,
+	pub vacant: u64,
+	pub peak: u64,
+	pub dedup_saved: u64,
+	// Inodes created and removed since startup
+	pub created: u64,
+	pub removed: u64,
+}
+
+/// Latency of an operation, as reported by `SLABFS_IOC_LATENCY`.
+/// Percentiles are approximate.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpLatency {
+	pub count: u64,
+	pub p50_ns: u64,
+	pub p90_ns: u64,
+	pub p99_ns: u64,
+	pub max_ns: u64,
+}
+
+/// Operation latencies, as reported by `SLABFS_IOC_LATENCY`. Only
+/// available if built with the `metrics` feature.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LatencyStats {
+	pub read: OpLatency,
+	pub write: OpLatency,
+	pub lookup: OpLatency,
+	pub readdir: OpLatency,
+}
+
+/// Directory tree under the inode the ioctl was issued on, as
+/// reported by `SLABFS_IOC_DUMP_TREE`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TreeDump {
+	// Length of the JSON text in `json`
+	pub len: u64,
+	// Nonzero if some entries did not fit
+	pub truncated: u64,
+	pub json: [u8; TREE_DUMP_MAX],
+}
+
+const _: () = assert!(core::mem::size_of::<TreeDump>() < 1 << 14);
+
+impl Default for TreeDump {
+	fn default() -> Self {
+		Self {
+			len: 0,
+			truncated: 0,
+			json: [0; TREE_DUMP_MAX],
+		}
+	}
+}
+
+/// Plain structures passed through ioctls.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` and have no padding, so that
+/// every byte of a value is initialised.
+pub(crate) unsafe trait IoctlStruct: Copy + Default {
+	fn as_bytes(&self) -> &[u8] {
+		let ptr = self as *const Self as *const u8;
+		// SAFETY: all bytes are initialised, as promised by the
+		// implementor.
+		unsafe { core::slice::from_raw_parts(ptr, core::mem::size_of::<Self>()) }
+	}
+}
+
+// All fields are u64s, or arrays of bytes that are a multiple of 8 long
+unsafe impl IoctlStruct for FsStats {}
+unsafe impl IoctlStruct for LatencyStats {}
+unsafe impl IoctlStruct for TreeDump {}
+
+const _: () = assert!(core::mem::size_of::<FsStats>() == 7 * 8);
+const _: () = assert!(core::mem::size_of::<LatencyStats>() == 4 * core::mem::size_of::<OpLatency>());
+const _: () = assert!(core::mem::size_of::<OpLatency>() == 5 * 8);
+const _: () = assert!(core::mem::size_of::<TreeDump>() == 2 * 8 + TREE_DUMP_MAX);
+
+thread_local! {
+	static REPLY: RefCell<Box<[u8]>> = RefCell::default();
+}
+
+/// Build an ioctl reply carrying `val`. `FileSystem::ioctl()` must
+/// return data borrowed from the filesystem, so each reply is copied
+/// into a buffer of its own that the thread keeps until its next
+/// reply.
+///
+/// # Safety
+///
+/// The returned data must not be used after the next call to
+/// `reply()` on the same thread.
+pub(crate) unsafe fn reply<'a, T: IoctlStruct>(val: &T, out_size: u32) -> io::Result<IoctlData<'a>> {
+	let bytes = val.as_bytes();
+	if (out_size as usize) < bytes.len() {
+		return Err(crate::ioerr!(libc::EINVAL));
+	}
+
+	let data = REPLY.with(|buf| {
+		let mut buf = buf.borrow_mut();
+		*buf = Box::from(bytes);
+		// SAFETY: the buffer is not touched until it is replaced by
+		// the next reply, which the caller promises to outlive this
+		// one.
+		unsafe { core::slice::from_raw_parts(buf.as_ptr(), buf.len()) }
+	});
+
+	Ok(IoctlData {
+		result: 0,
+		data: Some(data),
+	})
+}
+
+fn query<T: IoctlStruct>(path: &Path, cmd: u32) -> io::Result<T> {
+	let file = File::open(path)?;
+	let mut val = T::default();
+	let ret = unsafe { libc::ioctl(file.as_raw_fd(), cmd as _, &mut val as *mut T) };
+	if ret < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(val)
+}
+
+/// Query the stats of the slabfs instance mounted at or containing `path`.
+pub fn query_stats(path: &Path) -> io::Result<FsStats> {
+	query(path, SLABFS_IOC_STATS)
+}
+
+/// Query operation latencies. Fails with `ENOTTY` if the filesystem
+/// was built without the `metrics` feature.
+pub fn query_latency(path: &Path) -> io::Result<LatencyStats> {
+	query(path, SLABFS_IOC_LATENCY)
+}
+
+/// Describe the directory tree under `path` as JSON, without file
+/// contents. Returns false along with it if some entries were left
+/// out to keep it within `TREE_DUMP_MAX` bytes.
+pub fn query_tree(path: &Path) -> io::Result<(String, bool)> {
+	let dump: TreeDump = query(path, SLABFS_IOC_DUMP_TREE)?;
+	let json = dump.json.get(..dump.len as usize).ok_or(crate::ioerr!(InvalidData))?;
+	let json = String::from_utf8(json.to_vec()).map_err(|e| crate::ioerr!(InvalidData, e))?;
+	Ok((json, dump.truncated == 0))
+}
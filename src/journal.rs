@@ -0,0 +1,465 @@
+use crate::{ioerr, inode::InodeInfo, Inode};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+const MAGIC: &[u8; 8] = b"SLABJNL1";
+
+const TAG_EPOCH: u8 = 0;
+const TAG_CREATE: u8 = 1;
+const TAG_WRITE: u8 = 2;
+const TAG_SETATTR: u8 = 3;
+const TAG_UNLINK: u8 = 4;
+const TAG_SYMLINK: u8 = 5;
+const TAG_SETXATTR: u8 = 6;
+const TAG_REMOVEXATTR: u8 = 7;
+const TAG_GENERATION: u8 = 8;
+const TAG_REPLACE: u8 = 9;
+
+/// A single mutation of the filesystem. Inodes are numbered as they
+/// were when the record was written.
+#[derive(Debug)]
+pub enum Record {
+	// The filesystem was started again, after replaying every
+	// record before this one
+	Epoch,
+	Create {
+		parent: Inode,
+		ino: Inode,
+		mode: u32,
+		uid: u32,
+		gid: u32,
+		name: Vec<u8>,
+	},
+	Write {
+		ino: Inode,
+		offset: u64,
+		data: Vec<u8>,
+	},
+	Setattr {
+		ino: Inode,
+		valid: u32,
+		mode: u32,
+		uid: u32,
+		gid: u32,
+		size: u64,
+	},
+	Unlink {
+		parent: Inode,
+		name: Vec<u8>,
+	},
+	Symlink {
+		parent: Inode,
+		ino: Inode,
+		uid: u32,
+		gid: u32,
+		name: Vec<u8>,
+		target: Vec<u8>,
+	},
+	Setxattr {
+		ino: Inode,
+		name: Vec<u8>,
+		value: Vec<u8>,
+	},
+	Removexattr {
+		ino: Inode,
+		name: Vec<u8>,
+	},
+	// Inodes created from here on have at least this generation
+	Generation {
+		next: u64,
+	},
+	// The whole contents of a file were replaced at once
+	Replace {
+		ino: Inode,
+		data: Vec<u8>,
+	},
+}
+
+impl Record {
+	/// The record that creates `info` again.
+	pub fn create(parent: Inode, ino: Inode, name: &[u8], info: &InodeInfo) -> Self {
+		let (uid, gid) = (info.owner.uid, info.owner.gid);
+		let name = name.to_vec();
+		match info.symlink_target() {
+			Ok(target) => Self::Symlink {
+				parent,
+				ino,
+				uid,
+				gid,
+				name,
+				target: target.to_vec(),
+			},
+			Err(_) => Self::Create {
+				parent,
+				ino,
+				mode: info.st_mode(),
+				uid,
+				gid,
+				name,
+			},
+		}
+	}
+
+	/// The records that set the extended attributes of `info` again.
+	pub fn setxattrs(ino: Inode, info: &InodeInfo) -> impl Iterator<Item = Self> + '_ {
+		info.xattrs.iter().map(move |(name, value)| Self::Setxattr {
+			ino,
+			name: name.to_vec(),
+			value: value.to_vec(),
+		})
+	}
+
+	fn encode(&self, buf: &mut Vec<u8>) {
+		let ino = |buf: &mut Vec<u8>, ino: Inode| {
+			buf.extend_from_slice(&u64::from(ino).to_le_bytes());
+		};
+		let bytes = |buf: &mut Vec<u8>, b: &[u8]| {
+			buf.extend_from_slice(&(b.len() as u64).to_le_bytes());
+			buf.extend_from_slice(b);
+		};
+
+		match self {
+			Self::Epoch => buf.push(TAG_EPOCH),
+			Self::Create { parent, ino: child, mode, uid, gid, name } => {
+				buf.push(TAG_CREATE);
+				ino(buf, *parent);
+				ino(buf, *child);
+				buf.extend_from_slice(&mode.to_le_bytes());
+				buf.extend_from_slice(&uid.to_le_bytes());
+				buf.extend_from_slice(&gid.to_le_bytes());
+				bytes(buf, name);
+			}
+			Self::Write { ino: child, offset, data } => {
+				buf.push(TAG_WRITE);
+				ino(buf, *child);
+				buf.extend_from_slice(&offset.to_le_bytes());
+				bytes(buf, data);
+			}
+			Self::Setattr { ino: child, valid, mode, uid, gid, size } => {
+				buf.push(TAG_SETATTR);
+				ino(buf, *child);
+				buf.extend_from_slice(&valid.to_le_bytes());
+				buf.extend_from_slice(&mode.to_le_bytes());
+				buf.extend_from_slice(&uid.to_le_bytes());
+				buf.extend_from_slice(&gid.to_le_bytes());
+				buf.extend_from_slice(&size.to_le_bytes());
+			}
+			Self::Unlink { parent, name } => {
+				buf.push(TAG_UNLINK);
+				ino(buf, *parent);
+				bytes(buf, name);
+			}
+			Self::Symlink { parent, ino: child, uid, gid, name, target } => {
+				buf.push(TAG_SYMLINK);
+				ino(buf, *parent);
+				ino(buf, *child);
+				buf.extend_from_slice(&uid.to_le_bytes());
+				buf.extend_from_slice(&gid.to_le_bytes());
+				bytes(buf, name);
+				bytes(buf, target);
+			}
+			Self::Setxattr { ino: child, name, value } => {
+				buf.push(TAG_SETXATTR);
+				ino(buf, *child);
+				bytes(buf, name);
+				bytes(buf, value);
+			}
+			Self::Removexattr { ino: child, name } => {
+				buf.push(TAG_REMOVEXATTR);
+				ino(buf, *child);
+				bytes(buf, name);
+			}
+			Self::Generation { next } => {
+				buf.push(TAG_GENERATION);
+				buf.extend_from_slice(&next.to_le_bytes());
+			}
+			Self::Replace { ino: child, data } => {
+				buf.push(TAG_REPLACE);
+				ino(buf, *child);
+				bytes(buf, data);
+			}
+		}
+	}
+
+	/// Decode a record from the start of `buf`, returning it along
+	/// with its encoded length, or `None` if `buf` is cut short.
+	fn decode(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+		let mut dec = Decoder { buf, pos: 0 };
+		let Some(tag) = dec.take(1) else {
+			return Ok(None);
+		};
+		if tag[0] > TAG_REPLACE {
+			return Err(ioerr!(InvalidData, format!("bad journal record type {}", tag[0])));
+		}
+		Ok(dec.record(tag[0]).map(|rec| (rec, dec.pos)))
+	}
+}
+
+struct Decoder<'a> {
+	buf: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+	fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+		let end = self.pos.checked_add(n)?;
+		let b = self.buf.get(self.pos..end)?;
+		self.pos = end;
+		Some(b)
+	}
+
+	fn u32(&mut self) -> Option<u32> {
+		self.take(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+	}
+
+	fn u64(&mut self) -> Option<u64> {
+		self.take(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+	}
+
+	fn ino(&mut self) -> Option<Inode> {
+		self.u64().map(Inode::from)
+	}
+
+	fn bytes(&mut self) -> Option<Vec<u8>> {
+		let len = usize::try_from(self.u64()?).ok()?;
+		self.take(len).map(<[u8]>::to_vec)
+	}
+
+	fn record(&mut self, tag: u8) -> Option<Record> {
+		Some(match tag {
+			TAG_EPOCH => Record::Epoch,
+			TAG_CREATE => Record::Create {
+				parent: self.ino()?,
+				ino: self.ino()?,
+				mode: self.u32()?,
+				uid: self.u32()?,
+				gid: self.u32()?,
+				name: self.bytes()?,
+			},
+			TAG_WRITE => Record::Write {
+				ino: self.ino()?,
+				offset: self.u64()?,
+				data: self.bytes()?,
+			},
+			TAG_SETATTR => Record::Setattr {
+				ino: self.ino()?,
+				valid: self.u32()?,
+				mode: self.u32()?,
+				uid: self.u32()?,
+				gid: self.u32()?,
+				size: self.u64()?,
+			},
+			TAG_UNLINK => Record::Unlink {
+				parent: self.ino()?,
+				name: self.bytes()?,
+			},
+			TAG_SYMLINK => Record::Symlink {
+				parent: self.ino()?,
+				ino: self.ino()?,
+				uid: self.u32()?,
+				gid: self.u32()?,
+				name: self.bytes()?,
+				target: self.bytes()?,
+			},
+			TAG_SETXATTR => Record::Setxattr {
+				ino: self.ino()?,
+				name: self.bytes()?,
+				value: self.bytes()?,
+			},
+			TAG_REMOVEXATTR => Record::Removexattr {
+				ino: self.ino()?,
+				name: self.bytes()?,
+			},
+			TAG_GENERATION => Record::Generation { next: self.u64()? },
+			TAG_REPLACE => Record::Replace {
+				ino: self.ino()?,
+				data: self.bytes()?,
+			},
+			_ => unreachable!(),
+		})
+	}
+}
+
+// Largest write record built up from consecutive writes
+const COALESCE_MAX: usize = 1 << 20;
+
+/// Append-only log of the changes made to the filesystem, so that
+/// its contents can be rebuilt after a restart.
+#[derive(Debug)]
+pub struct Journal {
+	path: PathBuf,
+	file: Mutex<File>,
+	// Write record held back to be merged with the writes following
+	// it, if coalescing. Locked before `file`.
+	pending: Mutex<Option<Record>>,
+	coalesce: bool,
+}
+
+impl Journal {
+	/// Open the journal at `path`, creating it if needed, and return
+	/// the records already in it. A record cut short by a crash is
+	/// discarded. If `coalesce` is set, consecutive writes to a file
+	/// are merged into a single record, at the cost of not recording
+	/// them until the file is closed or synced, or another change is
+	/// made.
+	pub fn open(path: &Path, coalesce: bool) -> io::Result<(Self, Vec<Record>)> {
+		let mut file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)?;
+		let mut buf = Vec::new();
+		file.read_to_end(&mut buf)?;
+
+		let path = path.to_path_buf();
+		if buf.is_empty() {
+			file.write_all(MAGIC)?;
+			return Ok((Self::new(path, file, coalesce), Vec::new()));
+		}
+		if !buf.starts_with(MAGIC) {
+			return Err(ioerr!(InvalidData, "not a slabfs journal"));
+		}
+
+		let mut records = Vec::new();
+		let mut pos = MAGIC.len();
+		while pos < buf.len() {
+			match Record::decode(&buf[pos..])? {
+				Some((rec, len)) => {
+					records.push(rec);
+					pos += len;
+				}
+				None => {
+					log::warn!("Discarding incomplete journal record at offset {}", pos);
+					file.set_len(pos as u64)?;
+					break;
+				}
+			}
+		}
+		file.seek(SeekFrom::Start(pos as u64))?;
+
+		Ok((Self::new(path, file, coalesce), records))
+	}
+
+	fn new(path: PathBuf, file: File, coalesce: bool) -> Self {
+		Self {
+			path,
+			file: Mutex::new(file),
+			pending: Mutex::new(None),
+			coalesce,
+		}
+	}
+
+	/// Write out a record. Each record is written with a single call,
+	/// so it survives the process being killed, though not
+	/// necessarily a system crash.
+	fn write(&self, rec: &Record) -> io::Result<()> {
+		let mut buf = Vec::new();
+		rec.encode(&mut buf);
+
+		let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+		let pos = file.stream_position()?;
+		if let Err(e) = file.write_all(&buf) {
+			// Do not leave a partial record in front of later ones
+			file.set_len(pos)?;
+			file.seek(SeekFrom::Start(pos))?;
+			return Err(e);
+		}
+		Ok(())
+	}
+
+	/// Append a record. Writes may be held back to be coalesced;
+	/// any other record first writes out the held back one, so that
+	/// records stay in order.
+	pub fn append(&self, rec: &Record) -> io::Result<()> {
+		let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+		let Record::Write { ino, offset, data } = rec else {
+			self.write_pending(&mut pending)?;
+			return self.write(rec);
+		};
+		if !self.coalesce {
+			return self.write(rec);
+		}
+		if let Some(Record::Write { ino: p_ino, offset: p_offset, data: p_data }) = pending.as_mut() {
+			let follows = *p_ino == *ino && *p_offset + p_data.len() as u64 == *offset;
+			if follows && p_data.len() + data.len() <= COALESCE_MAX {
+				p_data.extend_from_slice(data);
+				return Ok(());
+			}
+		}
+		self.write_pending(&mut pending)?;
+		*pending = Some(Record::Write { ino: *ino, offset: *offset, data: data.clone() });
+		Ok(())
+	}
+
+	fn write_pending(&self, pending: &mut Option<Record>) -> io::Result<()> {
+		if let Some(rec) = pending.as_ref() {
+			self.write(rec)?;
+			*pending = None;
+		}
+		Ok(())
+	}
+
+	/// Write out any write held back to be coalesced.
+	pub fn flush(&self) -> io::Result<()> {
+		self.write_pending(&mut self.pending.lock().unwrap_or_else(PoisonError::into_inner))
+	}
+
+	/// Wait for the records appended so far to reach the disk.
+	pub fn sync(&self) -> io::Result<()> {
+		self.flush()?;
+		self.file.lock().unwrap_or_else(PoisonError::into_inner).sync_data()
+	}
+
+	/// Replace the journal with the records produced by `f`, which
+	/// are written to a new file first so that a crash leaves either
+	/// the old journal or the new one in place.
+	pub fn rewrite<F>(&self, f: F) -> io::Result<()>
+	where
+		F: FnOnce(&mut dyn FnMut(&Record) -> io::Result<()>) -> io::Result<()>,
+	{
+		// Held back writes are part of the current contents, which
+		// the new journal records anyway
+		let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+		let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+		let mut tmp_path = self.path.clone().into_os_string();
+		tmp_path.push(".tmp");
+
+		let res = (|| {
+			let mut out = BufWriter::new(File::create(&tmp_path)?);
+			out.write_all(MAGIC)?;
+			let mut buf = Vec::new();
+			f(&mut |rec| {
+				buf.clear();
+				rec.encode(&mut buf);
+				out.write_all(&buf)
+			})?;
+			let tmp = out.into_inner().map_err(|e| e.into_error())?;
+			tmp.sync_all()?;
+			std::fs::rename(&tmp_path, &self.path)?;
+			Ok(tmp)
+		})();
+
+		match res {
+			Ok(tmp) => {
+				*file = tmp;
+				*pending = None;
+				Ok(())
+			}
+			Err(e) => {
+				let _ = std::fs::remove_file(&tmp_path);
+				Err(e)
+			}
+		}
+	}
+}
+
+impl Drop for Journal {
+	fn drop(&mut self) {
+		if let Err(e) = self.flush() {
+			log::error!("Failed to append to journal: {}", e);
+		}
+	}
+}
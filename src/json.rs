@@ -0,0 +1,276 @@
+use crate::ioerr;
+use std::io;
+
+/// Append `s` to `out` as a JSON string literal.
+pub fn push_str(out: &mut String, s: &str) {
+	push_bytes(out, s.as_bytes());
+}
+
+/// Append `bytes` to `out` as a JSON string literal. Bytes that are
+/// not valid UTF-8 are escaped as the lone surrogates U+DC80 to
+/// U+DCFF, which valid text never holds, so that `parse()` gives
+/// them back as they were.
+pub fn push_bytes(out: &mut String, bytes: &[u8]) {
+	out.push('"');
+	for chunk in bytes.utf8_chunks() {
+		for c in chunk.valid().chars() {
+			match c {
+				'"' => out.push_str("\\\""),
+				'\\' => out.push_str("\\\\"),
+				'\n' => out.push_str("\\n"),
+				'\t' => out.push_str("\\t"),
+				c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+				c => out.push(c),
+			}
+		}
+		for b in chunk.invalid() {
+			out.push_str(&format!("\\u{:04x}", 0xdc00 | *b as u32));
+		}
+	}
+	out.push('"');
+}
+
+// Deepest nesting accepted by `parse()`
+const MAX_DEPTH: usize = 4096;
+
+/// A parsed JSON value. Only integer numbers are supported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+	Null,
+	Bool(bool),
+	Int(i64),
+	// Raw bytes, which may not be valid UTF-8
+	Str(Vec<u8>),
+	Array(Vec<Value>),
+	Object(Vec<(String, Value)>),
+}
+
+impl Value {
+	/// The member named `key`, if this is an object holding one.
+	pub fn get(&self, key: &str) -> Option<&Value> {
+		match self {
+			Self::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+			_ => None,
+		}
+	}
+
+	/// The string this holds, if it is valid UTF-8.
+	pub fn as_str(&self) -> Option<&str> {
+		self.as_bytes().and_then(|s| std::str::from_utf8(s).ok())
+	}
+
+	pub fn as_bytes(&self) -> Option<&[u8]> {
+		match self {
+			Self::Str(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	pub fn as_u64(&self) -> Option<u64> {
+		match self {
+			Self::Int(n) => u64::try_from(*n).ok(),
+			_ => None,
+		}
+	}
+
+	pub fn as_array(&self) -> Option<&[Value]> {
+		match self {
+			Self::Array(items) => Some(items),
+			_ => None,
+		}
+	}
+}
+
+/// Parse a complete JSON document.
+pub fn parse(text: &str) -> io::Result<Value> {
+	let mut p = Parser { buf: text.as_bytes(), pos: 0 };
+	let val = p.value(0)?;
+	p.skip_ws();
+	if p.pos != p.buf.len() {
+		return Err(p.err("trailing characters"));
+	}
+	Ok(val)
+}
+
+struct Parser<'a> {
+	buf: &'a [u8],
+	pos: usize,
+}
+
+impl Parser<'_> {
+	fn err(&self, msg: &str) -> io::Error {
+		ioerr!(InvalidData, format!("JSON error at byte {}: {}", self.pos, msg))
+	}
+
+	fn skip_ws(&mut self) {
+		while self.buf.get(self.pos).is_some_and(|c| c.is_ascii_whitespace()) {
+			self.pos += 1;
+		}
+	}
+
+	fn peek(&mut self) -> Option<u8> {
+		self.skip_ws();
+		self.buf.get(self.pos).copied()
+	}
+
+	fn expect(&mut self, c: u8) -> io::Result<()> {
+		if self.peek() != Some(c) {
+			return Err(self.err(&format!("expected '{}'", c as char)));
+		}
+		self.pos += 1;
+		Ok(())
+	}
+
+	fn literal(&mut self, lit: &str, val: Value) -> io::Result<Value> {
+		if !self.buf[self.pos..].starts_with(lit.as_bytes()) {
+			return Err(self.err("unexpected character"));
+		}
+		self.pos += lit.len();
+		Ok(val)
+	}
+
+	fn value(&mut self, depth: usize) -> io::Result<Value> {
+		if depth > MAX_DEPTH {
+			return Err(self.err("nested too deeply"));
+		}
+		match self.peek() {
+			Some(b'{') => self.object(depth),
+			Some(b'[') => self.array(depth),
+			Some(b'"') => self.string().map(Value::Str),
+			Some(b't') => self.literal("true", Value::Bool(true)),
+			Some(b'f') => self.literal("false", Value::Bool(false)),
+			Some(b'n') => self.literal("null", Value::Null),
+			Some(b'-' | b'0'..=b'9') => self.number(),
+			Some(_) => Err(self.err("unexpected character")),
+			None => Err(self.err("unexpected end of input")),
+		}
+	}
+
+	fn object(&mut self, depth: usize) -> io::Result<Value> {
+		self.expect(b'{')?;
+		let mut members = Vec::new();
+		if self.peek() == Some(b'}') {
+			self.pos += 1;
+			return Ok(Value::Object(members));
+		}
+		loop {
+			if self.peek() != Some(b'"') {
+				return Err(self.err("expected member name"));
+			}
+			let key = String::from_utf8(self.string()?).map_err(|_| self.err("bad member name"))?;
+			self.expect(b':')?;
+			members.push((key, self.value(depth + 1)?));
+			match self.peek() {
+				Some(b',') => self.pos += 1,
+				Some(b'}') => {
+					self.pos += 1;
+					return Ok(Value::Object(members));
+				}
+				_ => return Err(self.err("expected ',' or '}'")),
+			}
+		}
+	}
+
+	fn array(&mut self, depth: usize) -> io::Result<Value> {
+		self.expect(b'[')?;
+		let mut items = Vec::new();
+		if self.peek() == Some(b']') {
+			self.pos += 1;
+			return Ok(Value::Array(items));
+		}
+		loop {
+			items.push(self.value(depth + 1)?);
+			match self.peek() {
+				Some(b',') => self.pos += 1,
+				Some(b']') => {
+					self.pos += 1;
+					return Ok(Value::Array(items));
+				}
+				_ => return Err(self.err("expected ',' or ']'")),
+			}
+		}
+	}
+
+	fn number(&mut self) -> io::Result<Value> {
+		let start = self.pos;
+		if self.buf[self.pos] == b'-' {
+			self.pos += 1;
+		}
+		while self.buf.get(self.pos).is_some_and(u8::is_ascii_digit) {
+			self.pos += 1;
+		}
+		if matches!(self.buf.get(self.pos), Some(b'.' | b'e' | b'E')) {
+			return Err(self.err("only integers are supported"));
+		}
+		let text = std::str::from_utf8(&self.buf[start..self.pos]).unwrap();
+		text.parse().map(Value::Int).map_err(|_| self.err("bad number"))
+	}
+
+	fn hex4(&mut self) -> io::Result<u32> {
+		let digits = self.buf.get(self.pos..self.pos + 4).ok_or_else(|| self.err("bad escape"))?;
+		let n = std::str::from_utf8(digits)
+			.ok()
+			.and_then(|d| u32::from_str_radix(d, 16).ok())
+			.ok_or_else(|| self.err("bad escape"))?;
+		self.pos += 4;
+		Ok(n)
+	}
+
+	fn string(&mut self) -> io::Result<Vec<u8>> {
+		self.expect(b'"')?;
+		let mut out = Vec::new();
+		loop {
+			let Some(&c) = self.buf.get(self.pos) else {
+				return Err(self.err("unterminated string"));
+			};
+			self.pos += 1;
+			match c {
+				b'"' => break,
+				b'\\' => {
+					let Some(&e) = self.buf.get(self.pos) else {
+						return Err(self.err("unterminated string"));
+					};
+					self.pos += 1;
+					let ch = match e {
+						b'"' => '"',
+						b'\\' => '\\',
+						b'/' => '/',
+						b'b' => '\u{8}',
+						b'f' => '\u{c}',
+						b'n' => '\n',
+						b'r' => '\r',
+						b't' => '\t',
+						b'u' => {
+							let mut cp = self.hex4()?;
+							// Bytes that are not UTF-8, as escaped by
+							// push_bytes()
+							if (0xdc80..0xdd00).contains(&cp) {
+								out.push(cp as u8);
+								continue;
+							}
+							// Characters outside the BMP come as a
+							// surrogate pair
+							if (0xd800..0xdc00).contains(&cp)
+								&& self.buf[self.pos..].starts_with(b"\\u")
+							{
+								self.pos += 2;
+								let lo = self.hex4()?;
+								if !(0xdc00..0xe000).contains(&lo) {
+									return Err(self.err("bad surrogate pair"));
+								}
+								cp = 0x10000 + ((cp - 0xd800) << 10) + (lo - 0xdc00);
+							}
+							char::from_u32(cp).ok_or_else(|| self.err("bad escape"))?
+						}
+						_ => return Err(self.err("bad escape")),
+					};
+					let mut utf8 = [0u8; 4];
+					out.extend_from_slice(ch.encode_utf8(&mut utf8).as_bytes());
+				}
+				c if c < 0x20 => return Err(self.err("control character in string")),
+				c => out.push(c),
+			}
+		}
+		Ok(out)
+	}
+}
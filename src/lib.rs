@@ -0,0 +1,2194 @@
+mod acl;
+mod checksum;
+pub mod error;
+#[cfg(feature = "fault-inject")]
+mod fault;
+mod file_data;
+mod file_entry;
+mod file_io;
+mod handle;
+mod info;
+mod inode;
+pub mod ioctl;
+mod journal;
+pub mod json;
+mod metrics;
+mod name;
+mod name_cache;
+mod perm;
+#[cfg(feature = "tokio")]
+pub mod service;
+mod snapshot;
+#[cfg(test)]
+mod tests;
+mod tree;
+pub mod worker;
+mod xattr;
+
+use crate::{
+	acl::{Acl, ACL_ACCESS, ACL_DEFAULT},
+	file_data::{FileData, INLINE_CAP},
+	file_entry::{FsEntry, FsType},
+	file_io::{FileReader, FileWriter},
+	handle::{Handles, OpenFile},
+	info::Node,
+	inode::InodeInfo,
+	ioctl::{FsStats, TreeDump, SLABFS_IOC_DUMP_TREE, SLABFS_IOC_LATENCY, SLABFS_IOC_STATS, TREE_DUMP_MAX},
+	journal::{Journal, Record},
+	metrics::{Metrics, Op},
+	name_cache::NameCache,
+	perm::{FsOwner, FsPerm},
+};
+#[cfg(feature = "fault-inject")]
+use crate::fault::Faults;
+use core::time::Duration;
+use fastcmp::Compare;
+use fuse_backend_rs::abi::fuse_abi::{CreateIn, FsOptions, stat64, statvfs64};
+use fuse_backend_rs::api::filesystem::{
+	Context,
+	DirEntry,
+	Entry,
+	FileSystem,
+	GetxattrReply,
+	IoctlData,
+	ListxattrReply,
+	OpenOptions,
+	SetattrValid,
+	ZeroCopyReader,
+	ZeroCopyWriter,
+};
+use slab::Slab;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{CStr, CString};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+
+pub use crate::inode::Inode;
+pub use crate::snapshot::{SnapshotNode, TreeSnapshot};
+
+const ST_DEV: u64 = 666420;
+// Unit of st_blocks, also reported as the fundamental block size by
+// statfs, which counts blocks in it
+const ST_BLOCK: usize = 512;
+// Preferred I/O size, reported as st_blksize and f_bsize
+const IO_BLOCK: usize = 16384;
+const TIMEOUT_SECS: Duration = Duration::from_secs(10000);
+const DEFAULT_INITIAL_INODES: usize = 256;
+const UNLIMITED_INODES: u64 = u32::MAX as u64;
+// Reads up to this size skip the zero-copy machinery
+const SMALL_READ: usize = 16384;
+// Directories holding fewer than 1 / DIR_SLACK of the entries they
+// have room for give back the rest on compaction
+const DIR_SLACK: usize = 4;
+// Longest name of a directory entry
+const NAME_MAX: usize = 255;
+// Longest target of a symbolic link, including the terminating NUL
+const PATH_MAX: usize = 4096;
+
+#[macro_export]
+macro_rules! ioerr {
+	($k:ident) => {
+		std::io::Error::from(std::io::ErrorKind::$k)
+	};
+	($k:ident, $e:expr) => {
+		std::io::Error::new(std::io::ErrorKind::$k, $e)
+	};
+	($k:expr) => {
+		std::io::Error::from_raw_os_error($k)
+	};
+}
+
+/// Compare two names without bailing out at the first difference, so
+/// that timing does not reveal how much of a guessed name is right.
+/// Lengths are not hidden.
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+	let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+	std::hint::black_box(diff) == 0
+}
+
+/// Options for a file opened with `flags`. Writeback caching would
+/// have the kernel cache even files opened with `O_DIRECT`, so have
+/// it send their reads and writes straight to us instead.
+fn open_options(flags: u32) -> OpenOptions {
+	let mut opts = OpenOptions::empty();
+	opts.set(OpenOptions::DIRECT_IO, flags as i32 & libc::O_DIRECT != 0);
+	opts
+}
+
+#[derive(Debug)]
+struct FsFiles {
+	files: Slab<InodeInfo>,
+	// Equality function for directory entry names
+	name_eq: fn(&[u8], &[u8]) -> bool,
+	// Keep checksums of file contents and check them on reads
+	verify_reads: bool,
+	// Maximum number of inodes in use at once, if limited
+	max_inodes: Option<usize>,
+	// Highest number of inodes ever in use at once
+	peak: usize,
+	// Number of inodes ever created and removed, besides the root
+	created: u64,
+	removed: u64,
+	// Generation of the next inode created
+	generation: u64,
+	// Pool of directory entry names, shared between directories
+	// if enabled
+	names: Option<HashSet<Arc<[u8]>>>,
+	// File contents by hash, shared between files with identical
+	// data if enabled
+	contents: Option<HashMap<u64, Weak<[u8]>>>,
+	// Recently looked up names, if enabled. Locked on its own, as
+	// lookups only hold the read lock.
+	name_cache: Option<Mutex<NameCache>>,
+}
+
+impl FsFiles {
+	fn new(cfg: &SlabFsBuilder) -> Self {
+		Self {
+			files: Slab::with_capacity(cfg.initial_inodes),
+			name_eq: if cfg.constant_time_names { ct_eq } else { <[u8]>::feq },
+			verify_reads: cfg.verify_reads,
+			max_inodes: cfg.max_inodes,
+			peak: 0,
+			created: 0,
+			removed: 0,
+			generation: 1,
+			names: cfg.intern_names.then(HashSet::new),
+			contents: cfg.dedup.then(HashMap::new),
+			name_cache: cfg.name_cache.map(|n| Mutex::new(NameCache::new(n))),
+		}
+	}
+
+	/// Share the contents of `ino` with any other file holding the
+	/// same data, or make them available for sharing otherwise.
+	fn dedup(&mut self, ino: Inode) {
+		let Some(contents) = self.contents.as_mut() else {
+			return;
+		};
+		let Some(Ok(data)) = self.files
+			.get_mut(usize::from(ino))
+			.map(|info| info.file_data()) else
+		{
+			return;
+		};
+		// Inline data is not worth sharing
+		if matches!(data, FileData::Shared(..)) || data.len() <= INLINE_CAP {
+			return;
+		}
+
+		let mut hasher = DefaultHasher::new();
+		data.as_slice().hash(&mut hasher);
+		let hash = hasher.finish();
+
+		match contents.get(&hash).and_then(Weak::upgrade) {
+			Some(shared) if *shared == *data.as_slice() => {
+				*data = FileData::Shared(shared);
+			}
+			_ => {
+				let shared: Arc<[u8]> = Arc::from(data.as_slice());
+				contents.insert(hash, Arc::downgrade(&shared));
+				*data = FileData::Shared(shared);
+			}
+		}
+	}
+
+	/// Bytes saved by sharing file contents.
+	fn dedup_saved(&self) -> usize {
+		self.contents
+			.iter()
+			.flat_map(|contents| contents.values())
+			.map(|shared| {
+				let copies = shared.strong_count();
+				shared.upgrade()
+					.map_or(0, |d| d.len() * copies.saturating_sub(1))
+			})
+			.sum()
+	}
+
+	fn intern(&mut self, name: &CStr) -> Arc<[u8]> {
+		let name = name.to_bytes();
+		let Some(names) = self.names.as_mut() else {
+			return Arc::from(name);
+		};
+		if let Some(interned) = names.get(name) {
+			return interned.clone();
+		}
+		let interned: Arc<[u8]> = Arc::from(name);
+		names.insert(interned.clone());
+		interned
+	}
+
+	/// Drop a reference to a pooled name, taken by a directory entry
+	/// or an inode.
+	fn release_name(&mut self, name: Arc<[u8]>) {
+		// If only the pool and us hold the name, drop it from the pool
+		if let Some(names) = self.names.as_mut() {
+			if Arc::strong_count(&name) == 2 {
+				names.remove(&name);
+			}
+		}
+	}
+
+	fn stats(&self) -> FsStats {
+		let capacity = self.files.capacity();
+		let occupied = self.files.len();
+		FsStats {
+			capacity: capacity as u64,
+			occupied: occupied as u64,
+			vacant: (capacity - occupied) as u64,
+			peak: self.peak as u64,
+			created: self.created,
+			removed: self.removed,
+			dedup_saved: self.dedup_saved() as u64,
+		}
+	}
+
+	/// Number of inodes in use. Slot 0 is not an inode.
+	fn used(&self) -> usize {
+		self.files.len() - 1
+	}
+
+	#[inline(always)]
+	fn get(&self, ino: Inode) -> io::Result<&InodeInfo> {
+		// Slot 0 holds a placeholder, not an inode
+		match usize::from(ino) {
+			0 => Err(ioerr!(NotFound)),
+			idx => self.files.get(idx).ok_or(ioerr!(NotFound)),
+		}
+	}
+
+	#[inline(always)]
+	fn get_mut(&mut self, ino: Inode) -> io::Result<&mut InodeInfo> {
+		match usize::from(ino) {
+			0 => Err(ioerr!(NotFound)),
+			idx => self.files.get_mut(idx).ok_or(ioerr!(NotFound)),
+		}
+	}
+
+	#[inline(always)]
+	unsafe fn get_unchecked_mut(&mut self, ino: Inode) -> &mut InodeInfo {
+		let idx = usize::from(ino);
+		self.files.get_unchecked_mut(idx)
+	}
+
+	#[inline(always)]
+	unsafe fn get_unchecked(&self, ino: Inode) -> &InodeInfo {
+		let idx = usize::from(ino);
+		self.files.get_unchecked(idx)
+	}
+
+	fn remove(&mut self, ino: Inode) {
+		let idx = usize::from(ino);
+		let name = self.files.remove(idx).shared_name().clone();
+		self.removed += 1;
+		self.release_name(name);
+	}
+
+	fn insert_and_get(&mut self, mut info: InodeInfo) -> io::Result<(Inode, Entry)> {
+		if self.max_inodes.is_some_and(|max| self.used() >= max) {
+			return Err(ioerr!(libc::ENOSPC));
+		}
+		info.generation = self.generation;
+		self.generation += 1;
+		let slot = self.files.vacant_entry();
+		let ino = Inode::from(slot.key());
+		let entry = slot.insert(info).get_entry(ino);
+		self.peak = self.peak.max(self.files.len());
+		self.created += 1;
+		Ok((ino, entry))
+	}
+
+	fn insert(&mut self, info: InodeInfo) -> Inode {
+		let ino = Inode::from(self.files.insert(info));
+		self.peak = self.peak.max(self.files.len());
+		ino
+	}
+
+	/// Build a new inode to be created in `parent`, applying the
+	/// parent's default ACL if it has one.
+	fn make_inode(
+		&self,
+		ctx: &Context,
+		parent: Inode,
+		name: &CStr,
+		mut args: CreateIn,
+	) -> io::Result<InodeInfo> {
+		// The umask only applies without a default ACL to inherit
+		let Some(default) = Acl::get(&self.get(parent)?.xattrs, ACL_DEFAULT)? else {
+			args.mode &= !args.umask;
+			return InodeInfo::create(parent, name, ctx, args);
+		};
+		let (acl, mode) = default.inherit(args.mode);
+		args.mode = mode;
+		let mut info = InodeInfo::create(parent, name, ctx, args)?;
+		if let Some(acl) = acl {
+			info.xattrs.set(ACL_ACCESS, &acl.encode(), 0)?;
+		}
+		if info.is_dir() {
+			info.xattrs.set(ACL_DEFAULT, &default.encode(), 0)?;
+		}
+		Ok(info)
+	}
+
+	/// Move inodes from the end of the slab into vacant slots below
+	/// them, and give back the memory of the vacant slots left at
+	/// the end. Returns the number of inodes moved and of slots the
+	/// slab no longer spans. An inode only moves if nothing outside
+	/// this table knows it by number: the kernel must hold no lookups
+	/// on it, and `can_move` must allow it. As the slab is compacted
+	/// from its end, the first inode that cannot move ends the moving.
+	/// Directories left mostly empty by removals give back their
+	/// spare room too, keeping their entries in order, as readdir
+	/// offsets are positions in the list.
+	fn compact(&mut self, can_move: impl Fn(Inode) -> bool) -> (usize, usize) {
+		// Names of entries in directories that were freed without
+		// being emptied first are only held by the pool
+		if let Some(names) = self.names.as_mut() {
+			names.retain(|name| Arc::strong_count(name) > 1);
+		}
+		if let Some(contents) = self.contents.as_mut() {
+			contents.retain(|_, shared| shared.strong_count() > 0);
+		}
+		let mut spare = 0;
+		for (_, info) in self.files.iter_mut() {
+			if let Ok(children) = info.children_mut() {
+				if children.capacity() > DIR_SLACK * children.len().max(1) {
+					spare += children.capacity() - children.len();
+					children.shrink_to_fit();
+				}
+			}
+		}
+		if spare > 0 {
+			log::debug!("Compaction gave back room for {} directory entries", spare);
+		}
+
+		let end = |files: &Slab<InodeInfo>| files.iter().next_back().map_or(0, |(idx, _)| idx + 1);
+		let before = end(&self.files);
+		let mut moved = HashMap::new();
+		self.files.compact(|info, from, to| {
+			let from = Inode::from(from);
+			let ok = !from.is_reserved() && info.refs() == 0 && can_move(from);
+			if ok {
+				moved.insert(from, Inode::from(to));
+			}
+			ok
+		});
+		// Point parents and directory entries at the new slots
+		if !moved.is_empty() {
+			let new = |ino: Inode| moved.get(&ino).copied().unwrap_or(ino);
+			for (_, info) in self.files.iter_mut() {
+				info.parent = new(info.parent);
+				if let Ok(children) = info.children_mut() {
+					for (child, _) in children.iter_mut() {
+						*child = new(*child);
+					}
+				}
+			}
+			if let Some(cache) = self.name_cache.as_mut() {
+				cache.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+			}
+		}
+		self.files.shrink_to_fit();
+		(moved.len(), before - end(&self.files))
+	}
+
+	fn unlink_inode(&mut self, parent: Inode, name: &CStr) -> io::Result<Inode> {
+		let name_eq = self.name_eq;
+		let pinfo = self.get_mut(parent)?;
+		let children = pinfo.children_mut()?;
+		let name_bytes = name.to_bytes();
+		let idx = children
+			.iter()
+			.position(|(_, cname)| name_eq(cname, name_bytes))
+			.ok_or(ioerr!(NotFound))?;
+		let (child, cname) = children.swap_remove(idx);
+		if let Some(cache) = self.name_cache.as_mut() {
+			cache.get_mut().unwrap_or_else(PoisonError::into_inner).remove(parent, &cname);
+		}
+		self.release_name(cname);
+		if let Ok(info) = self.get_mut(child) {
+			info.unlink();
+		}
+		Ok(child)
+	}
+
+	/// Free `top`, which must already be out of its parent directory,
+	/// and everything under it. Inodes for which `is_open` holds are
+	/// only marked as unlinked and forgotten, to go away once their
+	/// last handle is closed. Returns the number of inodes freed.
+	fn free_tree(&mut self, top: Inode, is_open: impl Fn(Inode) -> bool) -> usize {
+		let mut freed = 0;
+		let mut stack = vec![top];
+		while let Some(ino) = stack.pop() {
+			let Ok(info) = self.get_mut(ino) else {
+				continue;
+			};
+			info.unlink();
+			let children = info.children_mut().map(std::mem::take).unwrap_or_default();
+			if is_open(ino) {
+				info.refsub(info.refs()).ok();
+			} else {
+				self.remove(ino);
+				freed += 1;
+			}
+			for (child, name) in children {
+				self.release_name(name);
+				// Only descend into inodes that belong here, so that
+				// a corrupt tree cannot send us around in circles
+				if self.get(child).is_ok_and(|c| c.parent == ino && !c.is_unlinked()) {
+					stack.push(child);
+				}
+			}
+		}
+		freed
+	}
+
+	/// Path of `ino` from the root, found by following parent
+	/// links, or None if it or any directory above it was removed.
+	fn path(&self, ino: Inode) -> Option<Vec<u8>> {
+		let mut names = Vec::new();
+		let mut cur = ino;
+		while cur != Inode::ROOT {
+			let info = self.get(cur).ok().filter(|info| !info.is_unlinked())?;
+			// Parent links cannot loop, but a corrupt tree should not
+			// hang us either
+			if names.len() >= self.files.len() {
+				return None;
+			}
+			names.push(info.name());
+			cur = info.parent;
+		}
+		let mut path = Vec::new();
+		for name in names.iter().rev() {
+			path.push(b'/');
+			path.extend_from_slice(name);
+		}
+		if path.is_empty() {
+			path.push(b'/');
+		}
+		Some(path)
+	}
+
+	#[inline(always)]
+	fn read_ino<F, T>(&self, ino: Inode, f: F) -> io::Result<T>
+	where
+		F: Fn(&InodeInfo) -> io::Result<T>,
+		T: Sized,
+	{
+		self.get(ino).and_then(f)
+	}
+
+	/// Set the size of the file `ino` to `len` bytes, zero-filling
+	/// it when it grows.
+	fn truncate(&mut self, ino: Inode, len: u64) -> io::Result<()> {
+		let new_len = usize::try_from(len).map_err(|_| ioerr!(libc::EFBIG))?;
+		self.write_ino(ino, |info| {
+			let data = info.file_data()?;
+			let old_len = data.len();
+			data.resize(new_len)?;
+			info.update_sums(old_len.min(new_len), old_len.max(new_len));
+			Ok(())
+		})
+	}
+
+	#[inline(always)]
+	fn write_ino<F, T>(&mut self, ino: Inode, f: F) -> io::Result<T>
+	where
+		F: FnMut(&mut InodeInfo) -> io::Result<T>,
+		T: Sized,
+	{
+		self.get_mut(ino).and_then(f)
+	}
+
+	#[allow(unused)]
+	fn write_name<F, T>(&mut self, parent: Inode, name: &CStr, mut f: F) -> io::Result<T>
+	where
+		F: FnMut((Inode, &mut InodeInfo)) -> io::Result<T>,
+		T: Sized,
+	{
+		let ino = self.get(parent)?
+			.children()?
+			.iter()
+			.find_map(|(ino, cname)| {
+				(self.name_eq)(cname, name.to_bytes()).then_some(*ino)
+			})
+			.ok_or(ioerr!(NotFound))?;
+		let info = unsafe { self.get_unchecked_mut(ino) };
+		f((ino, info))
+	}
+
+	#[inline(always)]
+	fn read_name<F, T>(&self, parent: Inode, name: &CStr, f: F) -> io::Result<T>
+	where
+		F: Fn((Inode, &InodeInfo)) -> io::Result<T>,
+		T: Sized,
+	{
+		let name_bytes = name.to_bytes();
+		let pinfo = self.get(parent)?;
+		let children = pinfo.children()?;
+		match name_bytes {
+			b"." => return f((parent, pinfo)),
+			b".." => return f((pinfo.parent, self.get(pinfo.parent)?)),
+			_ => (),
+		}
+
+		let mut cache = self.name_cache
+			.as_ref()
+			.map(|cache| cache.lock().unwrap_or_else(PoisonError::into_inner));
+		if let Some(cache) = cache.as_mut() {
+			// Only trust an entry that still names the inode, in case
+			// either was freed and its slot reused since
+			let hit = cache.get(parent, name_bytes).and_then(|child| {
+				self.get(child).ok().filter(|info| {
+					info.parent == parent && !info.is_unlinked() && info.name() == name_bytes
+				}).map(|info| (child, info))
+			});
+			match hit {
+				Some(hit) => return f(hit),
+				None => cache.remove(parent, name_bytes),
+			}
+		}
+
+		for (child, cname) in children {
+			if (self.name_eq)(cname, name_bytes) {
+				let info = if cfg!(debug_assertions) {
+					self.get(*child).expect("Stale child")
+				} else {
+					unsafe { self.get_unchecked(*child) }
+				};
+				if let Some(cache) = cache.as_mut() {
+					cache.insert(parent, name_bytes, *child);
+				}
+				return f((*child, info));
+			}
+		}
+
+		Err(ioerr!(NotFound))
+	}
+}
+
+#[derive(Debug)]
+pub struct SlabFs {
+	files: RwLock<FsFiles>,
+	handles: Handles,
+	// Advertise splice support to the kernel
+	splice: bool,
+	// Let any user set xattrs outside the user namespace
+	all_xattr_namespaces: bool,
+	// Log of changes, appended to with the write lock held
+	journal: Option<Journal>,
+	metrics: Metrics,
+	// Random failures of reads, writes and creates, if enabled
+	#[cfg(feature = "fault-inject")]
+	faults: Option<Faults>,
+	// Contents of .slabfs/config, if the directory is enabled
+	info: Option<String>,
+}
+
+/// An inode as listed by `SlabFs::list_inodes()`.
+#[derive(Clone, Debug)]
+pub struct InodeSummary {
+	pub ino: Inode,
+	// Path from the root, or None if removed from the tree
+	pub path: Option<Vec<u8>>,
+	// Type and permission bits, as in st_mode
+	pub mode: u32,
+	pub size: u64,
+	// Lookups held by the kernel
+	pub refs: u64,
+}
+
+/// Configuration for a new `SlabFs`.
+#[derive(Debug)]
+pub struct SlabFsBuilder {
+	initial_inodes: usize,
+	max_inodes: Option<usize>,
+	max_open_files: Option<usize>,
+	intern_names: bool,
+	dedup: bool,
+	constant_time_names: bool,
+	verify_reads: bool,
+	splice: bool,
+	all_xattr_namespaces: bool,
+	journal: Option<PathBuf>,
+	coalesce_writes: bool,
+	tree: Option<PathBuf>,
+	// Number of looked up names to remember, if any
+	name_cache: Option<usize>,
+	// Answer lookups of .slabfs in the root
+	info_dir: bool,
+	// Failure rate, errno and seed of injected faults
+	#[cfg(feature = "fault-inject")]
+	faults: Option<(f64, i32, u64)>,
+}
+
+impl Default for SlabFsBuilder {
+	fn default() -> Self {
+		Self {
+			initial_inodes: DEFAULT_INITIAL_INODES,
+			max_inodes: None,
+			max_open_files: None,
+			intern_names: false,
+			dedup: false,
+			constant_time_names: false,
+			verify_reads: false,
+			splice: true,
+			all_xattr_namespaces: false,
+			journal: None,
+			coalesce_writes: false,
+			tree: None,
+			name_cache: None,
+			info_dir: false,
+			#[cfg(feature = "fault-inject")]
+			faults: None,
+		}
+	}
+}
+
+impl SlabFsBuilder {
+	/// Number of inodes to preallocate room for.
+	pub fn initial_inodes(mut self, n: usize) -> Self {
+		self.initial_inodes = n;
+		self
+	}
+
+	/// Maximum number of inodes, including the root directory.
+	/// Creating more fails with `ENOSPC`.
+	pub fn max_inodes(mut self, n: usize) -> Self {
+		self.max_inodes = Some(n);
+		self
+	}
+
+	/// Maximum number of files and directories open at once.
+	/// Opening more fails with `ENFILE`.
+	pub fn max_open_files(mut self, n: usize) -> Self {
+		self.max_open_files = Some(n);
+		self
+	}
+
+	pub fn intern_names(mut self, enable: bool) -> Self {
+		self.intern_names = enable;
+		self
+	}
+
+	pub fn dedup(mut self, enable: bool) -> Self {
+		self.dedup = enable;
+		self
+	}
+
+	/// Look up names in constant time, so that timing does not leak
+	/// the names of files to clients that cannot list them.
+	pub fn constant_time_names(mut self, enable: bool) -> Self {
+		self.constant_time_names = enable;
+		self
+	}
+
+	/// Keep checksums of file contents, and fail reads with `EIO`
+	/// if the data no longer matches them.
+	pub fn verify_reads(mut self, enable: bool) -> Self {
+		self.verify_reads = enable;
+		self
+	}
+
+	/// Let the kernel splice data to and from the filesystem. On
+	/// by default.
+	pub fn splice(mut self, enable: bool) -> Self {
+		self.splice = enable;
+		self
+	}
+
+	/// Let users other than root set and remove xattrs in any
+	/// namespace but `security.` and `trusted.`, instead of only
+	/// `user.` ones and ACLs.
+	pub fn all_xattr_namespaces(mut self, enable: bool) -> Self {
+		self.all_xattr_namespaces = enable;
+		self
+	}
+
+	/// Record changes in a journal at `path`, and rebuild the
+	/// filesystem from it if it already exists.
+	pub fn journal<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.journal = Some(path.into());
+		self
+	}
+
+	/// Merge consecutive writes to a file into a single journal
+	/// record, which is only written once the file is closed or
+	/// synced, or another change is made. Writes not recorded yet
+	/// are lost if the process is killed.
+	pub fn coalesce_writes(mut self, enable: bool) -> Self {
+		self.coalesce_writes = enable;
+		self
+	}
+
+	/// Start with the tree described by a JSON dump at `path`, as
+	/// printed by `slabfs --dump-tree`. Cannot be combined with a
+	/// journal.
+	pub fn load_tree<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.tree = Some(path.into());
+		self
+	}
+
+	/// Remember up to `entries` recently looked up names, so that
+	/// looking them up again does not search their directory.
+	/// Cannot be combined with constant-time name comparisons, as
+	/// whether a name is remembered shows in the lookup time.
+	pub fn name_cache(mut self, entries: usize) -> Self {
+		self.name_cache = Some(entries);
+		self
+	}
+
+	/// Serve a read-only `.slabfs` directory in the root, whose
+	/// files describe the filesystem. It is not listed in the root,
+	/// only found by name.
+	pub fn info_dir(mut self, enable: bool) -> Self {
+		self.info_dir = enable;
+		self
+	}
+
+	/// Fail a fraction `rate` of reads, writes and creates with
+	/// `errno`, picked at random from a generator seeded with `seed`.
+	/// Meant for testing how applications handle errors.
+	#[cfg(feature = "fault-inject")]
+	pub fn fault_inject(mut self, rate: f64, errno: i32, seed: u64) -> Self {
+		self.faults = Some((rate, errno, seed));
+		self
+	}
+
+	pub fn build(self) -> io::Result<SlabFs> {
+		if self.initial_inodes == 0 {
+			return Err(ioerr!(InvalidInput, "initial inode count must be non-zero"));
+		}
+		if self.max_inodes == Some(0) {
+			return Err(ioerr!(InvalidInput, "maximum inode count must be non-zero"));
+		}
+		if self.max_open_files == Some(0) {
+			return Err(ioerr!(InvalidInput, "maximum open file count must be non-zero"));
+		}
+		if self.tree.is_some() && self.journal.is_some() {
+			return Err(ioerr!(InvalidInput, "cannot load a tree into a journaled filesystem"));
+		}
+		if self.name_cache == Some(0) {
+			return Err(ioerr!(InvalidInput, "name cache size must be non-zero"));
+		}
+		if self.name_cache.is_some() && self.constant_time_names {
+			return Err(ioerr!(InvalidInput, "cannot cache names compared in constant time"));
+		}
+		#[cfg(feature = "fault-inject")]
+		if let Some((rate, errno, _)) = self.faults {
+			if !(0.0..=1.0).contains(&rate) {
+				return Err(ioerr!(InvalidInput, "fault rate must be between 0 and 1"));
+			}
+			if errno <= 0 {
+				return Err(ioerr!(InvalidInput, "fault errno must be positive"));
+			}
+		}
+		let files = FsFiles::new(&self);
+		let mut fs = SlabFs {
+			files: RwLock::new(files),
+			handles: Handles::new(self.max_open_files),
+			splice: self.splice,
+			all_xattr_namespaces: self.all_xattr_namespaces,
+			journal: None,
+			metrics: Metrics::new(),
+			#[cfg(feature = "fault-inject")]
+			faults: self.faults.map(|(rate, errno, seed)| Faults::new(rate, errno, seed)),
+			info: self.info_dir.then(|| info::config(&self)),
+		};
+		// Inode 0 is not valid for FUSE, so fill slot 0 with a
+		// placeholder that is never handed out, to start the root
+		// at inode 1
+		fs.insert_entry(InodeInfo::empty());
+		fs.insert_entry(InodeInfo::root());
+
+		if let Some(path) = self.tree.as_deref() {
+			let text = std::fs::read_to_string(path)?;
+			tree::load(&fs, &text)?;
+		}
+
+		if let Some(path) = self.journal.as_deref() {
+			let (journal, records) = Journal::open(path, self.coalesce_writes)?;
+			log::info!("Replaying {} journal records", records.len());
+			fs.replay(records);
+			journal.append(&Record::Epoch)?;
+			fs.journal = Some(journal);
+		}
+		Ok(fs)
+	}
+}
+
+impl SlabFs {
+	pub fn builder() -> SlabFsBuilder {
+		SlabFsBuilder::default()
+	}
+
+	/// Fail if fault injection is enabled and picked this operation.
+	#[inline(always)]
+	fn inject_fault(&self) -> io::Result<()> {
+		#[cfg(feature = "fault-inject")]
+		if let Some(faults) = self.faults.as_ref() {
+			faults.check()?;
+		}
+		Ok(())
+	}
+
+	/// Lock the inode table for reading. A request that panics with
+	/// the lock held poisons it; keep serving the others rather than
+	/// panicking in every one of them from then on.
+	fn files(&self) -> RwLockReadGuard<'_, FsFiles> {
+		self.files.read().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	/// Lock the inode table for writing, recovering from poisoning
+	/// like `files()`.
+	fn files_mut(&self) -> RwLockWriteGuard<'_, FsFiles> {
+		self.files.write().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	/// The `.slabfs` node with inode number `ino`, if the directory
+	/// is enabled.
+	fn info_node(&self, ino: Inode) -> Option<Node> {
+		self.info.as_ref()?;
+		Node::from_ino(ino)
+	}
+
+	/// Fail adding or removing names in `parent` if it is the
+	/// `.slabfs` directory.
+	fn check_info_parent(&self, parent: Inode) -> io::Result<()> {
+		match self.info_node(parent) {
+			Some(_) => Err(ioerr!(libc::EACCES)),
+			None => Ok(()),
+		}
+	}
+
+	fn info_contents(&self, node: Node) -> Vec<u8> {
+		let config = self.info.as_deref().unwrap_or_default();
+		node.contents(&self.files(), config)
+	}
+
+	fn insert_entry(&self, info: InodeInfo) -> Inode {
+		self.files_mut().insert(info)
+	}
+
+	/// Compact the inode table, returning the number of inodes moved
+	/// to lower slots and of slots given back. Open inodes keep their
+	/// numbers, and so do all inodes if there is a journal, as its
+	/// records name inodes by number.
+	pub fn compact(&self) -> (usize, usize) {
+		let journaled = self.journal.is_some();
+		self.files_mut().compact(|ino| !journaled && !self.handles.is_open(ino))
+	}
+
+	/// Create an inode named `name` under `parent`, or open the
+	/// existing one unless `O_EXCL` is given.
+	fn create_inode(
+		&self,
+		ctx: &Context,
+		parent: Inode,
+		name: &CStr,
+		args: CreateIn,
+	) -> io::Result<Entry> {
+		self.add_inode(parent, name, args.flags, |files| files.make_inode(ctx, parent, name, args))
+	}
+
+	/// Like `create_inode()`, for inodes created without the kernel
+	/// asking, which it thus holds no reference to.
+	fn create_unref(
+		&self,
+		ctx: &Context,
+		parent: Inode,
+		name: &CStr,
+		args: CreateIn,
+	) -> io::Result<Entry> {
+		self.add_inode(parent, name, args.flags, |files| {
+			files.make_inode(ctx, parent, name, args).map(InodeInfo::without_refs)
+		})
+	}
+
+	/// Add the inode built by `make` to `parent` as `name`. If the
+	/// name exists, fail if `flags` has `O_EXCL`, and otherwise look
+	/// up the existing inode, which must be a regular file, and
+	/// truncate it if `flags` has `O_TRUNC`.
+	fn add_inode<F>(&self, parent: Inode, name: &CStr, flags: u32, make: F) -> io::Result<Entry>
+	where
+		F: FnOnce(&FsFiles) -> io::Result<InodeInfo>,
+	{
+		if self.info.is_some() && Node::lookup(parent, name.to_bytes()).is_some() {
+			return Err(ioerr!(AlreadyExists));
+		}
+		self.check_info_parent(parent)?;
+		let mut files = self.files_mut();
+
+		let existing = files.read_name(parent, name, |(ino, info)| {
+			if flags & libc::O_EXCL as u32 != 0 {
+				return Err(ioerr!(AlreadyExists));
+			}
+			// The kernel only takes regular files from create
+			match info.file_type() {
+				FsType::REG => Ok(ino),
+				FsType::DIR => Err(ioerr!(libc::EISDIR)),
+				_ => Err(ioerr!(AlreadyExists)),
+			}
+		});
+		match existing {
+			Ok(ino) => {
+				if flags & libc::O_TRUNC as u32 != 0 {
+					files.truncate(ino, 0)?;
+					self.record(|| Record::Setattr {
+						ino,
+						valid: SetattrValid::SIZE.bits(),
+						mode: 0,
+						uid: 0,
+						gid: 0,
+						size: 0,
+					})?;
+				}
+				let info = files.get(ino)?;
+				info.refinc()?;
+				return Ok(info.get_entry(ino));
+			}
+			Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+			Err(_) => (),
+		}
+
+		let mut info = make(&files)?;
+		if files.verify_reads {
+			info.track_sums();
+		}
+		// The inode and its entry in the parent share the name
+		let cname = files.intern(name);
+		info.share_name(&cname);
+		let (ino, entry) = match files.insert_and_get(info) {
+			Ok(inserted) => inserted,
+			Err(e) => {
+				files.release_name(cname);
+				return Err(e);
+			}
+		};
+
+		// Add to parent
+		if let Err(e) = files.write_ino(parent, |pinfo| {
+			// The parent may have been removed while the kernel
+			// still holds a reference to it
+			if pinfo.is_unlinked() {
+				return Err(ioerr!(NotFound));
+			}
+			pinfo.add_child(ino, cname.clone())
+		}) {
+			files.remove(ino);
+			files.release_name(cname);
+			return Err(e);
+		}
+
+		let info = files.get(ino).unwrap();
+		self.record(|| Record::create(parent, ino, name.to_bytes(), info))?;
+		// Replay does not inherit default ACLs, so record the result
+		for rec in Record::setxattrs(ino, info) {
+			self.record(|| rec)?;
+		}
+		Ok(entry)
+	}
+
+	/// Drop `count` lookups of `ino`. Inodes that are already gone
+	/// are skipped, and the root and slot 0 are never removed.
+	fn forget_one(&self, files: &mut FsFiles, ino: Inode, count: u64) {
+		if ino.is_reserved() || self.info_node(ino).is_some() {
+			return;
+		}
+		match files.read_ino(ino, |info| info.refsub(count)) {
+			Ok(true) => self.release_unused(files, ino),
+			Ok(false) => (),
+			Err(_) => log::warn!("Forget for unknown inode {:?}", ino),
+		}
+	}
+
+	/// Remove `ino` if it was unlinked, the kernel has forgotten it
+	/// and it is not open. Inodes in the tree stay until unlinked,
+	/// whether the kernel knows about them or not, and unlinked ones
+	/// stay usable while open.
+	fn release_unused(&self, files: &mut FsFiles, ino: Inode) {
+		let unused = files.get(ino).is_ok_and(|info| info.is_unlinked() && info.refs() == 0);
+		if unused && !self.handles.is_open(ino) {
+			files.remove(ino);
+		}
+	}
+
+	/// Close a handle.
+	fn close(&self, handle: u64) -> io::Result<()> {
+		let mut files = self.files_mut();
+		let file = self.handles.remove(handle)?;
+		self.release_unused(&mut files, file.ino);
+		Ok(())
+	}
+
+	/// Append a record to the journal, if any. Must be called with
+	/// the write lock held, once the change has been made.
+	fn record<F: FnOnce() -> Record>(&self, rec: F) -> io::Result<()> {
+		let Some(journal) = self.journal.as_ref() else {
+			return Ok(());
+		};
+		journal.append(&rec()).map_err(|e| {
+			log::error!("Failed to append to journal: {}", e);
+			e
+		})
+	}
+
+	/// Rewrite the journal with just the records needed to rebuild
+	/// the current contents of the filesystem, so that it does not
+	/// grow without bounds. Changes are blocked until it is done.
+	pub fn checkpoint(&self) -> io::Result<()> {
+		let Some(journal) = self.journal.as_ref() else {
+			return Ok(());
+		};
+		// Appends happen with the write lock held, so holding the
+		// read lock keeps the journal consistent with the snapshot
+		let files = self.files();
+		journal.rewrite(|append| {
+			// Inodes created after a restart must not reuse the
+			// generations of ones that were removed
+			append(&Record::Generation { next: files.generation })?;
+			let root = files.get(Inode::ROOT)?;
+			append(&Record::Setattr {
+				ino: Inode::ROOT,
+				valid: (SetattrValid::MODE | SetattrValid::UID | SetattrValid::GID).bits(),
+				mode: root.st_mode(),
+				uid: root.owner.uid,
+				gid: root.owner.gid,
+				size: 0,
+			})?;
+			Record::setxattrs(Inode::ROOT, root).try_for_each(|rec| append(&rec))?;
+
+			// Parents must be created before their children. Inodes
+			// that were unlinked are left out, open or not.
+			let mut dirs = VecDeque::from([Inode::ROOT]);
+			while let Some(parent) = dirs.pop_front() {
+				for (ino, name) in files.get(parent)?.children()? {
+					let info = files.get(*ino)?;
+					append(&Record::create(parent, *ino, name, info))?;
+					Record::setxattrs(*ino, info).try_for_each(|rec| append(&rec))?;
+					if info.is_dir() {
+						dirs.push_back(*ino);
+						continue;
+					}
+					match info.file_contents() {
+						Ok([]) | Err(_) => (),
+						Ok(data) => append(&Record::Write {
+							ino: *ino,
+							offset: 0,
+							data: data.to_vec(),
+						})?,
+					}
+				}
+			}
+			Ok(())
+		})
+	}
+
+	/// Apply the records of a journal. Inodes get new numbers as
+	/// they are created again, so map the ones in the records to
+	/// those. A run of the filesystem starts out with the result of
+	/// replaying the records before it, so the inodes it inherited
+	/// already have the numbers the replay gives them.
+	fn replay(&self, records: Vec<Record>) {
+		let mut inos: HashMap<Inode, Inode> = HashMap::new();
+		for rec in records {
+			let map = |ino| inos.get(&ino).copied().unwrap_or(ino);
+			let res = match rec {
+				Record::Epoch => {
+					inos.clear();
+					Ok(())
+				}
+				Record::Generation { next } => {
+					let mut files = self.files_mut();
+					files.generation = files.generation.max(next);
+					Ok(())
+				}
+				Record::Create { parent, ino, mode, uid, gid, name } => {
+					let ctx = Context { uid, gid, pid: 0 };
+					let args = CreateIn {
+						flags: libc::O_EXCL as u32,
+						mode,
+						umask: 0,
+						fuse_flags: 0,
+					};
+					// The mode is the one the inode ended up with, and any
+					// inherited ACLs have records of their own, so do not
+					// inherit the parent's default ACL again
+					CString::new(name)
+						.map_err(io::Error::from)
+						.and_then(|name| {
+							let parent = map(parent);
+							self.add_inode(parent, &name, args.flags, |_| {
+								InodeInfo::create(parent, &name, &ctx, args).map(InodeInfo::without_refs)
+							})
+						})
+						.map(|entry| {
+							inos.insert(ino, Inode::from(entry.inode));
+						})
+				}
+				Record::Symlink { parent, ino, uid, gid, name, target } => {
+					let ctx = Context { uid, gid, pid: 0 };
+					CString::new(name)
+						.map_err(io::Error::from)
+						.and_then(|name| {
+							let parent = map(parent);
+							self.add_inode(parent, &name, libc::O_EXCL as u32, |_| {
+								InodeInfo::symlink(parent, &name, &ctx, &target).map(InodeInfo::without_refs)
+							})
+						})
+						.map(|entry| {
+							inos.insert(ino, Inode::from(entry.inode));
+						})
+				}
+				Record::Write { ino, offset, data } => {
+					self.write_at(map(ino), offset, &data).map(drop)
+				}
+				Record::Replace { ino, data } => self.replace_contents(map(ino), data),
+				Record::Setattr { ino, valid, mode, uid, gid, size } => {
+					let mut attr: stat64 = unsafe { std::mem::zeroed() };
+					attr.st_mode = mode;
+					attr.st_uid = uid;
+					attr.st_gid = gid;
+					attr.st_size = size as i64;
+					let valid = SetattrValid::from_bits_truncate(valid);
+					self.setattr(&Context::new(), map(ino), attr, None, valid).map(drop)
+				}
+				Record::Setxattr { ino, name, value } => CString::new(name)
+					.map_err(io::Error::from)
+					.and_then(|name| self.setxattr(&Context::new(), map(ino), &name, &value, 0)),
+				Record::Removexattr { ino, name } => CString::new(name)
+					.map_err(io::Error::from)
+					.and_then(|name| self.removexattr(&Context::new(), map(ino), &name)),
+				Record::Unlink { parent, name } => {
+					// Nothing else refers to the inode, unlike when the
+					// kernel removed it, so it can go right away
+					CString::new(name)
+						.map_err(io::Error::from)
+						.and_then(|name| {
+							let mut files = self.files_mut();
+							let child = files.unlink_inode(map(parent), &name)?;
+							files.free_tree(child, |_| false);
+							Ok(())
+						})
+				}
+			};
+			if let Err(e) = res {
+				log::warn!("Skipping journal record: {}", e);
+			}
+		}
+	}
+}
+
+/// Synchronous API to drive the filesystem in-process, without going
+/// through FUSE. These calls bypass permission checks, and inodes are
+/// created as owned by root. The kernel only learns of created inodes
+/// once it looks them up.
+impl SlabFs {
+	/// Create a file named `name` under `parent`. `mode` may include
+	/// a file type; otherwise a regular file is created.
+	pub fn create_file(&self, parent: Inode, name: &str, mode: u32) -> io::Result<Inode> {
+		let name = CString::new(name)?;
+		let mode = match mode & libc::S_IFMT {
+			0 => mode | libc::S_IFREG,
+			_ => mode,
+		};
+		let args = CreateIn {
+			flags: libc::O_EXCL as u32,
+			mode,
+			umask: 0,
+			fuse_flags: 0,
+		};
+		let entry = self.create_unref(&Context::new(), parent, &name, args)?;
+		Ok(Inode::from(entry.inode))
+	}
+
+	/// Create empty regular files with mode 0644 under `parent`, one
+	/// for each of `names`, taking the lock only once. Either all of
+	/// them are created or, on error, none is.
+	pub fn bulk_create(&self, parent: Inode, names: &[&str]) -> io::Result<Vec<Inode>> {
+		let names = names
+			.iter()
+			.map(|name| {
+				let name = CString::new(*name)?;
+				match name.as_bytes() {
+					b"" | b"." | b".." => Err(ioerr!(libc::EINVAL)),
+					n if n.contains(&b'/') => Err(ioerr!(libc::EINVAL)),
+					n if n.len() > NAME_MAX => Err(ioerr!(libc::ENAMETOOLONG)),
+					_ => Ok(name),
+				}
+			})
+			.collect::<io::Result<Vec<_>>>()?;
+		let ctx = Context::new();
+		let args = CreateIn {
+			flags: libc::O_EXCL as u32,
+			mode: libc::S_IFREG | 0o644,
+			umask: 0,
+			fuse_flags: 0,
+		};
+
+		let mut files = self.files_mut();
+		let pinfo = files.get(parent)?;
+		if pinfo.is_unlinked() {
+			return Err(ioerr!(NotFound));
+		}
+		// Check every name up front, against the directory and
+		// each other
+		let mut taken: HashSet<&[u8]> = pinfo.children()?.iter().map(|(_, n)| &**n).collect();
+		if !names.iter().all(|name| taken.insert(name.as_bytes())) {
+			return Err(ioerr!(AlreadyExists));
+		}
+		if files.max_inodes.is_some_and(|max| files.used() + names.len() > max) {
+			return Err(ioerr!(libc::ENOSPC));
+		}
+
+		let mut children = Vec::with_capacity(names.len());
+		for name in &names {
+			let made = files.make_inode(&ctx, parent, name, args).and_then(|info| {
+				let mut info = info.without_refs();
+				if files.verify_reads {
+					info.track_sums();
+				}
+				let cname = files.intern(name);
+				info.share_name(&cname);
+				match files.insert_and_get(info) {
+					Ok((ino, _)) => Ok((ino, cname)),
+					Err(e) => {
+						files.release_name(cname);
+						Err(e)
+					}
+				}
+			});
+			match made {
+				Ok(child) => children.push(child),
+				Err(e) => {
+					for (ino, name) in children {
+						files.remove(ino);
+						files.release_name(name);
+					}
+					return Err(e);
+				}
+			}
+		}
+		let inos: Vec<Inode> = children.iter().map(|(ino, _)| *ino).collect();
+		files.get_mut(parent)?.children_mut()?.extend(children);
+
+		for (ino, name) in inos.iter().zip(&names) {
+			let info = files.get(*ino).unwrap();
+			self.record(|| Record::create(parent, *ino, name.to_bytes(), info))?;
+			for rec in Record::setxattrs(*ino, info) {
+				self.record(|| rec)?;
+			}
+		}
+		Ok(inos)
+	}
+
+	/// Create a regular file named `name` under `parent` with the
+	/// mode and contents of `src`. The contents are shared, not
+	/// copied, until either file is written to.
+	pub fn clone_file(&self, src: Inode, parent: Inode, name: &str) -> io::Result<Inode> {
+		let mode = self.files().read_ino(src, |info| {
+			info.file_contents()?;
+			Ok(info.st_mode())
+		})?;
+		let ino = self.create_file(parent, name, mode)?;
+
+		let mut files = self.files_mut();
+		let data = files.get_mut(src)?.file_data()?.share();
+		files.write_ino(ino, |info| {
+			let len = data.len();
+			*info.file_data()? = data.clone();
+			info.update_sums(0, len);
+			if len > 0 && !info.is_unlinked() {
+				self.record(|| Record::Write {
+					ino,
+					offset: 0,
+					data: data.as_slice().to_vec(),
+				})?;
+			}
+			Ok(ino)
+		})
+	}
+
+	pub fn write_at(&self, ino: Inode, offset: u64, buf: &[u8]) -> io::Result<usize> {
+		let start = usize::try_from(offset).map_err(|_| ioerr!(libc::EFBIG))?;
+		let end = start.checked_add(buf.len()).ok_or(ioerr!(libc::EFBIG))?;
+		let mut files = self.files_mut();
+		files.write_ino(ino, |info| {
+			let unlinked = info.is_unlinked();
+			let file = info.file_data()?;
+			let old_len = file.len();
+			file.try_grow(end)?[start..end].copy_from_slice(buf);
+			info.update_sums(old_len.min(start), end);
+			if !unlinked {
+				self.record(|| Record::Write {
+					ino,
+					offset,
+					data: buf.to_vec(),
+				})?;
+			}
+			Ok(buf.len())
+		})
+	}
+
+	/// Replace the whole contents of the regular file `ino` with
+	/// `data` in one step, so that readers see either the old
+	/// contents or the new ones, never a mix of both.
+	pub fn replace_contents(&self, ino: Inode, data: Vec<u8>) -> io::Result<()> {
+		let mut files = self.files_mut();
+		let info = files.get_mut(ino)?;
+		let contents = info.file_data()?;
+		let (old_len, new_len) = (contents.len(), data.len());
+		*contents = FileData::from(data);
+		info.update_sums(0, old_len.max(new_len));
+		if !info.is_unlinked() {
+			let data = info.file_contents()?;
+			self.record(|| Record::Replace { ino, data: data.to_vec() })?;
+		}
+		Ok(())
+	}
+
+	pub fn read_at(&self, ino: Inode, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+		let mut files = self.files_mut();
+		files.write_ino(ino, |info| {
+			let data = info.file_data()?.as_slice();
+			let start = usize::try_from(offset).map_or(data.len(), |s| s.min(data.len()));
+			let end = start.saturating_add(len).min(data.len());
+			let buf = data[start..end].to_vec();
+			if !info.verify_sums(start, end) {
+				log::error!("Checksum mismatch in inode {:?}", ino);
+				return Err(ioerr!(libc::EIO));
+			}
+			Ok(buf)
+		})
+	}
+
+	/// Whether `uid` and `gid` would be granted the access in `mask`
+	/// (`R_OK`, `W_OK`, `X_OK`) to `ino`, as checked by `access()`.
+	/// Nothing is accessed.
+	pub fn check_access(&self, ino: Inode, uid: u32, gid: u32, mask: u32) -> io::Result<bool> {
+		let files = self.files();
+		files.read_ino(ino, |info| info.permits(uid, gid, mask))
+	}
+
+	/// Remove `ino` from its directory and free it along with
+	/// everything under it, in one go. Returns the number of inodes
+	/// freed. Meant for trees not in use through FUSE: any lookups
+	/// the kernel holds on them are dropped, though open files stay
+	/// usable until closed.
+	pub fn remove_tree(&self, ino: Inode) -> io::Result<usize> {
+		if ino == Inode::ROOT {
+			return Err(ioerr!(libc::EBUSY));
+		}
+		let mut files = self.files_mut();
+		let info = files.get(ino)?;
+		if info.is_unlinked() {
+			return Err(ioerr!(NotFound));
+		}
+		let parent = info.parent;
+		let name = CString::new(info.name())?;
+		files.unlink_inode(parent, &name)?;
+		let freed = files.free_tree(ino, |ino| self.handles.is_open(ino));
+		self.record(|| Record::Unlink {
+			parent,
+			name: name.into_bytes(),
+		})?;
+		Ok(freed)
+	}
+
+	/// List up to `max` live inodes, in inode number order, with
+	/// their paths. Unlike walking the tree, this also finds inodes
+	/// that were removed but are still known to the kernel or open,
+	/// which helps track down leaks.
+	pub fn list_inodes(&self, max: usize) -> Vec<InodeSummary> {
+		let files = self.files();
+		files.files
+			.iter()
+			.skip(1)
+			.take(max)
+			.map(|(idx, info)| {
+				let ino = Inode::from(idx);
+				let st = info.stat64(ino);
+				InodeSummary {
+					ino,
+					path: files.path(ino),
+					mode: st.st_mode,
+					size: st.st_size as u64,
+					refs: info.refs(),
+				}
+			})
+			.collect()
+	}
+
+	/// Take a copy of the whole tree, with the names, types, sizes
+	/// and contents of its inodes, under a single lock, so that it
+	/// is consistent even while the filesystem keeps changing.
+	/// Contents shared between files, e.g. by deduplication or
+	/// `clone_file()`, are shared with the snapshot too; the rest
+	/// are copied. Tree dumps do not go through it, as they need no
+	/// contents and stop once their buffer is full; they walk the
+	/// tree under the read lock instead.
+	pub fn snapshot(&self) -> io::Result<TreeSnapshot> {
+		snapshot::take(&self.files())
+	}
+
+	pub fn list_dir(&self, ino: Inode) -> io::Result<Vec<(Inode, Vec<u8>)>> {
+		let files = self.files();
+		files.read_ino(ino, |info| {
+			Ok(info.children()?
+				.iter()
+				.map(|(child, name)| (*child, name.to_vec()))
+				.collect())
+		})
+	}
+}
+
+impl FileSystem for SlabFs {
+	type Inode = Inode;
+	type Handle = u64;
+
+	fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+		log::trace!("init(capable={:?})", _capable);
+		let mut cap = FsOptions::empty();
+		cap.set(FsOptions::HAS_IOCTL_DIR, true);
+		cap.set(FsOptions::ABORT_ERROR, true);
+		cap.set(FsOptions::ASYNC_READ, true);
+		cap.set(FsOptions::ASYNC_DIO, true);
+		cap.set(FsOptions::BIG_WRITES, true);
+		// Let the kernel send up to 1 MiB per read or write, as set by
+		// fuse-backend-rs, instead of its default of 128 KiB
+		cap.set(FsOptions::MAX_PAGES, true);
+		cap.set(FsOptions::PARALLEL_DIROPS, true);
+		cap.set(FsOptions::ZERO_MESSAGE_OPEN, true);
+		// The kernel checks permissions against the ACLs kept in
+		// xattrs, and leaves applying the umask to us, as it must not
+		// be applied when a default ACL is inherited
+		cap.set(FsOptions::POSIX_ACL, true);
+		cap.set(FsOptions::DONT_MASK, true);
+		//cap.set(FsOptions::DO_READDIRPLUS, true);
+		// Writes are cached in the kernel until flushed; fsync()
+		// syncs the journal, if any
+		cap.set(FsOptions::WRITEBACK_CACHE, true);
+		//cap.set(FsOptions::EXPLICIT_INVAL_DATA, true);
+		cap.set(FsOptions::SPLICE_READ, self.splice);
+		cap.set(FsOptions::SPLICE_WRITE, self.splice);
+		cap.set(FsOptions::SPLICE_MOVE, self.splice);
+		Ok(cap)
+	}
+
+	fn destroy(&self) {
+		log::trace!("destroy()");
+		// Writes the kernel never synced should survive a restart too
+		if let Some(journal) = self.journal.as_ref() {
+			if let Err(e) = journal.sync() {
+				log::error!("Failed to sync journal: {}", e);
+			}
+		}
+		let files = self.files();
+		let bytes: usize = files.files
+			.iter()
+			.filter_map(|(_, info)| info.file_contents().ok())
+			.map(<[u8]>::len)
+			.sum();
+		log::info!("Shutting down with {} inodes holding {} bytes", files.used(), bytes);
+	}
+
+	fn readdir(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		handle: Self::Handle,
+		size: u32,
+		offset: u64,
+		add_entry: &mut dyn FnMut(DirEntry<'_>) -> io::Result<usize>,
+	) -> io::Result<()> {
+		log::trace!(
+			"readdir(inode={:?}, off={}, size={})",
+			inode, offset, size
+		);
+		let _timer = self.metrics.time(Op::Readdir);
+
+		let file = self.handles.get(handle)?;
+		if file.ino != inode {
+			return Err(ioerr!(libc::EBADF));
+		}
+		if !file.dir {
+			return Err(ioerr!(libc::ENOTDIR));
+		}
+
+		if size == 0 {
+			return Ok(());
+		}
+		if self.info_node(inode).is_some() {
+			return info::readdir(offset, add_entry);
+		}
+		let offset = offset as usize;
+
+		let files = self.files();
+		for (i, (child, _)) in files.get(inode)?
+			.children()?
+			.iter()
+			.enumerate()
+			.skip(offset)
+		{
+			let info = files.get(*child).expect("Stale child?");
+			let dir_entry = info.get_direntry(*child, (i as u64) + 1);
+			if add_entry(dir_entry)? == 0 {
+				break;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn create(
+		&self,
+		ctx: &Context,
+		parent: Self::Inode,
+		name: &CStr,
+		args: CreateIn,
+	) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+		log::trace!("create(parent={:?}, name={:?}, pid={})", parent, name, ctx.pid);
+		self.inject_fault()?;
+		let opts = open_options(args.flags);
+		let entry = self.create_inode(ctx, parent, name, args)?;
+		let ino = Inode::from(entry.inode);
+		let handle = self.handles.insert(OpenFile { ino, dir: false }).inspect_err(|_| {
+			// The kernel will not know about the inode after all
+			if let Ok(info) = self.files().get(ino) {
+				info.refsub(1).ok();
+			}
+		})?;
+		Ok((entry, Some(handle), opts))
+	}
+
+	fn mkdir(
+		&self,
+		ctx: &Context,
+		parent: Self::Inode,
+		name: &CStr,
+		mode: u32,
+		umask: u32,
+	) -> io::Result<Entry> {
+		log::trace!("mkdir(parent={:?}, name={:?}, pid={})", parent, name, ctx.pid);
+		let args = CreateIn {
+			flags: libc::O_EXCL as u32,
+			mode: mode | libc::S_IFDIR,
+			umask,
+			fuse_flags: 0,
+		};
+		self.create_inode(ctx, parent, name, args)
+	}
+
+	fn mknod(
+		&self,
+		ctx: &Context,
+		parent: Self::Inode,
+		name: &CStr,
+		mode: u32,
+		rdev: u32,
+		umask: u32,
+	) -> io::Result<Entry> {
+		log::trace!("mknod(parent={:?}, name={:?}, mode={:o}, rdev={:#x}, pid={})", parent, name, mode, rdev, ctx.pid);
+		// Directories are made through mkdir()
+		if mode & libc::S_IFMT == libc::S_IFDIR {
+			return Err(ioerr!(libc::EINVAL));
+		}
+		// The only character device supported is the 0,0 whiteout
+		// used by overlayfs
+		if mode & libc::S_IFMT == libc::S_IFCHR && rdev != 0 {
+			return Err(ioerr!(libc::EOPNOTSUPP));
+		}
+		let args = CreateIn {
+			flags: libc::O_EXCL as u32,
+			mode,
+			umask,
+			fuse_flags: 0,
+		};
+		self.create_inode(ctx, parent, name, args)
+	}
+
+	fn symlink(
+		&self,
+		ctx: &Context,
+		linkname: &CStr,
+		parent: Self::Inode,
+		name: &CStr,
+	) -> io::Result<Entry> {
+		log::trace!("symlink(parent={:?}, name={:?}, target={:?}, pid={})", parent, name, linkname, ctx.pid);
+		self.add_inode(parent, name, libc::O_EXCL as u32, |_| {
+			InodeInfo::symlink(parent, name, ctx, linkname.to_bytes())
+		})
+	}
+
+	fn readlink(&self, _ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+		log::trace!("readlink(inode={:?})", inode);
+		let files = self.files();
+		files.read_ino(inode, |info| Ok(info.symlink_target()?.to_vec()))
+	}
+
+	fn open(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		flags: u32,
+		_fuse_flags: u32,
+	) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+		log::trace!("open(inode={:?}, flags={:#x})", inode, flags);
+		if let Some(node) = self.info_node(inode) {
+			let dir = node == Node::Dir;
+			if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+				return Err(ioerr!(if dir { libc::EISDIR } else { libc::EACCES }));
+			}
+			let handle = self.handles.insert(OpenFile { ino: inode, dir })?;
+			// The contents change between reads, so bypass the page
+			// cache and its idea of the file size
+			return Ok((Some(handle), open_options(flags) | OpenOptions::DIRECT_IO));
+		}
+		// Hold the lock until the handle exists, so that the inode
+		// cannot be forgotten in between
+		let files = self.files();
+		let dir = files.get(inode)?.is_dir();
+		if dir && flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+			return Err(ioerr!(libc::EISDIR));
+		}
+		let handle = self.handles.insert(OpenFile { ino: inode, dir })?;
+		Ok((Some(handle), open_options(flags)))
+	}
+
+	fn opendir(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_flags: u32,
+	) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+		log::trace!("opendir(inode={:?})", inode);
+		let files = self.files();
+		let dir = match self.info_node(inode) {
+			Some(node) => node == Node::Dir,
+			None => files.get(inode)?.is_dir(),
+		};
+		if !dir {
+			return Err(ioerr!(libc::ENOTDIR));
+		}
+		let handle = self.handles.insert(OpenFile { ino: inode, dir: true })?;
+		Ok((Some(handle), OpenOptions::empty()))
+	}
+
+	fn release(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_flags: u32,
+		handle: Self::Handle,
+		_flush: bool,
+		_flock_release: bool,
+		_lock_owner: Option<u64>,
+	) -> io::Result<()> {
+		log::trace!("release(inode={:?}, handle={})", inode, handle);
+		self.close(handle)
+	}
+
+	fn releasedir(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_flags: u32,
+		handle: Self::Handle,
+	) -> io::Result<()> {
+		log::trace!("releasedir(inode={:?}, handle={})", inode, handle);
+		self.close(handle)
+	}
+
+	fn read(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_handle: Self::Handle,
+		w: &mut dyn ZeroCopyWriter,
+		size: u32,
+		offset: u64,
+		_lock_owner: Option<u64>,
+		_flags: u32,
+	) -> io::Result<usize> {
+		log::trace!("read(inode={:?}, sz={}, off={})", inode, size, offset);
+		let _timer = self.metrics.time(Op::Read);
+		self.inject_fault()?;
+		if size == 0 {
+			return Ok(0);
+		}
+		if let Some(node) = self.info_node(inode) {
+			let data = self.info_contents(node);
+			let start = (offset as usize).min(data.len());
+			let end = start.saturating_add(size as usize).min(data.len());
+			w.write_all(&data[start..end])?;
+			return Ok(end - start);
+		}
+		let mut files = self.files_mut();
+		files.write_ino(inode, |info| {
+			let start = offset as usize;
+			if !info.verify_sums(start, start.saturating_add(size as usize)) {
+				log::error!("Checksum mismatch in inode {:?}", inode);
+				return Err(ioerr!(libc::EIO));
+			}
+			let data = info.file_data()?.as_slice();
+			// Small reads are copied straight into the reply, which
+			// takes less work than going through a volatile slice
+			if size as usize <= SMALL_READ {
+				let end = start.saturating_add(size as usize).min(data.len());
+				let buf = data.get(start..end).unwrap_or_default();
+				w.write_all(buf)?;
+				return Ok(buf.len());
+			}
+			let mut reader = FileReader::new(data);
+			w.write_from(&mut reader, size as usize, offset)
+		})
+	}
+
+	fn write(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		handle: Self::Handle,
+		r: &mut dyn ZeroCopyReader,
+		size: u32,
+		offset: u64,
+		_lock_owner: Option<u64>,
+		_delayed_write: bool,
+		_flags: u32,
+		_fuse_flags: u32,
+	) -> io::Result<usize> {
+		log::trace!("write(inode={:?}, sz={}, off={})", inode, size, offset);
+		let _timer = self.metrics.time(Op::Write);
+		self.inject_fault()?;
+		if self.handles.get(handle)?.dir {
+			return Err(ioerr!(libc::EISDIR));
+		}
+		if size == 0 {
+			return Ok(0);
+		}
+		let mut files = self.files_mut();
+		files.write_ino(inode, |info| {
+			let unlinked = info.is_unlinked();
+			let data = info.file_data()?;
+			let old_len = data.len();
+			let mut writer = FileWriter::new(data);
+			// The reader may hand over the data in several pieces.
+			// Once some of it is written, report that much rather
+			// than an error, as write(2) would.
+			let size = size as usize;
+			let mut n = 0;
+			let mut res = Ok(());
+			while n < size {
+				match r.read_to(&mut writer, size - n, offset + n as u64) {
+					Ok(0) => break,
+					Ok(done) => n += done,
+					Err(e) => {
+						if n == 0 {
+							res = Err(e);
+						}
+						break;
+					}
+				}
+			}
+			// Only sum what was written, so that a short write does
+			// not hide corruption past it
+			let start = offset as usize;
+			info.update_sums(old_len.min(start), start + n);
+			res?;
+			// Changes to removed files are lost on restart anyway
+			if !unlinked {
+				self.record(|| Record::Write {
+					ino: inode,
+					offset,
+					data: info.file_contents().unwrap()[start..start + n].to_vec(),
+				})?;
+			}
+			Ok(n)
+		})
+	}
+
+	fn lookup(
+		&self,
+		_ctx: &Context,
+		parent: Self::Inode,
+		name: &CStr,
+	) -> io::Result<Entry> {
+		log::trace!("lookup(parent={:?}, name={:?})", parent, name);
+		let _timer = self.metrics.time(Op::Lookup);
+		if self.info.is_some() {
+			if let Some(node) = Node::lookup(parent, name.to_bytes()) {
+				return Ok(node.get_entry(self.info_contents(node).len()));
+			}
+		}
+		// The lookup count is atomic, so go through the read lock
+		let files = self.files();
+		// Missing names fail with ENOENT rather than returning an
+		// entry with inode 0, which the kernel would cache as a
+		// negative entry for the entry timeout. The kernel does not
+		// cache errors, so a name created after a failed lookup is
+		// found right away, even if created without going through
+		// the kernel.
+		files.read_name(parent, name, |(ino, info)| {
+			info.refinc()?;
+			Ok(info.get_entry(ino))
+		})
+	}
+
+	fn forget(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		count: u64,
+	) {
+		log::trace!("forget(inode={:?}, count={})", inode, count);
+		let mut files = self.files_mut();
+		self.forget_one(&mut files, inode, count);
+	}
+
+	fn batch_forget(
+		&self,
+		_ctx: &Context,
+		requests: Vec<(Self::Inode, u64)>,
+	) {
+		log::trace!("batch_forget()");
+		// Apply all the lookups dropped for an inode at once, should
+		// it be listed more than once
+		let mut counts: HashMap<Inode, u64> = HashMap::with_capacity(requests.len());
+		for (ino, count) in requests.into_iter() {
+			let total = counts.entry(ino).or_default();
+			*total = total.saturating_add(count);
+		}
+		let mut files = self.files_mut();
+		for (ino, count) in counts {
+			self.forget_one(&mut files, ino, count);
+		}
+	}
+
+	fn flush(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_handle: Self::Handle,
+		_lock_owner: u64,
+	) -> io::Result<()> {
+		log::trace!("flush(inode={:?})", inode);
+		if self.files().contents.is_some() {
+			self.files_mut().dedup(inode);
+		}
+		match self.journal.as_ref() {
+			Some(journal) => journal.flush(),
+			None => Ok(()),
+		}
+	}
+
+	fn fsync(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_datasync: bool,
+		_handle: Self::Handle,
+	) -> io::Result<()> {
+		log::trace!("fsync(inode={:?})", inode);
+		// Data written back by the kernel is already in memory, so
+		// there is only something to do if changes are journaled
+		match self.journal.as_ref() {
+			Some(journal) => journal.sync(),
+			None => Ok(()),
+		}
+	}
+
+	fn fsyncdir(
+		&self,
+		ctx: &Context,
+		inode: Self::Inode,
+		datasync: bool,
+		handle: Self::Handle,
+	) -> io::Result<()> {
+		self.fsync(ctx, inode, datasync, handle)
+	}
+
+	fn getattr(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_handle: Option<Self::Handle>,
+	) -> io::Result<(stat64, Duration)> {
+		log::trace!("getattr({:?})", inode);
+		if let Some(node) = self.info_node(inode) {
+			let size = self.info_contents(node).len();
+			return Ok((node.stat64(size), Duration::ZERO));
+		}
+		let files = self.files();
+		files.read_ino(inode, |info| {
+			Ok((info.stat64(inode), TIMEOUT_SECS))
+		})
+	}
+
+	fn setattr(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		attr: stat64,
+		_handle: Option<Self::Handle>,
+		valid: SetattrValid,
+	) -> io::Result<(stat64, Duration)> {
+		log::trace!("setattr(inode={:?}, valid={:?})", inode, valid);
+
+		let mut files = self.files_mut();
+		if valid.contains(SetattrValid::SIZE) {
+			let len = u64::try_from(attr.st_size).map_err(|_| ioerr!(libc::EINVAL))?;
+			files.truncate(inode, len)?;
+		}
+		files.write_ino(inode, |info| {
+			if valid.contains(SetattrValid::UID) {
+				info.owner.uid = attr.st_uid;
+			}
+			if valid.contains(SetattrValid::GID) {
+				info.owner.gid = attr.st_gid;
+			}
+			if valid.contains(SetattrValid::MODE) {
+				info.perm = FsPerm::try_from(attr.st_mode)?;
+				debug_assert_eq!(info.st_mode(), attr.st_mode);
+				if let Some(mut acl) = Acl::get(&info.xattrs, ACL_ACCESS)? {
+					acl.chmod(attr.st_mode);
+					info.xattrs.set(ACL_ACCESS, &acl.encode(), 0)?;
+				}
+			}
+
+			let logged = valid & (SetattrValid::UID
+				| SetattrValid::GID
+				| SetattrValid::MODE
+				| SetattrValid::SIZE);
+			if !logged.is_empty() && !info.is_unlinked() {
+				self.record(|| Record::Setattr {
+					ino: inode,
+					valid: logged.bits(),
+					mode: attr.st_mode,
+					uid: attr.st_uid,
+					gid: attr.st_gid,
+					size: attr.st_size as u64,
+				})?;
+			}
+
+			Ok((info.stat64(inode), TIMEOUT_SECS))
+		})
+	}
+
+	fn rmdir(
+		&self,
+		ctx: &Context,
+		parent: Self::Inode,
+		name: &CStr,
+	) -> io::Result<()> {
+		log::trace!("rmdir(parent={:?}, name={:?}, pid={})", parent, name, ctx.pid);
+		if self.info.is_some() && Node::lookup(parent, name.to_bytes()).is_some() {
+			return Err(ioerr!(libc::EBUSY));
+		}
+		let mut files = self.files_mut();
+		// Checked under the same lock as the removal, so that entries
+		// created in the directory meanwhile are not cut off from the
+		// tree along with it
+		if !files.read_name(parent, name, |(_, info)| Ok(info.children()?.is_empty()))? {
+			return Err(ioerr!(libc::ENOTEMPTY));
+		}
+		let child = files.unlink_inode(parent, name)?;
+		self.release_unused(&mut files, child);
+		self.record(|| Record::Unlink {
+			parent,
+			name: name.to_bytes().to_vec(),
+		})
+	}
+
+	fn unlink(
+		&self,
+		ctx: &Context,
+		parent: Self::Inode,
+		name: &CStr,
+	) -> io::Result<()> {
+		log::trace!("unlink(parent={:?}, name={:?}, pid={})", parent, name, ctx.pid);
+		self.check_info_parent(parent)?;
+		let mut files = self.files_mut();
+		let child = files.unlink_inode(parent, name)?;
+		self.release_unused(&mut files, child);
+		self.record(|| Record::Unlink {
+			parent,
+			name: name.to_bytes().to_vec(),
+		})
+	}
+
+	fn statfs(&self, _ctx: &Context, inode: Self::Inode) -> io::Result<statvfs64> {
+		log::trace!("statfs(inode={:?})", inode);
+		let mut st: statvfs64 = unsafe { std::mem::zeroed() };
+		st.f_namemax = NAME_MAX as u64;
+		st.f_bsize = IO_BLOCK as u64;
+		st.f_frsize = ST_BLOCK as u64;
+
+		// Without a limit, report plenty of room so that tools
+		// checking for free inodes do not give up
+		let files = self.files();
+		let max = files.max_inodes.map_or(UNLIMITED_INODES, |max| max as u64);
+		st.f_files = max;
+		st.f_ffree = max.saturating_sub(files.used() as u64);
+		st.f_favail = st.f_ffree;
+		Ok(st)
+	}
+
+	fn access(&self, ctx: &Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+		log::trace!("access(inode={:?}, mask={:#o})", inode, mask);
+		if let Some(node) = self.info_node(inode) {
+			// Everyone gets the owner bits
+			return match mask & !(node.perm() >> 6) {
+				0 => Ok(()),
+				_ => Err(ioerr!(libc::EACCES)),
+			};
+		}
+		let files = self.files();
+		files.read_ino(inode, |info| {
+			match info.permits(ctx.uid, ctx.gid, mask)? {
+				true => Ok(()),
+				false => Err(ioerr!(libc::EACCES)),
+			}
+		})
+	}
+
+	fn setxattr(
+		&self,
+		ctx: &Context,
+		inode: Self::Inode,
+		name: &CStr,
+		value: &[u8],
+		flags: u32,
+	) -> io::Result<()> {
+		log::trace!("setxattr(inode={:?}, name={:?}, flags={:#x})", inode, name, flags);
+		xattr::check_access(name.to_bytes(), ctx.uid, self.all_xattr_namespaces)?;
+		let mut files = self.files_mut();
+		files.write_ino(inode, |info| {
+			match name.to_bytes() {
+				// Keep the permission bits in sync, and only store the
+				// ACL if it says more than them
+				ACL_ACCESS => {
+					let acl = Acl::parse(value)?;
+					info.perm = FsPerm::try_from(acl.mode())?;
+					if acl.is_minimal() {
+						let _ = info.xattrs.remove(ACL_ACCESS);
+					} else {
+						info.xattrs.set(ACL_ACCESS, value, flags)?;
+					}
+				}
+				ACL_DEFAULT if !info.is_dir() => return Err(ioerr!(libc::EACCES)),
+				ACL_DEFAULT => {
+					Acl::parse(value)?;
+					info.xattrs.set(ACL_DEFAULT, value, flags)?;
+				}
+				name => info.xattrs.set(name, value, flags)?,
+			}
+			if !info.is_unlinked() {
+				self.record(|| Record::Setxattr {
+					ino: inode,
+					name: name.to_bytes().to_vec(),
+					value: value.to_vec(),
+				})?;
+			}
+			Ok(())
+		})
+	}
+
+	fn getxattr(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		name: &CStr,
+		size: u32,
+	) -> io::Result<GetxattrReply> {
+		log::trace!("getxattr(inode={:?}, name={:?}, size={})", inode, name, size);
+		if self.info_node(inode).is_some() {
+			return Err(ioerr!(libc::ENODATA));
+		}
+		let files = self.files();
+		files.read_ino(inode, |info| {
+			let value = info.xattrs.get(name.to_bytes())?;
+			match size {
+				0 => Ok(GetxattrReply::Count(value.len() as u32)),
+				s if (s as usize) < value.len() => Err(ioerr!(libc::ERANGE)),
+				_ => Ok(GetxattrReply::Value(value.to_vec())),
+			}
+		})
+	}
+
+	fn listxattr(&self, _ctx: &Context, inode: Self::Inode, size: u32) -> io::Result<ListxattrReply> {
+		log::trace!("listxattr(inode={:?}, size={})", inode, size);
+		if self.info_node(inode).is_some() {
+			return Ok(match size {
+				0 => ListxattrReply::Count(0),
+				_ => ListxattrReply::Names(Vec::new()),
+			});
+		}
+		let files = self.files();
+		files.read_ino(inode, |info| {
+			let names = info.xattrs.names();
+			match size {
+				0 => Ok(ListxattrReply::Count(names.len() as u32)),
+				s if (s as usize) < names.len() => Err(ioerr!(libc::ERANGE)),
+				_ => Ok(ListxattrReply::Names(names)),
+			}
+		})
+	}
+
+	fn removexattr(&self, ctx: &Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+		log::trace!("removexattr(inode={:?}, name={:?})", inode, name);
+		xattr::check_access(name.to_bytes(), ctx.uid, self.all_xattr_namespaces)?;
+		let mut files = self.files_mut();
+		files.write_ino(inode, |info| {
+			info.xattrs.remove(name.to_bytes())?;
+			if !info.is_unlinked() {
+				self.record(|| Record::Removexattr {
+					ino: inode,
+					name: name.to_bytes().to_vec(),
+				})?;
+			}
+			Ok(())
+		})
+	}
+
+	fn ioctl(
+		&self,
+		_ctx: &Context,
+		inode: Self::Inode,
+		_handle: Self::Handle,
+		_flags: u32,
+		cmd: u32,
+		_data: IoctlData<'_>,
+		out_size: u32,
+	) -> io::Result<IoctlData<'_>> {
+		log::trace!("ioctl(inode={:?}, cmd={:#x})", inode, cmd);
+		// SAFETY: for every reply, the server writes it out before the
+		// thread handles its next request.
+		match cmd {
+			SLABFS_IOC_STATS => {
+				let stats = self.files().stats();
+				unsafe { ioctl::reply(&stats, out_size) }
+			}
+			SLABFS_IOC_LATENCY => match self.metrics.latency() {
+				Some(latency) => unsafe { ioctl::reply(&latency, out_size) },
+				None => Err(ioerr!(libc::ENOTTY)),
+			},
+			SLABFS_IOC_DUMP_TREE => {
+				let files = self.files();
+				let (json, complete) = tree::dump(&files, inode, TREE_DUMP_MAX)?;
+				drop(files);
+				let mut dump = TreeDump::default();
+				dump.json[..json.len()].copy_from_slice(json.as_bytes());
+				dump.len = json.len() as u64;
+				dump.truncated = !complete as u64;
+				unsafe { ioctl::reply(&dump, out_size) }
+			}
+			_ => Err(ioerr!(libc::ENOTTY)),
+		}
+	}
+}
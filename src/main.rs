@@ -1,503 +1,431 @@
-mod error;
-mod file_entry;
-mod file_io;
-mod inode;
-mod perm;
-
-use crate::{
-	error::FsErr,
-	file_entry::{FsEntry, FsType},
-	file_io::{FileReader, FileWriter},
-	inode::{Inode, InodeInfo},
-	perm::{FsOwner, FsPerm},
-};
+mod opts;
+mod sem;
+
+use crate::{opts::Opts, sem::Semaphore};
 use core::time::Duration;
-use fastcmp::Compare;
-use fuse_backend_rs::abi::fuse_abi::{CreateIn, FsOptions, stat64};
-use fuse_backend_rs::api::filesystem::{
-	Context,
-	DirEntry,
-	Entry,
-	FileSystem,
-	OpenOptions,
-	SetattrValid,
-	ZeroCopyReader,
-	ZeroCopyWriter,
-};
+use fuse_backend_rs::api::filesystem::FileSystem;
 use fuse_backend_rs::api::server::Server;
 use fuse_backend_rs::transport::{FuseChannel, FuseSession};
-use slab::Slab;
-use std::ffi::CStr;
+use slabfs::{error::FsErr, ioctl, ioerr, worker, SlabFs};
+use std::ffi::CString;
+use std::fs::File;
 use std::io;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Instant;
 
-const ST_DEV: u64 = 666420;
-const TIMEOUT_SECS: Duration = Duration::from_secs(10000);
+// Number of threads serving requests, or 0 for one per CPU
 const NUM_THREADS: usize = 1;
-
-#[macro_export]
-macro_rules! ioerr {
-	($k:ident) => {
-		std::io::Error::from(std::io::ErrorKind::$k)
-	};
-	($k:ident, $e:expr) => {
-		std::io::Error::new(std::io::ErrorKind::$k, $e)
-	};
-	($k:expr) => {
-		std::io::Error::from_raw_os_error($k)
-	};
+const FUSE_DEV_MAJOR: u32 = 10;
+const FUSE_DEV_MINOR: u32 = 229;
+const SIGNAL_POLL: Duration = Duration::from_millis(100);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of server threads to start for a request of `n`, where 0
+/// means one per CPU. Never less than one, as nothing would serve
+/// requests otherwise.
+fn worker_count(n: usize) -> usize {
+	match n {
+		0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+		n => n,
+	}
 }
 
-#[derive(Debug)]
-struct FsFiles {
-	files: Slab<InodeInfo>,
+fn svc_loop(
+	srv: Arc<Server<Arc<SlabFs>>>,
+	channel: FuseChannel,
+	inflight: Option<Arc<Semaphore>>,
+	shutdown: Arc<AtomicBool>,
+) {
+	worker::run(&srv, channel, &shutdown, || inflight.as_ref().map(|sem| sem.acquire()));
 }
 
-impl FsFiles {
-	fn new() -> Self {
-		Self {
-			files: Slab::with_capacity(256),
-		}
+/// Take ownership of an inherited, already open /dev/fuse descriptor.
+fn fuse_dev_from_fd(fd: RawFd) -> io::Result<File> {
+	let mut st: libc::stat = unsafe { std::mem::zeroed() };
+	if unsafe { libc::fstat(fd, &mut st) } < 0 {
+		return Err(io::Error::last_os_error());
 	}
-
-	#[inline(always)]
-	fn get(&self, ino: Inode) -> io::Result<&InodeInfo> {
-		let idx = usize::from(ino);
-		self.files.get(idx).ok_or(ioerr!(NotFound))
+	let dev = unsafe { (libc::major(st.st_rdev), libc::minor(st.st_rdev)) };
+	if st.st_mode & libc::S_IFMT != libc::S_IFCHR || dev != (FUSE_DEV_MAJOR, FUSE_DEV_MINOR) {
+		return Err(ioerr!(InvalidInput, format!("fd {} is not a FUSE device", fd)));
 	}
 
-	#[inline(always)]
-	fn get_mut(&mut self, ino: Inode) -> io::Result<&mut InodeInfo> {
-		let idx = usize::from(ino);
-		self.files.get_mut(idx).ok_or(ioerr!(NotFound))
+	let file = unsafe { File::from_raw_fd(fd) };
+	// Channels expect a non-blocking device, as set up by mount()
+	let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+	if flags < 0
+		|| unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+	{
+		return Err(io::Error::last_os_error());
 	}
+	Ok(file)
+}
 
-	#[inline(always)]
-	unsafe fn get_unchecked_mut(&mut self, ino: Inode) -> &mut InodeInfo {
-		let idx = usize::from(ino);
-		self.files.get_unchecked_mut(idx)
-	}
+/// Set up a session for an inherited /dev/fuse descriptor. The
+/// filesystem is already mounted, so any access to the mountpoint
+/// would block until we start serving requests; give the session an
+/// inert path instead. Sessions unmount their path when dropped, so
+/// this one must never be dropped.
+fn fd_session(fd: RawFd) -> Result<ManuallyDrop<FuseSession>, FsErr> {
+	let mut sess = ManuallyDrop::new(FuseSession::new(Path::new("/"), "slabfs", "", false)?);
+	sess.set_fuse_file(fuse_dev_from_fd(fd)?);
+	Ok(sess)
+}
 
-	#[inline(always)]
-	unsafe fn get_unchecked(&self, ino: Inode) -> &InodeInfo {
-		let idx = usize::from(ino);
-		self.files.get_unchecked(idx)
+/// Block termination signals in the calling thread, and in any
+/// thread it spawns afterwards, so that they can be waited on with
+/// `wait_signal()`.
+fn block_signals() -> libc::sigset_t {
+	unsafe {
+		let mut set: libc::sigset_t = std::mem::zeroed();
+		libc::sigemptyset(&mut set);
+		libc::sigaddset(&mut set, libc::SIGINT);
+		libc::sigaddset(&mut set, libc::SIGTERM);
+		libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+		set
 	}
+}
 
-	fn remove(&mut self, ino: Inode) {
-		let idx = usize::from(ino);
-		self.files.remove(idx);
-	}
+fn wait_signal(set: &libc::sigset_t, timeout: Duration) -> Option<i32> {
+	let ts = libc::timespec {
+		tv_sec: timeout.as_secs() as _,
+		tv_nsec: timeout.subsec_nanos() as _,
+	};
+	let sig = unsafe { libc::sigtimedwait(set, std::ptr::null_mut(), &ts) };
+	(sig > 0).then_some(sig)
+}
 
-	fn insert_and_get(&mut self, info: InodeInfo) -> (Inode, Entry) {
-		let slot = self.files.vacant_entry();
-		let ino = Inode::from(slot.key());
-		let entry = slot.insert(info).get_entry(ino);
-		(ino, entry)
+/// Wait for the service threads to finish their current request and
+/// exit. Returns false if some did not do so in time.
+fn drain(thrds: &[JoinHandle<()>], timeout: Duration) -> bool {
+	let deadline = Instant::now() + timeout;
+	while !thrds.iter().all(|t| t.is_finished()) {
+		if Instant::now() >= deadline {
+			return false;
+		}
+		std::thread::sleep(Duration::from_millis(10));
 	}
+	true
+}
 
-	fn insert(&mut self, info: InodeInfo) -> Inode {
-		Inode::from(self.files.insert(info))
+fn compact_loop(fs: Arc<SlabFs>, interval: Duration) {
+	loop {
+		std::thread::sleep(interval);
+		let (moved, reclaimed) = fs.compact();
+		log::info!("Compaction moved {} inodes and reclaimed {} slots", moved, reclaimed);
 	}
+}
 
-	fn unlink_inode(&mut self, parent: Inode, name: &CStr) -> io::Result<()> {
-		let pinfo = self.get_mut(parent)?;
-		let children = pinfo.children_mut()?;
-		let name_bytes = name.to_bytes();
-		let idx = children
-			.iter()
-			.position(|(_, cname)| cname.feq(name_bytes))
-			.ok_or(ioerr!(NotFound))?;
-		children.swap_remove(idx);
-		Ok(())
+fn checkpoint_loop(fs: Arc<SlabFs>, interval: Duration) {
+	loop {
+		std::thread::sleep(interval);
+		match fs.checkpoint() {
+			Ok(()) => log::info!("Checkpointed journal"),
+			Err(e) => log::error!("Journal checkpoint failed: {}", e),
+		}
 	}
+}
 
-	#[inline(always)]
-	fn read_ino<F, T>(&self, ino: Inode, f: F) -> io::Result<T>
-	where
-		F: Fn(&InodeInfo) -> io::Result<T>,
-		T: Sized,
-	{
-		self.get(ino).and_then(f)
-	}
+fn usage() -> ! {
+	let prog = std::env::args().next().unwrap();
+	eprintln!(
+		"Usage: {} [--compact-interval <secs>] [--initial-inodes <n>] [--max-inodes <n>] \
+		 [--max-open-files <n>] [--name-cache-entries <n>] [--intern-names] [--dedup] [--constant-time-names] [--verify-reads] \
+		 [--no-splice] [--allow-all-xattr-namespaces] [--journal <path>] \
+		 [--coalesce-writes] [--checkpoint-interval <secs>] [--load-tree <path>] [--info-dir] [--max-inflight <n>] \
+		 [--fsname <name>] [--subtype <type>] [--fault-inject <rate>,<errno>[,<seed>]] <mountpoint>[:<options>]...",
+		prog
+	);
+	eprintln!("       {} [options] --fd <n>", prog);
+	eprintln!("       {} --stats <path>", prog);
+	eprintln!("       {} --dump-tree <path>", prog);
+	std::process::exit(0)
+}
 
-	#[inline(always)]
-	fn write_ino<F, T>(&mut self, ino: Inode, f: F) -> io::Result<T>
-	where
-		F: FnMut(&mut InodeInfo) -> io::Result<T>,
-		T: Sized,
-	{
-		self.get_mut(ino).and_then(f)
+/// Check that `path` can be mounted on, to report problems more
+/// clearly than the transport would.
+fn check_mountpoint(path: &Path) -> Result<(), FsErr> {
+	let bad = |why: &str| FsErr::Mountpoint(format!("{}: {}", path.display(), why));
+	let meta = match std::fs::metadata(path) {
+		Ok(meta) => meta,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(bad("no such directory")),
+		Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+			return Err(bad("permission denied"));
+		}
+		Err(e) => return Err(e.into()),
+	};
+	if !meta.is_dir() {
+		return Err(bad("not a directory"));
+	}
+	// Users other than root may only mount on directories they can
+	// write to
+	let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|_| bad("invalid path"))?;
+	if unsafe { libc::geteuid() != 0 && libc::access(cpath.as_ptr(), libc::W_OK) < 0 } {
+		return Err(bad("no write permission"));
 	}
+	if std::fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_some()) {
+		log::warn!("{} is not empty, its contents are hidden while mounted", path.display());
+	}
+	Ok(())
+}
 
-	#[allow(unused)]
-	fn write_name<F, T>(&mut self, parent: Inode, name: &CStr, mut f: F) -> io::Result<T>
-	where
-		F: FnMut((Inode, &mut InodeInfo)) -> io::Result<T>,
-		T: Sized,
-	{
-		let ino = self.get(parent)?
-			.children()?
-			.iter()
-			.find_map(|(ino, cname)| {
-				cname.feq(name.to_bytes()).then_some(*ino)
-			})
-			.ok_or(ioerr!(NotFound))?;
-		let info = unsafe { self.get_unchecked_mut(ino) };
-		f((ino, info))
-	}
-
-	#[inline(always)]
-	fn read_name<F, T>(&self, parent: Inode, name: &CStr, f: F) -> io::Result<T>
-	where
-		F: Fn((Inode, &InodeInfo)) -> io::Result<T>,
-		T: Sized,
-	{
-		let name_bytes = name.to_bytes();
-		for (child, cname) in self.get(parent)?.children()? {
-			if cname.feq(name_bytes) {
-				let info = if cfg!(debug_assertions) {
-					self.get(*child).expect("Stale child")
-				} else {
-					unsafe { self.get_unchecked(*child) }
-				};
-				return f((*child, info));
-			}
+/// Build the filesystem for the mount described by `opts`, mount it
+/// and start its background threads.
+fn start(opts: &Opts) -> Result<(Arc<SlabFs>, ManuallyDrop<FuseSession>), FsErr> {
+	let mut builder = SlabFs::builder()
+		.intern_names(opts.intern_names)
+		.dedup(opts.dedup)
+		.constant_time_names(opts.constant_time_names)
+		.verify_reads(opts.verify_reads)
+		.splice(!opts.no_splice)
+		.all_xattr_namespaces(opts.allow_all_xattr_namespaces)
+		.info_dir(opts.info_dir);
+	if let Some(n) = opts.initial_inodes {
+		builder = builder.initial_inodes(n);
+	}
+	if let Some(n) = opts.max_inodes {
+		builder = builder.max_inodes(n);
+	}
+	if let Some(n) = opts.max_open_files {
+		builder = builder.max_open_files(n);
+	}
+	if let Some(n) = opts.name_cache_entries {
+		builder = builder.name_cache(n);
+	}
+	if let Some(path) = opts.journal.as_ref() {
+		builder = builder.journal(path).coalesce_writes(opts.coalesce_writes);
+	}
+	if let Some(path) = opts.load_tree.as_ref() {
+		builder = builder.load_tree(path);
+	}
+	#[cfg(feature = "fault-inject")]
+	if let Some((rate, errno, seed)) = opts.fault_inject {
+		log::warn!("Failing {}% of reads, writes and creates with errno {}", rate * 100.0, errno);
+		builder = builder.fault_inject(rate, errno, seed);
+	}
+	let fs = builder.build()?;
+	let fs = Arc::new(fs);
+	let sess = match opts.fd {
+		Some(fd) => fd_session(fd)?,
+		None => {
+			let mut sess = FuseSession::new_with_autounmount(
+				Path::new(&opts.mountpoint),
+				opts.fsname.as_deref().unwrap_or("slabfs"),
+				opts.subtype.as_deref().unwrap_or(""),
+				false,
+				true,
+			)?;
+			sess.mount()?;
+			ManuallyDrop::new(sess)
 		}
+	};
 
-		Err(ioerr!(NotFound))
+	if let Some(interval) = opts.checkpoint_interval {
+		let fs = fs.clone();
+		std::thread::Builder::new()
+			.name("checkpoint".to_string())
+			.spawn(move || checkpoint_loop(fs, interval))
+			.unwrap();
 	}
-}
 
-#[derive(Debug)]
-struct SlabFs {
-	files: RwLock<FsFiles>,
+	if let Some(interval) = opts.compact_interval {
+		let fs = fs.clone();
+		std::thread::Builder::new()
+			.name("compaction".to_string())
+			.spawn(move || compact_loop(fs, interval))
+			.unwrap();
+	}
+	Ok((fs, sess))
 }
 
-impl SlabFs {
-	fn new() -> Self {
-		let fs = Self {
-			files: RwLock::new(FsFiles::new()),
-		};
-		fs.insert_entry(InodeInfo::empty());
-		fs.insert_entry(InodeInfo::dir("/").unwrap());
-		fs
+/// Unmount the filesystems of `sessions`, unless they were mounted
+/// by whoever passed us the descriptor.
+fn unmount(sessions: Vec<ManuallyDrop<FuseSession>>, inherited: bool) {
+	if inherited {
+		return;
 	}
-
-	fn insert_entry(&self, info: InodeInfo) -> Inode {
-		self.files.write().unwrap().insert(info)
+	for sess in sessions {
+		drop(ManuallyDrop::into_inner(sess));
 	}
 }
 
-impl FileSystem for SlabFs {
-	type Inode = Inode;
-	type Handle = u64;
-
-	fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
-		log::trace!("init(capable={:?})", _capable);
-		let mut cap = FsOptions::empty();
-		cap.set(FsOptions::HAS_IOCTL_DIR, true);
-		cap.set(FsOptions::ABORT_ERROR, true);
-		cap.set(FsOptions::ASYNC_READ, true);
-		cap.set(FsOptions::ASYNC_DIO, true);
-		cap.set(FsOptions::BIG_WRITES, true);
-		cap.set(FsOptions::PARALLEL_DIROPS, true);
-		cap.set(FsOptions::ZERO_MESSAGE_OPEN, true);
-		//cap.set(FsOptions::DO_READDIRPLUS, true);
-		cap.set(FsOptions::WRITEBACK_CACHE, true);
-		//cap.set(FsOptions::EXPLICIT_INVAL_DATA, true);
-		cap.set(FsOptions::SPLICE_READ, true);
-		cap.set(FsOptions::SPLICE_WRITE, true);
-		cap.set(FsOptions::SPLICE_MOVE, true);
-		Ok(cap)
-	}
-
-	fn readdir(
-		&self,
-		_ctx: &Context,
-		inode: Self::Inode,
-		_handle: Self::Handle,
-		size: u32,
-		offset: u64,
-		add_entry: &mut dyn FnMut(DirEntry<'_>) -> io::Result<usize>,
-	) -> io::Result<()> {
-		log::trace!(
-			"readdir(inode={:?}, off={}, size={})",
-			inode, offset, size
-		);
-
-		let size = size as usize;
-		let offset = offset as usize;
-		if size == 0 {
-			return Ok(());
+fn main() -> Result<(), FsErr> {
+	env_logger::init();
+
+	let opts = match Opts::parse(std::env::args().skip(1)) {
+		Ok(opts) => opts,
+		Err(e) => {
+			eprintln!("{}", e);
+			usage();
 		}
+	};
 
-		let files = self.files.read().unwrap();
-		for (i, (child, _)) in files.get(inode)?
-			.children()?
-			.iter()
-			.enumerate()
-			.skip(offset)
-		{
-			let info = files.get(*child).expect("Stale child?");
-			let dir_entry = info.get_direntry(*child, (i as u64) + 1);
-			if add_entry(dir_entry)? == 0 {
-				break;
+	if opts.stats {
+		let stats = ioctl::query_stats(Path::new(&opts.mountpoint))?;
+		println!("capacity: {}", stats.capacity);
+		println!("occupied: {}", stats.occupied);
+		println!("vacant:   {}", stats.vacant);
+		println!("peak:     {}", stats.peak);
+		println!("dedup:    {} bytes saved", stats.dedup_saved);
+		println!("created:  {}", stats.created);
+		println!("removed:  {}", stats.removed);
+		// Only available with the metrics feature
+		if let Ok(lat) = ioctl::query_latency(Path::new(&opts.mountpoint)) {
+			println!("latency (ns):  count      p50      p90      p99      max");
+			for (op, l) in [
+				("read", lat.read),
+				("write", lat.write),
+				("lookup", lat.lookup),
+				("readdir", lat.readdir),
+			] {
+				println!(
+					"  {:<8} {:>10} {:>8} {:>8} {:>8} {:>8}",
+					op, l.count, l.p50_ns, l.p90_ns, l.p99_ns, l.max_ns
+				);
 			}
 		}
-
-		Ok(())
+		return Ok(());
 	}
 
-	fn create(
-		&self,
-		ctx: &Context,
-		parent: Self::Inode,
-		name: &CStr,
-		args: CreateIn,
-	) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
-		log::trace!("create(parent={:?}, name={:?})", parent, name);
-		let info = InodeInfo::create(name, ctx, args)?;
-
-		let mut files = self.files.write().unwrap();
-		let (ino, entry) = files.insert_and_get(info);
-
-		// Add to parent
-		if let Err(e) = files.write_ino(parent, |pinfo| {
-			pinfo.add_child(ino, name)
-		}) {
-			files.remove(ino);
-			return Err(e);
+	if let Some(path) = opts.dump_tree.as_ref() {
+		let (tree, complete) = ioctl::query_tree(Path::new(path))?;
+		println!("{}", tree);
+		if !complete {
+			eprintln!("Output truncated, dump a subdirectory to see more");
 		}
+		return Ok(());
+	}
 
-		Ok((entry, None, OpenOptions::empty()))
-	}
-
-	fn mkdir(
-		&self,
-		ctx: &Context,
-		parent: Self::Inode,
-		name: &CStr,
-		mode: u32,
-		umask: u32,
-	) -> io::Result<Entry> {
-		log::trace!("mkdir(parent={:?}, name={:?})", parent, name);
-		let args = CreateIn {
-			flags: 0,
-			mode: mode | libc::S_IFDIR,
-			umask,
-			fuse_flags: 0,
-		};
-		let (entry, _, _) = self.create(ctx, parent, name, args)?;
-		Ok(entry)
-	}
-
-	fn read(
-		&self,
-		_ctx: &Context,
-		inode: Self::Inode,
-		_handle: Self::Handle,
-		w: &mut dyn ZeroCopyWriter,
-		size: u32,
-		offset: u64,
-		_lock_owner: Option<u64>,
-		_flags: u32,
-	) -> io::Result<usize> {
-		log::trace!("read(inode={:?}, sz={}, off={})", inode, size, offset);
-		let mut files = self.files.write().unwrap();
-		files.write_ino(inode, |info| {
-			let data = info.file_data()?;
-			let mut reader = FileReader::new(data);
-			w.write_from(&mut reader, size as usize, offset)
-		})
-	}
-
-	fn write(
-		&self,
-		_ctx: &Context,
-		inode: Self::Inode,
-		_handle: Self::Handle,
-		r: &mut dyn ZeroCopyReader,
-		size: u32,
-		offset: u64,
-		_lock_owner: Option<u64>,
-		_delayed_write: bool,
-		_flags: u32,
-		_fuse_flags: u32,
-	) -> io::Result<usize> {
-		log::trace!("write(inode={:?}, sz={}, off={})", inode, size, offset);
-		let mut files = self.files.write().unwrap();
-		files.write_ino(inode, |info| {
-			let data = info.file_data()?;
-			let mut writer = FileWriter::new(data);
-			r.read_to(&mut writer, size as usize, offset)
-		})
-	}
-
-	fn lookup(
-		&self,
-		_ctx: &Context,
-		parent: Self::Inode,
-		name: &CStr,
-	) -> io::Result<Entry> {
-		log::trace!("lookup(parent={:?}, name={:?})", parent, name);
-		// The lookup count is atomic, so go through the read lock
-		let files = self.files.read().unwrap();
-		files.read_name(parent, name, |(ino, info)| {
-			info.refinc()?;
-			Ok(info.get_entry(ino))
-		})
-	}
-
-	fn forget(
-		&self,
-		_ctx: &Context,
-		inode: Self::Inode,
-		count: u64,
-	) {
-		log::trace!("forget(inode={:?}, count={})", inode, count);
-		let mut files = self.files.write().unwrap();
-		let deleted = files.read_ino(inode, |info| {
-			info.refsub(count)
-		}).unwrap();
-		if deleted {
-			files.remove(inode);
+	let sigs = block_signals();
+	let shutdown = Arc::new(AtomicBool::new(false));
+	let inflight = opts.max_inflight.map(|n| Arc::new(Semaphore::new(n)));
+	// Catch bad mountpoints before mounting anything
+	if opts.fd.is_none() {
+		for m in opts.mounts.iter() {
+			check_mountpoint(Path::new(&m.mountpoint))?;
 		}
 	}
-
-	fn batch_forget(
-		&self,
-		_ctx: &Context,
-		requests: Vec<(Self::Inode, u64)>,
-	) {
-		log::trace!("batch_forget()");
-		let mut files = self.files.write().unwrap();
-		for (ino, count) in requests.into_iter() {
-			let deleted = files.write_ino(ino, |info| {
-				info.refsub(count)
-			}).unwrap();
-			if deleted {
-				files.remove(ino);
+	let mut sessions = Vec::with_capacity(opts.mounts.len());
+	let mut filesystems = Vec::with_capacity(opts.mounts.len());
+	let mut thrds = Vec::new();
+	for m in opts.mounts.iter() {
+		let (fs, sess) = match start(m) {
+			Ok(started) => started,
+			Err(e) => {
+				unmount(sessions, opts.fd.is_some());
+				return Err(e);
 			}
+		};
+		let server = Arc::new(Server::new(fs.clone()));
+		filesystems.push(fs);
+		for _ in 0..worker_count(NUM_THREADS) {
+			let srv = server.clone();
+			let ch = sess.new_channel().unwrap();
+			let sem = inflight.clone();
+			let stop = shutdown.clone();
+			let t = std::thread::Builder::new()
+				.name("fuse_server".to_string())
+				.spawn(move || svc_loop(srv, ch, sem, stop))
+				.unwrap();
+			thrds.push(t);
 		}
+		sessions.push(sess);
 	}
 
-	fn getattr(
-		&self,
-		_ctx: &Context,
-		inode: Self::Inode,
-		_handle: Option<Self::Handle>,
-	) -> io::Result<(stat64, Duration)> {
-		log::trace!("getattr({:?})", inode);
-		let files = self.files.read().unwrap();
-		files.read_ino(inode, |info| {
-			Ok((info.stat64(inode), TIMEOUT_SECS))
-		})
-	}
-
-	fn setattr(
-		&self,
-		_ctx: &Context,
-		inode: Self::Inode,
-		attr: stat64,
-		_handle: Option<Self::Handle>,
-		valid: SetattrValid,
-	) -> io::Result<(stat64, Duration)> {
-		log::trace!("setattr(inode={:?}, valid={:?})", inode, valid);
-
-		let mut files = self.files.write().unwrap();
-		files.write_ino(inode, |info| {
-			if valid.contains(SetattrValid::UID) {
-				info.owner.uid = attr.st_uid;
-			}
-			if valid.contains(SetattrValid::GID) {
-				info.owner.gid = attr.st_gid;
-			}
-			if valid.contains(SetattrValid::MODE) {
-				info.perm = FsPerm::try_from(attr.st_mode)?;
-				debug_assert_eq!(info.st_mode(), attr.st_mode);
-			}
-			if valid.contains(SetattrValid::SIZE) {
-				let data = info.file_data()?;
-				data.resize(attr.st_size as usize, 0);
-			}
-
-			Ok((info.stat64(inode), TIMEOUT_SECS))
-		})
+	// Serve until everything is unmounted, or until a signal tells us
+	// to stop
+	while !thrds.iter().all(|t| t.is_finished()) {
+		let Some(sig) = wait_signal(&sigs, SIGNAL_POLL) else {
+			continue;
+		};
+		log::info!("Got signal {}, shutting down", sig);
+		shutdown.store(true, Ordering::Relaxed);
+		for sess in sessions.iter() {
+			sess.wake()?;
+		}
+		if !drain(&thrds, DRAIN_TIMEOUT) {
+			log::warn!("Timed out waiting for in-flight requests");
+		}
+		break;
 	}
 
-	fn rmdir(
-		&self,
-		_ctx: &Context,
-		parent: Self::Inode,
-		name: &CStr,
-	) -> io::Result<()> {
-		log::trace!("rmdir(parent={:?}, name={:?})", parent, name);
-		self.files.write().unwrap().unlink_inode(parent, name)
+	// Still clean up if a worker died, so that no writes are lost
+	for t in thrds.into_iter().filter(|t| t.is_finished()) {
+		if t.join().is_err() {
+			log::error!("A server thread panicked");
+		}
 	}
 
-	fn unlink(
-		&self,
-		_ctx: &Context,
-		parent: Self::Inode,
-		name: &CStr,
-	) -> io::Result<()> {
-		log::trace!("unlink(parent={:?}, name={:?})", parent, name);
-		self.files.write().unwrap().unlink_inode(parent, name)
+	// The kernel only sends DESTROY for block device mounts, so
+	// always clean up here; doing so twice is harmless
+	for fs in filesystems.iter() {
+		fs.destroy();
 	}
-}
 
-fn svc_loop(srv: Arc<Server<SlabFs>>, mut channel: FuseChannel) {
-	log::info!("Starting thread: {:?}", std::thread::current().id());
-	while let Ok(rq) = channel.get_request() {
-		let Some((rd, wr)) = rq else {
-			continue;
-		};
-		if let Err(e) = srv.handle_message(rd, wr.into(), None, None) {
-			log::error!("FUSE error: {:?}", e);
-		}
-	}
-}
+	log::info!("Exiting");
+	unmount(sessions, opts.fd.is_some());
 
-fn usage() -> ! {
-	eprintln!("Usage: {} <mountpoint>", std::env::args().next().unwrap());
-	std::process::exit(0)
+	Ok(())
 }
 
-fn main() -> Result<(), FsErr> {
-	env_logger::init();
-
-	let Some(mountpoint) = std::env::args().nth(1) else {
-		usage();
-	};
-
-	let server = Arc::new(Server::new(SlabFs::new()));
-	let mut sess = FuseSession::new_with_autounmount(
-		Path::new(&mountpoint),
-		"slabfs",
-		"",
-		false,
-		true,
-	)?;
-	sess.mount()?;
-
-	let mut thrds = Vec::with_capacity(NUM_THREADS);
-	for _ in 0..NUM_THREADS {
-		let srv = server.clone();
-		let ch = sess.new_channel().unwrap();
-		let t = std::thread::Builder::new()
-			.name("fuse_server".to_string())
-			.spawn(move || svc_loop(srv, ch))
-			.unwrap();
-		thrds.push(t);
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn worker_count_is_never_zero() {
+		let cpus = std::thread::available_parallelism().unwrap().get();
+		assert_eq!(worker_count(0), cpus);
+		assert!(worker_count(0) >= 1);
+		assert_eq!(worker_count(1), 1);
+		assert_eq!(worker_count(7), 7);
 	}
 
-	for t in thrds {
-		t.join().unwrap();
+	fn parse(args: &[&str]) -> Result<Opts, FsErr> {
+		Opts::parse(args.iter().map(|a| a.to_string()))
 	}
 
-	log::info!("Exiting");
+	#[test]
+	fn mounts_take_shared_and_their_own_options() {
+		let opts = parse(&["--dedup", "--max-inodes", "10", "/a", "/b:max-inodes=20,verify-reads"]).unwrap();
+		let [a, b] = &opts.mounts[..] else {
+			panic!("{:?}", opts.mounts);
+		};
+		assert_eq!((a.mountpoint.as_str(), a.max_inodes, a.verify_reads, a.dedup), ("/a", Some(10), false, true));
+		assert_eq!((b.mountpoint.as_str(), b.max_inodes, b.verify_reads, b.dedup), ("/b", Some(20), true, true));
+
+		for args in [
+			&["/a", "/a"][..],
+			&["--journal", "/j", "/a", "/b"],
+			&["/a:bogus"],
+			&["/a:dedup=1"],
+			&[":dedup"],
+			&["--fd", "3", "/a", "/b"],
+		] {
+			assert!(matches!(parse(args), Err(FsErr::Opt(_))), "{:?}", args);
+		}
+	}
 
-	Ok(())
+	#[test]
+	fn mountpoints_are_checked_before_mounting() {
+		let dir = std::env::temp_dir().join(format!("slabfs-{}-mountpoint", std::process::id()));
+		std::fs::create_dir(&dir).unwrap();
+		let why = |path: &Path| match check_mountpoint(path) {
+			Err(FsErr::Mountpoint(msg)) => msg,
+			res => panic!("{:?}", res),
+		};
+		check_mountpoint(&dir).unwrap();
+		assert!(why(&dir.join("missing")).ends_with("no such directory"));
+		std::fs::write(dir.join("file"), b"").unwrap();
+		assert!(why(&dir.join("file")).ends_with("not a directory"));
+		// Only warned about, as the kernel allows it
+		check_mountpoint(&dir).unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
 }
@@ -0,0 +1,162 @@
+use crate::ioctl::LatencyStats;
+#[cfg(feature = "metrics")]
+use crate::ioctl::OpLatency;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+/// Operations whose latency is tracked.
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+	Read,
+	Write,
+	Lookup,
+	Readdir,
+}
+
+// Each power of two is split into this many buckets, giving a
+// relative error of at most 1 / SUB_BUCKETS
+#[cfg(feature = "metrics")]
+const SUB_BITS: u32 = 2;
+#[cfg(feature = "metrics")]
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+#[cfg(feature = "metrics")]
+const BUCKETS: usize = 64 * SUB_BUCKETS;
+
+/// Log-linear histogram of latencies in nanoseconds.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+struct Histogram {
+	buckets: [AtomicU64; BUCKETS],
+	max: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl Histogram {
+	fn new() -> Self {
+		Self {
+			buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+			max: AtomicU64::new(0),
+		}
+	}
+
+	fn bucket(ns: u64) -> usize {
+		if ns < SUB_BUCKETS as u64 {
+			return ns as usize;
+		}
+		let exp = 63 - ns.leading_zeros();
+		let sub = (ns >> (exp - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+		(exp - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+	}
+
+	// Largest value falling in a bucket
+	fn bucket_max(idx: usize) -> u64 {
+		if idx < SUB_BUCKETS {
+			return idx as u64;
+		}
+		let exp = (idx / SUB_BUCKETS) as u32 + SUB_BITS - 1;
+		let sub = (idx % SUB_BUCKETS) as u64;
+		let lo = (1u64 << exp) | (sub << (exp - SUB_BITS));
+		lo + (1u64 << (exp - SUB_BITS)) - 1
+	}
+
+	fn record(&self, ns: u64) {
+		self.buckets[Self::bucket(ns)].fetch_add(1, Ordering::Relaxed);
+		self.max.fetch_max(ns, Ordering::Relaxed);
+	}
+
+	fn summary(&self) -> OpLatency {
+		let counts: Vec<u64> = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+		let count: u64 = counts.iter().sum();
+		let max = self.max.load(Ordering::Relaxed);
+		let percentile = |p: u64| {
+			let rank = (count * p).div_ceil(100).max(1);
+			let mut seen = 0;
+			for (idx, n) in counts.iter().enumerate() {
+				seen += n;
+				if seen >= rank {
+					return Self::bucket_max(idx).min(max);
+				}
+			}
+			max
+		};
+		if count == 0 {
+			return OpLatency::default();
+		}
+		OpLatency {
+			count,
+			p50_ns: percentile(50),
+			p90_ns: percentile(90),
+			p99_ns: percentile(99),
+			max_ns: max,
+		}
+	}
+}
+
+/// Latency histograms per operation. Without the `metrics` feature
+/// this does nothing.
+#[derive(Debug)]
+pub struct Metrics {
+	#[cfg(feature = "metrics")]
+	ops: [Histogram; 4],
+}
+
+impl Metrics {
+	pub fn new() -> Self {
+		Self {
+			#[cfg(feature = "metrics")]
+			ops: std::array::from_fn(|_| Histogram::new()),
+		}
+	}
+
+	/// Start timing an operation, which ends when the returned
+	/// guard is dropped.
+	#[cfg(feature = "metrics")]
+	#[inline(always)]
+	pub fn time(&self, op: Op) -> Timer<'_> {
+		Timer {
+			hist: &self.ops[op as usize],
+			start: Instant::now(),
+		}
+	}
+
+	#[cfg(not(feature = "metrics"))]
+	#[inline(always)]
+	pub fn time(&self, _op: Op) -> Timer<'_> {
+		Timer(std::marker::PhantomData)
+	}
+
+	/// Latency percentiles, if enabled.
+	#[cfg(feature = "metrics")]
+	pub fn latency(&self) -> Option<LatencyStats> {
+		Some(LatencyStats {
+			read: self.ops[Op::Read as usize].summary(),
+			write: self.ops[Op::Write as usize].summary(),
+			lookup: self.ops[Op::Lookup as usize].summary(),
+			readdir: self.ops[Op::Readdir as usize].summary(),
+		})
+	}
+
+	#[cfg(not(feature = "metrics"))]
+	pub fn latency(&self) -> Option<LatencyStats> {
+		None
+	}
+}
+
+#[cfg(feature = "metrics")]
+pub struct Timer<'a> {
+	hist: &'a Histogram,
+	start: Instant,
+}
+
+#[cfg(not(feature = "metrics"))]
+pub struct Timer<'a>(std::marker::PhantomData<&'a ()>);
+
+#[cfg(feature = "metrics")]
+impl Drop for Timer<'_> {
+	fn drop(&mut self) {
+		let ns = self.start.elapsed().as_nanos();
+		self.hist.record(u64::try_from(ns).unwrap_or(u64::MAX));
+	}
+}
@@ -0,0 +1,65 @@
+use crate::ioerr;
+use std::ffi::CStr;
+use std::io;
+use std::sync::Arc;
+
+/// The name of a directory entry. Never contains NUL bytes or
+/// slashes. Its storage can be shared with the entry naming the
+/// inode in its directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Name(Arc<[u8]>);
+
+impl Name {
+	/// The name of the root directory, which is never listed.
+	pub fn empty() -> Self {
+		Self(Arc::from([]))
+	}
+
+	pub fn new(name: &[u8]) -> io::Result<Self> {
+		Self::validate(name)?;
+		Ok(Self(Arc::from(name)))
+	}
+
+	/// Check that `name` can be used as the name of an entry.
+	pub fn validate(name: &[u8]) -> io::Result<()> {
+		if name.is_empty() || name.contains(&0) || name.contains(&b'/') {
+			return Err(ioerr!(libc::EINVAL));
+		}
+		Ok(())
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+
+	/// Store the name in `shared`, which must hold the same bytes,
+	/// instead of a copy of its own.
+	pub fn share(&mut self, shared: &Arc<[u8]>) {
+		debug_assert_eq!(*self.0, **shared);
+		self.0 = shared.clone();
+	}
+
+	pub fn shared(&self) -> &Arc<[u8]> {
+		&self.0
+	}
+}
+
+impl Default for Name {
+	fn default() -> Self {
+		Self::empty()
+	}
+}
+
+impl TryFrom<&CStr> for Name {
+	type Error = io::Error;
+	fn try_from(name: &CStr) -> Result<Self, Self::Error> {
+		Self::new(name.to_bytes())
+	}
+}
+
+impl TryFrom<&str> for Name {
+	type Error = io::Error;
+	fn try_from(name: &str) -> Result<Self, Self::Error> {
+		Self::new(name.as_bytes())
+	}
+}
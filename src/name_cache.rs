@@ -0,0 +1,81 @@
+use crate::Inode;
+use std::collections::{BTreeMap, HashMap};
+
+// Inode named by each name in a directory, with the tick of its last
+// use
+type Names = HashMap<Box<[u8]>, (Inode, u64)>;
+
+/// Recently looked up directory entries, mapping a directory and a
+/// name in it to the inode it names. Once full, the least recently
+/// used entry makes room for a new one.
+#[derive(Debug)]
+pub struct NameCache {
+	max: usize,
+	// Entries by directory
+	entries: HashMap<Inode, Names>,
+	// Directory and name of each entry by the tick of its last use,
+	// oldest first
+	by_use: BTreeMap<u64, (Inode, Box<[u8]>)>,
+	tick: u64,
+}
+
+impl NameCache {
+	pub fn new(max: usize) -> Self {
+		Self {
+			max,
+			entries: HashMap::new(),
+			by_use: BTreeMap::new(),
+			tick: 0,
+		}
+	}
+
+	fn next_tick(&mut self) -> u64 {
+		self.tick += 1;
+		self.tick
+	}
+
+	pub fn get(&mut self, parent: Inode, name: &[u8]) -> Option<Inode> {
+		let tick = self.next_tick();
+		let (child, used) = self.entries.get_mut(&parent)?.get_mut(name)?;
+		let entry = self.by_use.remove(used).unwrap();
+		*used = tick;
+		self.by_use.insert(tick, entry);
+		Some(*child)
+	}
+
+	pub fn insert(&mut self, parent: Inode, name: &[u8], child: Inode) {
+		self.remove(parent, name);
+		if self.by_use.len() >= self.max {
+			if let Some((_, (p, n))) = self.by_use.pop_first() {
+				self.forget(p, &n);
+			}
+		}
+		let tick = self.next_tick();
+		self.entries
+			.entry(parent)
+			.or_default()
+			.insert(name.into(), (child, tick));
+		self.by_use.insert(tick, (parent, name.into()));
+	}
+
+	pub fn remove(&mut self, parent: Inode, name: &[u8]) {
+		if let Some(used) = self.forget(parent, name) {
+			self.by_use.remove(&used);
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.entries.clear();
+		self.by_use.clear();
+	}
+
+	// Drop an entry from `entries` only, returning its tick
+	fn forget(&mut self, parent: Inode, name: &[u8]) -> Option<u64> {
+		let names = self.entries.get_mut(&parent)?;
+		let (_, used) = names.remove(name)?;
+		if names.is_empty() {
+			self.entries.remove(&parent);
+		}
+		Some(used)
+	}
+}
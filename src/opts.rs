@@ -0,0 +1,287 @@
+use slabfs::error::FsErr;
+use core::str::FromStr;
+use core::time::Duration;
+
+#[derive(Clone, Debug, Default)]
+pub struct Opts {
+	// Mountpoint of this mount, or path queried by --stats
+	pub mountpoint: String,
+	// Settings of each filesystem to mount, in order
+	pub mounts: Vec<Opts>,
+	// Seconds between background compaction passes
+	pub compact_interval: Option<Duration>,
+	// Number of inodes to preallocate room for
+	pub initial_inodes: Option<usize>,
+	// Maximum number of inodes
+	pub max_inodes: Option<usize>,
+	// Maximum number of open files and directories
+	pub max_open_files: Option<usize>,
+	// Number of looked up names to remember
+	pub name_cache_entries: Option<usize>,
+	// Serve the .slabfs directory describing the mount
+	pub info_dir: bool,
+	// Share identical directory entry names
+	pub intern_names: bool,
+	// Share the storage of files with identical contents
+	pub dedup: bool,
+	// Compare names in constant time
+	pub constant_time_names: bool,
+	// Checksum file contents and verify them on reads
+	pub verify_reads: bool,
+	// Do not let the kernel splice data
+	pub no_splice: bool,
+	// Let any user set xattrs outside the user namespace
+	pub allow_all_xattr_namespaces: bool,
+	// Record changes in, and restore them from, this journal file
+	pub journal: Option<String>,
+	// Start with the tree described by this JSON dump
+	pub load_tree: Option<String>,
+	// Merge consecutive writes into one journal record
+	pub coalesce_writes: bool,
+	// Seconds between journal checkpoints
+	pub checkpoint_interval: Option<Duration>,
+	// Maximum number of requests handled at once across all threads
+	pub max_inflight: Option<usize>,
+	// Source and subtype shown in the mount table
+	pub fsname: Option<String>,
+	pub subtype: Option<String>,
+	// Failure rate, errno and seed of injected faults
+	#[cfg(feature = "fault-inject")]
+	pub fault_inject: Option<(f64, i32, u64)>,
+	// Serve an already mounted, inherited /dev/fuse descriptor
+	pub fd: Option<i32>,
+	// Print the stats of the mount containing `mountpoint` and exit
+	pub stats: bool,
+	// Print the directory tree under this path and exit
+	pub dump_tree: Option<String>,
+}
+
+fn value<T, I>(name: &str, args: &mut I) -> Result<T, FsErr>
+where
+	T: FromStr,
+	I: Iterator<Item = String>,
+{
+	let val = args
+		.next()
+		.ok_or_else(|| FsErr::Opt(format!("{} requires a value", name)))?;
+	val.parse()
+		.map_err(|_| FsErr::Opt(format!("bad value for {}: {:?}", name, val)))
+}
+
+// Parse a `rate,errno[,seed]` fault injection spec
+#[cfg(feature = "fault-inject")]
+fn fault_spec<I>(name: &str, args: &mut I) -> Result<(f64, i32, u64), FsErr>
+where
+	I: Iterator<Item = String>,
+{
+	let val: String = value(name, args)?;
+	let bad = || FsErr::Opt(format!("bad value for {}: {:?}", name, val));
+	let mut parts = val.split(',');
+	let rate: f64 = parts.next().and_then(|p| p.parse().ok()).ok_or_else(bad)?;
+	let errno: i32 = parts.next().and_then(|p| p.parse().ok()).ok_or_else(bad)?;
+	let seed: u64 = match parts.next() {
+		Some(p) => p.parse().map_err(|_| bad())?,
+		None => 1,
+	};
+	if parts.next().is_some() || !(0.0..=1.0).contains(&rate) || errno <= 0 {
+		return Err(bad());
+	}
+	Ok((rate, errno, seed))
+}
+
+impl Opts {
+	/// Apply `arg` if it is a filesystem option, which may be given
+	/// for all mounts or for a single one, taking its value from
+	/// `args`. Returns whether it was one.
+	fn set<I>(&mut self, arg: &str, args: &mut I) -> Result<bool, FsErr>
+	where
+		I: Iterator<Item = String>,
+	{
+		match arg {
+			"--compact-interval" => {
+				let secs: u64 = value(arg, args)?;
+				if secs == 0 {
+					return Err(FsErr::Opt(format!("{} must be non-zero", arg)));
+				}
+				self.compact_interval = Some(Duration::from_secs(secs));
+			}
+			"--checkpoint-interval" => {
+				let secs: u64 = value(arg, args)?;
+				if secs == 0 {
+					return Err(FsErr::Opt(format!("{} must be non-zero", arg)));
+				}
+				self.checkpoint_interval = Some(Duration::from_secs(secs));
+			}
+			"--journal" => self.journal = Some(value(arg, args)?),
+			"--load-tree" => self.load_tree = Some(value(arg, args)?),
+			"--fsname" => self.fsname = Some(value(arg, args)?),
+			"--subtype" => self.subtype = Some(value(arg, args)?),
+			"--initial-inodes" => {
+				let n: usize = value(arg, args)?;
+				if n == 0 {
+					return Err(FsErr::Opt(format!("{} must be non-zero", arg)));
+				}
+				self.initial_inodes = Some(n);
+			}
+			"--max-inodes" => {
+				let n: usize = value(arg, args)?;
+				if n == 0 {
+					return Err(FsErr::Opt(format!("{} must be non-zero", arg)));
+				}
+				self.max_inodes = Some(n);
+			}
+			"--max-open-files" => {
+				let n: usize = value(arg, args)?;
+				if n == 0 {
+					return Err(FsErr::Opt(format!("{} must be non-zero", arg)));
+				}
+				self.max_open_files = Some(n);
+			}
+			"--name-cache-entries" => {
+				let n: usize = value(arg, args)?;
+				if n == 0 {
+					return Err(FsErr::Opt(format!("{} must be non-zero", arg)));
+				}
+				self.name_cache_entries = Some(n);
+			}
+			"--intern-names" => self.intern_names = true,
+			"--dedup" => self.dedup = true,
+			"--constant-time-names" => self.constant_time_names = true,
+			"--verify-reads" => self.verify_reads = true,
+			"--no-splice" => self.no_splice = true,
+			"--allow-all-xattr-namespaces" => self.allow_all_xattr_namespaces = true,
+			"--coalesce-writes" => self.coalesce_writes = true,
+			"--info-dir" => self.info_dir = true,
+			_ => return Ok(false),
+		}
+		Ok(true)
+	}
+
+	/// Settings for a mount given as `mountpoint[:options]`, where
+	/// `options` are filesystem options separated by commas, named
+	/// as on the command line without the leading dashes, and
+	/// followed by `=value` if they take one. They override the ones
+	/// given for all mounts.
+	fn mount(&self, spec: &str) -> Result<Self, FsErr> {
+		let (mountpoint, options) = spec.split_once(':').unwrap_or((spec, ""));
+		if mountpoint.is_empty() && self.fd.is_none() {
+			return Err(FsErr::Opt(format!("missing mountpoint in {:?}", spec)));
+		}
+		let mut m = Self {
+			mountpoint: mountpoint.to_string(),
+			mounts: Vec::new(),
+			..self.clone()
+		};
+		for opt in options.split(',').filter(|o| !o.is_empty()) {
+			let (name, val) = match opt.split_once('=') {
+				Some((name, val)) => (name, Some(val.to_string())),
+				None => (opt, None),
+			};
+			let arg = format!("--{}", name);
+			let mut val = val.into_iter();
+			if !m.set(&arg, &mut val)? {
+				return Err(FsErr::Opt(format!("unknown mount option {}", name)));
+			}
+			if val.next().is_some() {
+				return Err(FsErr::Opt(format!("mount option {} takes no value", name)));
+			}
+		}
+		m.check()?;
+		Ok(m)
+	}
+
+	fn check(&self) -> Result<(), FsErr> {
+		if self.checkpoint_interval.is_some() && self.journal.is_none() {
+			return Err(FsErr::Opt("--checkpoint-interval requires --journal".to_string()));
+		}
+		if self.coalesce_writes && self.journal.is_none() {
+			return Err(FsErr::Opt("--coalesce-writes requires --journal".to_string()));
+		}
+		if self.load_tree.is_some() && self.journal.is_some() {
+			return Err(FsErr::Opt("--load-tree cannot be used with --journal".to_string()));
+		}
+		if self.name_cache_entries.is_some() && self.constant_time_names {
+			return Err(FsErr::Opt(
+				"--name-cache-entries cannot be used with --constant-time-names".to_string(),
+			));
+		}
+		Ok(())
+	}
+
+	pub fn parse<I>(mut args: I) -> Result<Self, FsErr>
+	where
+		I: Iterator<Item = String>,
+	{
+		let mut opts = Self::default();
+		let mut specs = Vec::new();
+
+		while let Some(arg) = args.next() {
+			if opts.set(&arg, &mut args)? {
+				continue;
+			}
+			match arg.as_str() {
+				"--max-inflight" => {
+					let n: usize = value(&arg, &mut args)?;
+					if n == 0 {
+						return Err(FsErr::Opt(format!("{} must be non-zero", arg)));
+					}
+					opts.max_inflight = Some(n);
+				}
+				"--fd" => opts.fd = Some(value(&arg, &mut args)?),
+				"--dump-tree" => opts.dump_tree = Some(value(&arg, &mut args)?),
+				#[cfg(feature = "fault-inject")]
+				"--fault-inject" => opts.fault_inject = Some(fault_spec(&arg, &mut args)?),
+				#[cfg(not(feature = "fault-inject"))]
+				"--fault-inject" => {
+					return Err(FsErr::Opt(format!("{} requires the fault-inject feature", arg)));
+				}
+				"--stats" => opts.stats = true,
+				a if a.starts_with("--") => {
+					return Err(FsErr::Opt(format!("unknown option {}", a)));
+				}
+				_ => specs.push(arg),
+			}
+		}
+
+		// Querying a running instance only takes a path
+		if opts.stats || opts.dump_tree.is_some() {
+			match specs.as_slice() {
+				[] if opts.dump_tree.is_some() => (),
+				[path] => opts.mountpoint = path.clone(),
+				[] => return Err(FsErr::Opt("missing mountpoint".to_string())),
+				[_, extra, ..] => {
+					return Err(FsErr::Opt(format!("unexpected argument {:?}", extra)));
+				}
+			}
+			return Ok(opts);
+		}
+
+		// A mounted descriptor needs no mountpoint, but serves a
+		// single filesystem
+		if opts.fd.is_some() {
+			if specs.len() > 1 {
+				return Err(FsErr::Opt("--fd serves a single filesystem".to_string()));
+			}
+			if specs.is_empty() {
+				specs.push(String::new());
+			}
+		}
+		if specs.is_empty() {
+			return Err(FsErr::Opt("missing mountpoint".to_string()));
+		}
+
+		let mut mounts = Vec::with_capacity(specs.len());
+		for spec in specs.iter() {
+			let m = opts.mount(spec)?;
+			if mounts.iter().any(|o: &Self| o.mountpoint == m.mountpoint) {
+				return Err(FsErr::Opt(format!("{} is mounted twice", m.mountpoint)));
+			}
+			if m.journal.is_some() && mounts.iter().any(|o| o.journal == m.journal) {
+				return Err(FsErr::Opt("mounts cannot share a journal".to_string()));
+			}
+			mounts.push(m);
+		}
+		opts.mounts = mounts;
+		Ok(opts)
+	}
+}
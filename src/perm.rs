@@ -34,6 +34,12 @@ impl FsPerm {
 			| Self::OTHER_READ | Self::OTHER_EXEC
 	}
 
+	/// Symbolic links always have every permission, as they are
+	/// never checked.
+	pub fn symlink() -> Self {
+		Self::USER_RWX | Self::GROUP_RWX | Self::OTHER_RWX
+	}
+
 	pub fn file() -> Self {
 		Self::USER_READ
 			| FsPerm::USER_WRITE
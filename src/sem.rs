@@ -0,0 +1,66 @@
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+/// A counting semaphore.
+#[derive(Debug)]
+pub struct Semaphore {
+	permits: Mutex<usize>,
+	cond: Condvar,
+}
+
+/// A permit acquired from a `Semaphore`, given back when dropped.
+pub struct Permit<'a> {
+	sem: &'a Semaphore,
+}
+
+impl Semaphore {
+	pub fn new(permits: usize) -> Self {
+		Self {
+			permits: Mutex::new(permits),
+			cond: Condvar::new(),
+		}
+	}
+
+	// The count is valid even if a holder panicked
+	fn permits(&self) -> MutexGuard<'_, usize> {
+		self.permits.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	pub fn acquire(&self) -> Permit<'_> {
+		let mut permits = self.cond
+			.wait_while(self.permits(), |p| *p == 0)
+			.unwrap_or_else(PoisonError::into_inner);
+		*permits -= 1;
+		Permit { sem: self }
+	}
+}
+
+impl Drop for Permit<'_> {
+	fn drop(&mut self) {
+		*self.sem.permits() += 1;
+		self.sem.cond.notify_one();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+
+	#[test]
+	fn permits_survive_a_panicking_holder() {
+		let sem = Arc::new(Semaphore::new(1));
+		let holder = sem.clone();
+		std::thread::spawn(move || {
+			let _permit = holder.acquire();
+			// Poison the lock while the permit is given back
+			let _guard = holder.permits();
+			panic!("request handler failed");
+		})
+		.join()
+		.unwrap_err();
+		assert!(sem.permits.is_poisoned());
+		drop(sem.acquire());
+		let _permit = sem.acquire();
+		assert_eq!(*sem.permits(), 0);
+	}
+}
@@ -0,0 +1,56 @@
+//! A Tokio wrapper around the blocking request loop, for applications
+//! that embed slabfs and already run a runtime.
+//!
+//! This is not an async driver. Reading the FUSE device blocks, so
+//! `serve()` runs each worker, the same `worker::run()` loop as the
+//! slabfs binary's threads, on the runtime's blocking pool, where it
+//! holds a thread until the filesystem is unmounted; the returned
+//! future only waits for them. Async tasks keep running meanwhile, but
+//! no threads are saved over the synchronous path, only starting and
+//! joining them by hand. Workers count against the runtime's
+//! `max_blocking_threads`.
+//!
+//! With Tokio's `macros` and `rt-multi-thread` features enabled by the
+//! application, which slabfs does not need itself:
+//!
+//! ```ignore
+//! use fuse_backend_rs::transport::FuseSession;
+//! use slabfs::{error::FsErr, SlabFs};
+//! use std::path::Path;
+//! use std::sync::Arc;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), FsErr> {
+//!     let fs = Arc::new(SlabFs::builder().build()?);
+//!     let mut sess = FuseSession::new(Path::new("/mnt"), "slabfs", "", false)?;
+//!     sess.mount()?;
+//!     // Other tasks keep running until this returns on unmount
+//!     slabfs::service::serve(fs, &sess, 2).await?;
+//!     sess.umount()?;
+//!     Ok(())
+//! }
+//! ```
+
+use crate::{error::FsErr, ioerr, worker, SlabFs};
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::transport::FuseSession;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+/// Serve requests for `fs` through the mounted session `sess` with
+/// `workers` workers (at least one), returning once it is unmounted.
+pub async fn serve(fs: Arc<SlabFs>, sess: &FuseSession, workers: usize) -> Result<(), FsErr> {
+	let srv = Arc::new(Server::new(fs));
+	let mut tasks = Vec::with_capacity(workers.max(1));
+	for _ in 0..workers.max(1) {
+		let channel = sess.new_channel()?;
+		let srv = srv.clone();
+		// Only unmounting stops the workers
+		let stop = AtomicBool::new(false);
+		tasks.push(tokio::task::spawn_blocking(move || worker::run(&srv, channel, &stop, || ())));
+	}
+	for task in tasks {
+		task.await.map_err(|e| ioerr!(Other, e))?;
+	}
+	Ok(())
+}
@@ -0,0 +1,95 @@
+use crate::{FsFiles, Inode};
+use std::io;
+use std::sync::Arc;
+
+/// A node of a `TreeSnapshot`.
+#[derive(Clone, Debug)]
+pub struct SnapshotNode {
+	pub ino: Inode,
+	// Name in the parent directory; the root's own name
+	pub name: Arc<[u8]>,
+	// Type and permission bits, as in st_mode
+	pub mode: u32,
+	pub size: u64,
+	// Contents of a regular file or target of a symbolic link
+	contents: Option<Arc<[u8]>>,
+	// Indices of the entries of a directory in the snapshot
+	children: Vec<usize>,
+}
+
+impl SnapshotNode {
+	/// The contents of a regular file, or the target of a symbolic
+	/// link.
+	pub fn contents(&self) -> Option<&[u8]> {
+		self.contents.as_deref()
+	}
+
+	pub fn is_dir(&self) -> bool {
+		self.mode & libc::S_IFMT == libc::S_IFDIR
+	}
+}
+
+/// The directory tree as it was at one point in time, as returned
+/// by `SlabFs::snapshot()`. Later changes to the filesystem do not
+/// show in it, and walking it takes no locks.
+#[derive(Clone, Debug)]
+pub struct TreeSnapshot {
+	// Depth first, starting with the root
+	nodes: Vec<SnapshotNode>,
+}
+
+impl TreeSnapshot {
+	pub fn root(&self) -> &SnapshotNode {
+		&self.nodes[0]
+	}
+
+	/// The entries of `dir`, in directory order.
+	pub fn children<'a>(&'a self, dir: &'a SnapshotNode) -> impl Iterator<Item = &'a SnapshotNode> {
+		dir.children.iter().map(|&i| &self.nodes[i])
+	}
+
+	/// All nodes, depth first, each directory before its entries.
+	pub fn iter(&self) -> impl Iterator<Item = &SnapshotNode> {
+		self.nodes.iter()
+	}
+
+	pub fn len(&self) -> usize {
+		self.nodes.len()
+	}
+
+	/// Always false, as the root is part of the snapshot.
+	pub fn is_empty(&self) -> bool {
+		self.nodes.is_empty()
+	}
+}
+
+/// Copy the tree under the root out of `files`. File contents that
+/// are shared are not copied.
+pub fn take(files: &FsFiles) -> io::Result<TreeSnapshot> {
+	let mut nodes: Vec<SnapshotNode> = Vec::new();
+	let root = files.get(Inode::ROOT)?;
+	let mut stack = vec![(Inode::ROOT, Arc::from(root.name()), None::<usize>)];
+	while let Some((ino, name, parent)) = stack.pop() {
+		let info = files.get(ino)?;
+		let st = info.stat64(ino);
+		let idx = nodes.len();
+		if let Some(parent) = parent {
+			nodes[parent].children.push(idx);
+		}
+		nodes.push(SnapshotNode {
+			ino,
+			name,
+			mode: st.st_mode,
+			size: st.st_size as u64,
+			contents: info.shared_contents(),
+			children: Vec::new(),
+		});
+		// Pushed in reverse, so that entries come out in order
+		if let Ok(children) = info.children() {
+			for (child, name) in children.iter().rev() {
+				stack.push((*child, name.clone(), Some(idx)));
+			}
+		}
+	}
+	Ok(TreeSnapshot { nodes })
+}
@@ -0,0 +1,2085 @@
+use super::*;
+use crate::file_data::{FileData, INLINE_CAP};
+use crate::ioctl::IoctlStruct;
+use crate::name::Name;
+use crate::xattr::{XATTR_TOTAL_MAX, XATTR_VALUE_MAX};
+use fuse_backend_rs::common::file_buf::FileVolatileSlice;
+use fuse_backend_rs::common::file_traits::FileReadWriteVolatile;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+fn cstr(name: &str) -> CString {
+	CString::new(name).unwrap()
+}
+
+/// Make a directory as the kernel would, holding one lookup on it.
+fn mkdir(fs: &SlabFs, parent: Inode, name: &str) -> Inode {
+	let entry = fs.mkdir(&Context::new(), parent, &cstr(name), 0o755, 0).unwrap();
+	Inode::from(entry.inode)
+}
+
+/// Make a regular file as the kernel would, holding one lookup on it.
+fn mknod(fs: &SlabFs, parent: Inode, name: &str) -> Inode {
+	let mode = libc::S_IFREG | 0o644;
+	let entry = fs.mknod(&Context::new(), parent, &cstr(name), mode, 0, 0).unwrap();
+	Inode::from(entry.inode)
+}
+
+#[test]
+fn compaction_moves_unreferenced_inodes_into_freed_slots() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let names: Vec<String> = (0..50).map(|i| format!("f{}", i)).collect();
+	for name in &names {
+		fs.create_file(Inode::ROOT, name, 0o644).unwrap();
+	}
+	// Forgotten by the kernel, so free to move
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	fs.forget(&ctx, dir, 1);
+	let inner = fs.create_file(dir, "inner", 0o644).unwrap();
+	fs.write_at(inner, 0, b"data").unwrap();
+	for name in &names {
+		fs.unlink(&ctx, Inode::ROOT, &cstr(name)).unwrap();
+	}
+	assert_eq!(usize::from(inner), 53);
+
+	assert_eq!(fs.compact(), (2, 50));
+	let dir = find(&fs, Inode::ROOT, "d").unwrap();
+	let inner = find(&fs, dir, "inner").unwrap();
+	assert_eq!((usize::from(dir), usize::from(inner)), (3, 2));
+	assert_eq!(fs.files().get(inner).unwrap().parent, dir);
+	assert_eq!(fs.lookup(&ctx, dir, &cstr("inner")).unwrap().inode, u64::from(inner));
+	assert_eq!(fs.read_at(inner, 0, 64).unwrap(), b"data");
+	assert!(all_reachable(&fs));
+
+	// Inodes the kernel or a handle knows by number stay put, and
+	// stop the moving from the end
+	let gap = fs.create_file(Inode::ROOT, "gap", 0o644).unwrap();
+	let known = mknod(&fs, Inode::ROOT, "known");
+	fs.unlink(&ctx, Inode::ROOT, &cstr("gap")).unwrap();
+	assert_eq!(fs.compact(), (0, 0));
+	fs.forget(&ctx, known, 1);
+	let handle = open(&fs, known, libc::O_RDONLY).unwrap();
+	assert_eq!(fs.compact(), (0, 0));
+	fs.release(&ctx, known, 0, handle, false, false, None).unwrap();
+	assert_eq!(fs.compact(), (1, 1));
+	assert_eq!(find(&fs, Inode::ROOT, "known"), Some(gap));
+
+	// Journal records name inodes by number
+	let journal = TempPath::new("compact");
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	fs.create_file(Inode::ROOT, "a", 0o644).unwrap();
+	fs.create_file(Inode::ROOT, "b", 0o644).unwrap();
+	fs.unlink(&ctx, Inode::ROOT, &cstr("a")).unwrap();
+	assert_eq!(fs.compact(), (0, 0));
+}
+
+#[test]
+fn interned_names_are_shared_and_released() {
+	let fs = SlabFs::builder().intern_names(true).build().unwrap();
+	let (a, b) = (mkdir(&fs, Inode::ROOT, "a"), mkdir(&fs, Inode::ROOT, "b"));
+	let fa = mknod(&fs, a, "same");
+	let fb = mknod(&fs, b, "same");
+	{
+		let files = fs.files();
+		let pooled = files.names.as_ref().unwrap().get(&b"same"[..]).unwrap();
+		assert!(Arc::ptr_eq(files.get(fa).unwrap().shared_name(), pooled));
+		assert!(Arc::ptr_eq(files.get(fb).unwrap().shared_name(), pooled));
+		// The pool, two directory entries and two inodes
+		assert_eq!(Arc::strong_count(pooled), 5);
+	}
+
+	let ctx = Context::new();
+	for (dir, ino) in [(a, fa), (b, fb)] {
+		fs.unlink(&ctx, dir, &cstr("same")).unwrap();
+		fs.forget(&ctx, ino, 1);
+	}
+	assert!(!fs.files().names.as_ref().unwrap().contains(&b"same"[..]));
+}
+
+#[test]
+fn identical_files_share_storage_after_flush() {
+	let fs = SlabFs::builder().dedup(true).build().unwrap();
+	let data = vec![0x5a; 4096];
+	let (a, b) = (mknod(&fs, Inode::ROOT, "a"), mknod(&fs, Inode::ROOT, "b"));
+	for ino in [a, b] {
+		fs.write_at(ino, 0, &data).unwrap();
+		fs.flush(&Context::new(), ino, 0, 0).unwrap();
+	}
+
+	let files = fs.files();
+	let contents = |ino| files.get(ino).unwrap().file_contents().unwrap().as_ptr();
+	assert_eq!(contents(a), contents(b));
+	assert_eq!(files.stats().dedup_saved, data.len() as u64);
+	drop(files);
+	assert_eq!(fs.read_at(b, 0, data.len()).unwrap(), data);
+
+	// Writing gives the file a copy of its own again
+	fs.write_at(b, 0, b"x").unwrap();
+	assert_eq!(fs.files().stats().dedup_saved, 0);
+	assert_eq!(fs.read_at(a, 0, data.len()).unwrap(), data);
+}
+
+/// Whether every inode that was not removed can be reached from the
+/// root.
+fn all_reachable(fs: &SlabFs) -> bool {
+	let files = fs.files();
+	files.files
+		.iter()
+		.skip(1)
+		.filter(|(_, info)| !info.is_unlinked())
+		.all(|(idx, _)| files.path(Inode::from(idx)).is_some())
+}
+
+#[test]
+fn create_racing_rmdir_never_orphans_entries() {
+	let fs = Arc::new(SlabFs::builder().build().unwrap());
+	let ctx = Context::new();
+	for round in 0..200 {
+		let dir = mkdir(&fs, Inode::ROOT, "d");
+		let barrier = Arc::new(std::sync::Barrier::new(2));
+		let creator = {
+			let (fs, barrier) = (fs.clone(), barrier.clone());
+			std::thread::spawn(move || {
+				barrier.wait();
+				let mode = libc::S_IFREG | 0o644;
+				for i in 0..2000 {
+					let name = cstr(&format!("f{}", i));
+					if let Err(e) = fs.mknod(&Context::new(), dir, &name, mode, 0, 0) {
+						assert_eq!(e.kind(), io::ErrorKind::NotFound, "round {}", round);
+						break;
+					}
+				}
+			})
+		};
+		barrier.wait();
+		loop {
+			match fs.rmdir(&ctx, Inode::ROOT, &cstr("d")) {
+				Ok(()) => break,
+				Err(e) => {
+					assert_eq!(e.raw_os_error(), Some(libc::ENOTEMPTY));
+					for (_, name) in fs.list_dir(dir).unwrap() {
+						fs.unlink(&ctx, dir, &CString::new(name).unwrap()).unwrap();
+					}
+				}
+			}
+		}
+		creator.join().unwrap();
+		assert!(all_reachable(&fs), "round {}", round);
+	}
+}
+
+fn create(fs: &SlabFs, parent: Inode, name: &str, flags: i32) -> io::Result<Inode> {
+	let args = CreateIn {
+		flags: (libc::O_CREAT | libc::O_RDWR | flags) as u32,
+		mode: libc::S_IFREG | 0o644,
+		umask: 0,
+		fuse_flags: 0,
+	};
+	let (entry, _, _) = fs.create(&Context::new(), parent, &cstr(name), args)?;
+	Ok(Inode::from(entry.inode))
+}
+
+#[test]
+fn create_with_o_excl_fails_on_existing_names() {
+	let fs = SlabFs::builder().build().unwrap();
+	create(&fs, Inode::ROOT, "f", libc::O_EXCL).unwrap();
+	mkdir(&fs, Inode::ROOT, "d");
+	for name in ["f", "d"] {
+		let e = create(&fs, Inode::ROOT, name, libc::O_EXCL).unwrap_err();
+		assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+	}
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 2);
+}
+
+#[test]
+fn create_without_o_excl_opens_existing_files() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ino = create(&fs, Inode::ROOT, "f", libc::O_EXCL).unwrap();
+	fs.write_at(ino, 0, b"contents").unwrap();
+
+	assert_eq!(create(&fs, Inode::ROOT, "f", 0).unwrap(), ino);
+	assert_eq!(fs.read_at(ino, 0, 64).unwrap(), b"contents");
+	assert_eq!(fs.files().get(ino).unwrap().refs(), 2);
+
+	assert_eq!(create(&fs, Inode::ROOT, "f", libc::O_TRUNC).unwrap(), ino);
+	assert_eq!(fs.read_at(ino, 0, 64).unwrap(), b"");
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 1);
+
+	mkdir(&fs, Inode::ROOT, "d");
+	let e = create(&fs, Inode::ROOT, "d", 0).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EISDIR));
+}
+
+#[test]
+fn ioctl_replies_carry_the_whole_struct() {
+	let fs = SlabFs::builder().build().unwrap();
+	mknod(&fs, Inode::ROOT, "f");
+	let ctx = Context::new();
+	let size = std::mem::size_of::<FsStats>() as u32;
+	let stats = fs.files().stats();
+	let reply = fs.ioctl(&ctx, Inode::ROOT, 0, 0, SLABFS_IOC_STATS, IoctlData::default(), size).unwrap();
+	assert_eq!(reply.data.unwrap(), stats.as_bytes());
+
+	let e = fs.ioctl(&ctx, Inode::ROOT, 0, 0, SLABFS_IOC_STATS, IoctlData::default(), size - 1);
+	assert_eq!(e.err().unwrap().raw_os_error(), Some(libc::EINVAL));
+}
+
+#[test]
+fn names_under_files_fail_with_enotdir() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let file = mknod(&fs, Inode::ROOT, "a");
+	let b = cstr("b");
+	let errs = [
+		fs.lookup(&ctx, file, &b).err(),
+		create(&fs, file, "b", 0).err(),
+		fs.mkdir(&ctx, file, &b, 0o755, 0).err(),
+		fs.unlink(&ctx, file, &b).err(),
+		fs.rmdir(&ctx, file, &b).err(),
+	];
+	for e in errs {
+		assert_eq!(e.unwrap().raw_os_error(), Some(libc::ENOTDIR));
+	}
+	assert_eq!(fs.lookup(&ctx, Inode::ROOT, &b).err().unwrap().kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn dot_and_dotdot_resolve_to_the_directory_and_its_parent() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let sub = mkdir(&fs, dir, "s");
+	let refs = |ino| fs.files().get(ino).unwrap().refs();
+
+	let before = refs(dir);
+	assert_eq!(fs.lookup(&ctx, dir, &cstr(".")).unwrap().inode, u64::from(dir));
+	assert_eq!(fs.lookup(&ctx, sub, &cstr("..")).unwrap().inode, u64::from(dir));
+	assert_eq!(refs(dir), before + 2);
+	assert_eq!(fs.lookup(&ctx, Inode::ROOT, &cstr("..")).unwrap().inode, u64::from(Inode::ROOT));
+}
+
+#[test]
+fn inode_cap_fails_with_enospc_until_a_slot_is_freed() {
+	let fs = SlabFs::builder().max_inodes(3).build().unwrap();
+	let ctx = Context::new();
+	let file = mknod(&fs, Inode::ROOT, "a");
+	mkdir(&fs, Inode::ROOT, "d");
+	let name = cstr("b");
+	let errs = [
+		create(&fs, Inode::ROOT, "b", 0).err(),
+		fs.mkdir(&ctx, Inode::ROOT, &name, 0o755, 0).err(),
+		fs.mknod(&ctx, Inode::ROOT, &name, libc::S_IFREG | 0o644, 0, 0).err(),
+		fs.symlink(&ctx, &cstr("a"), Inode::ROOT, &name).err(),
+		fs.create_file(Inode::ROOT, "b", 0o644).err(),
+	];
+	for e in errs {
+		assert_eq!(e.unwrap().raw_os_error(), Some(libc::ENOSPC));
+	}
+
+	// The slot is only freed once the kernel forgets the inode
+	fs.unlink(&ctx, Inode::ROOT, &cstr("a")).unwrap();
+	assert!(create(&fs, Inode::ROOT, "b", 0).is_err());
+	fs.forget(&ctx, file, 1);
+	create(&fs, Inode::ROOT, "b", 0).unwrap();
+}
+
+#[test]
+fn statfs_counts_free_inodes() {
+	let ctx = Context::new();
+	let fs = SlabFs::builder().max_inodes(10).build().unwrap();
+	let st = fs.statfs(&ctx, Inode::ROOT).unwrap();
+	assert_eq!((st.f_files, st.f_ffree, st.f_favail), (10, 9, 9));
+	mknod(&fs, Inode::ROOT, "a");
+	mkdir(&fs, Inode::ROOT, "d");
+	let st = fs.statfs(&ctx, Inode::ROOT).unwrap();
+	assert_eq!((st.f_files, st.f_ffree, st.f_favail), (10, 7, 7));
+
+	let fs = SlabFs::builder().build().unwrap();
+	let free = fs.statfs(&ctx, Inode::ROOT).unwrap().f_ffree;
+	mknod(&fs, Inode::ROOT, "a");
+	assert_eq!(fs.statfs(&ctx, Inode::ROOT).unwrap().f_ffree, free - 1);
+}
+
+/// List `dir` through readdir, as the kernel would.
+fn readdir(fs: &SlabFs, dir: Inode) -> Vec<(Inode, Vec<u8>)> {
+	let ctx = Context::new();
+	let (handle, _) = fs.opendir(&ctx, dir, 0).unwrap();
+	let handle = handle.unwrap();
+	let mut entries = Vec::new();
+	fs.readdir(&ctx, dir, handle, 4096, 0, &mut |entry| {
+		entries.push((Inode::from(entry.ino), entry.name.to_vec()));
+		Ok(1)
+	})
+	.unwrap();
+	fs.releasedir(&ctx, dir, 0, handle).unwrap();
+	entries
+}
+
+#[test]
+fn root_lists_its_entries_but_not_itself() {
+	let fs = SlabFs::builder().build().unwrap();
+	assert!(readdir(&fs, Inode::ROOT).is_empty());
+	assert!(fs.files().get(Inode::ROOT).unwrap().name().is_empty());
+
+	let a = mknod(&fs, Inode::ROOT, "a");
+	let d = mkdir(&fs, Inode::ROOT, "d");
+	let expected = vec![(a, b"a".to_vec()), (d, b"d".to_vec())];
+	assert_eq!(readdir(&fs, Inode::ROOT), expected);
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap(), expected);
+
+	let ctx = Context::new();
+	assert_eq!(fs.lookup(&ctx, d, &cstr("..")).unwrap().inode, u64::from(Inode::ROOT));
+}
+
+#[test]
+fn constant_time_comparison_agrees_with_feq() {
+	// feq reads whole words, so compare heap copies, which are
+	// aligned like the names the filesystem stores
+	let names: [&[u8]; 8] = [b"", b"a", b"b", b"ab", b"ba", b"abc", b"abd", &[0xff; 300]];
+	let names = names.map(<[u8]>::to_vec);
+	for a in &names {
+		for b in &names {
+			assert_eq!(ct_eq(a, b), a.feq(b), "{:?} {:?}", a, b);
+		}
+	}
+
+	let fs = SlabFs::builder().constant_time_names(true).build().unwrap();
+	let ctx = Context::new();
+	let a = mknod(&fs, Inode::ROOT, "name");
+	mknod(&fs, Inode::ROOT, "nam");
+	assert_eq!(fs.lookup(&ctx, Inode::ROOT, &cstr("name")).unwrap().inode, u64::from(a));
+	assert!(fs.lookup(&ctx, Inode::ROOT, &cstr("namf")).is_err());
+	fs.unlink(&ctx, Inode::ROOT, &cstr("name")).unwrap();
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 1);
+}
+
+/// A path in the temporary directory for a test to use, removed
+/// when dropped.
+struct TempPath(std::path::PathBuf);
+
+impl TempPath {
+	fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("slabfs-{}-{}", std::process::id(), name));
+		let _ = std::fs::remove_file(&path);
+		Self(path)
+	}
+}
+
+impl Drop for TempPath {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.0);
+	}
+}
+
+fn find(fs: &SlabFs, parent: Inode, name: &str) -> Option<Inode> {
+	let entries = fs.list_dir(parent).unwrap();
+	entries.into_iter().find(|(_, n)| n == name.as_bytes()).map(|(ino, _)| ino)
+}
+
+#[test]
+fn journal_replays_after_a_crash() {
+	let journal = TempPath::new("crash-replay");
+	let ctx = Context::new();
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	let a = fs.create_file(Inode::ROOT, "a", 0o600).unwrap();
+	fs.write_at(a, 0, b"hello").unwrap();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	fs.create_file(dir, "b", 0o644).unwrap();
+	fs.symlink(&ctx, &cstr("../a"), dir, &cstr("l")).unwrap();
+	fs.create_file(Inode::ROOT, "gone", 0o644).unwrap();
+	fs.unlink(&ctx, Inode::ROOT, &cstr("gone")).unwrap();
+	// Created through the library, so the kernel holds no reference
+	assert_eq!(fs.files().get(a).unwrap().refs(), 0);
+	// Crash without closing the journal
+	std::mem::forget(fs);
+
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	let a = find(&fs, Inode::ROOT, "a").unwrap();
+	let dir = find(&fs, Inode::ROOT, "d").unwrap();
+	assert!(find(&fs, Inode::ROOT, "gone").is_none());
+	assert_eq!(fs.read_at(a, 0, 64).unwrap(), b"hello");
+	let b = find(&fs, dir, "b").unwrap();
+	assert_eq!(fs.readlink(&ctx, find(&fs, dir, "l").unwrap()).unwrap(), b"../a");
+	for ino in [a, dir, b] {
+		assert_eq!(fs.files().get(ino).unwrap().refs(), 0);
+	}
+
+	// Files in the tree outlive the kernel forgetting them, or a
+	// handle on them being closed
+	fs.lookup(&ctx, Inode::ROOT, &cstr("a")).unwrap();
+	fs.forget(&ctx, a, 1);
+	let (handle, _) = fs.open(&ctx, a, libc::O_RDONLY as u32, 0).unwrap();
+	fs.release(&ctx, a, 0, handle.unwrap(), false, false, None).unwrap();
+	assert_eq!(fs.read_at(a, 0, 64).unwrap(), b"hello");
+
+	// Nothing refers to the file once unlinked, so it goes right away
+	fs.unlink(&ctx, Inode::ROOT, &cstr("a")).unwrap();
+	assert!(fs.files().get(a).is_err());
+}
+
+fn getxattr(fs: &SlabFs, ino: Inode, name: &str) -> Option<Vec<u8>> {
+	match fs.getxattr(&Context::new(), ino, &cstr(name), 256) {
+		Ok(GetxattrReply::Value(value)) => Some(value),
+		Ok(GetxattrReply::Count(_)) => unreachable!(),
+		Err(e) => {
+			assert_eq!(e.raw_os_error(), Some(libc::ENODATA));
+			None
+		}
+	}
+}
+
+#[test]
+fn checkpoint_restores_xattrs_after_a_crash() {
+	let journal = TempPath::new("checkpoint");
+	let ctx = Context::new();
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	fs.setxattr(&ctx, Inode::ROOT, &cstr("user.root"), b"r", 0).unwrap();
+	// Opened and unlinked, so left out of the checkpoint
+	let gone = mknod(&fs, Inode::ROOT, "gone");
+	fs.setxattr(&ctx, gone, &cstr("user.gone"), b"g", 0).unwrap();
+	fs.open(&ctx, gone, libc::O_RDWR as u32, 0).unwrap();
+	fs.unlink(&ctx, Inode::ROOT, &cstr("gone")).unwrap();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	fs.setxattr(&ctx, file, &cstr("user.f"), b"f", 0).unwrap();
+	fs.write_at(file, 0, b"before").unwrap();
+
+	// Inherited modes and ACLs changed after creation stay changed:
+	// u::rw-,g::r--,o::--- masks new files to 0640, and with a named
+	// user entry also gives them an access ACL
+	let minimal = acl(&[(0x01, 6, u32::MAX), (0x04, 4, u32::MAX), (0x20, 0, u32::MAX)]);
+	let named = acl(&[(0x01, 6, u32::MAX), (0x02, 4, 1000), (0x04, 0, u32::MAX), (0x10, 4, u32::MAX), (0x20, 0, u32::MAX)]);
+	let access = cstr("system.posix_acl_access");
+	let default = cstr("system.posix_acl_default");
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	fs.setxattr(&ctx, dir, &default, &minimal, 0).unwrap();
+	let chmod = |fs: &SlabFs, ino, mode| {
+		let mut attr = stat(fs, ino);
+		attr.st_mode = libc::S_IFREG | mode;
+		fs.setattr(&ctx, ino, attr, None, SetattrValid::MODE).unwrap();
+	};
+	let masked = mknod(&fs, dir, "masked");
+	assert_eq!(stat(&fs, masked).st_mode & 0o777, 0o640);
+	chmod(&fs, masked, 0o644);
+	let acls = mkdir(&fs, Inode::ROOT, "acls");
+	fs.setxattr(&ctx, acls, &default, &named, 0).unwrap();
+	let stripped = mknod(&fs, acls, "stripped");
+	fs.removexattr(&ctx, stripped, &access).unwrap();
+	let sub = mkdir(&fs, acls, "sub");
+	fs.removexattr(&ctx, sub, &default).unwrap();
+	mknod(&fs, acls, "kept");
+
+	fs.checkpoint().unwrap();
+	fs.write_at(file, 0, b"after!").unwrap();
+	mknod(&fs, acls, "late");
+	std::mem::forget(fs);
+
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 3);
+	let file = find(&fs, Inode::ROOT, "f").unwrap();
+	assert_eq!(fs.read_at(file, 0, 64).unwrap(), b"after!");
+	assert_eq!(getxattr(&fs, Inode::ROOT, "user.root").unwrap(), b"r");
+	assert_eq!(getxattr(&fs, file, "user.f").unwrap(), b"f");
+	assert_eq!(getxattr(&fs, file, "user.gone"), None);
+
+	let dir = find(&fs, Inode::ROOT, "d").unwrap();
+	let acls = find(&fs, Inode::ROOT, "acls").unwrap();
+	let masked = find(&fs, dir, "masked").unwrap();
+	assert_eq!(stat(&fs, masked).st_mode & 0o777, 0o644);
+	let stripped = find(&fs, acls, "stripped").unwrap();
+	assert_eq!(getxattr(&fs, stripped, "system.posix_acl_access"), None);
+	let sub = find(&fs, acls, "sub").unwrap();
+	assert_eq!(getxattr(&fs, sub, "system.posix_acl_default"), None);
+	for name in ["kept", "late"] {
+		let ino = find(&fs, acls, name).unwrap();
+		assert_eq!(getxattr(&fs, ino, "system.posix_acl_access").unwrap(), named);
+		assert_eq!(stat(&fs, ino).st_mode & 0o777, 0o640);
+	}
+}
+
+#[test]
+fn verified_reads_catch_flipped_bytes() {
+	let fs = SlabFs::builder().verify_reads(true).build().unwrap();
+	let ino = mknod(&fs, Inode::ROOT, "f");
+	let data: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+	fs.write_at(ino, 0, &data).unwrap();
+	assert_eq!(fs.read_at(ino, 0, data.len()).unwrap(), data);
+
+	// Behind the filesystem's back, as memory corruption would
+	let flip = |i: usize| {
+		let mut files = fs.files_mut();
+		files.get_mut(ino).unwrap().file_data().unwrap().try_grow(0).unwrap()[i] ^= 1;
+	};
+	flip(12_345);
+	let e = fs.read_at(ino, 0, data.len()).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EIO));
+	// Only the blocks covering the read are checked
+	assert_eq!(fs.read_at(ino, 0, 4096).unwrap(), &data[..4096]);
+
+	flip(12_345);
+	assert_eq!(fs.read_at(ino, 0, data.len()).unwrap(), data);
+}
+
+fn stat(fs: &SlabFs, ino: Inode) -> stat64 {
+	fs.getattr(&Context::new(), ino, None).unwrap().0
+}
+
+#[test]
+fn deduplicated_files_split_their_blocks() {
+	let fs = SlabFs::builder().dedup(true).build().unwrap();
+	let data = vec![7; 64 * 1024];
+	let inos: Vec<Inode> = (0..4).map(|i| mknod(&fs, Inode::ROOT, &format!("f{}", i))).collect();
+	for &ino in &inos {
+		fs.write_at(ino, 0, &data).unwrap();
+		assert_eq!(stat(&fs, ino).st_blocks, 128);
+		fs.flush(&Context::new(), ino, 0, 0).unwrap();
+	}
+
+	// What du would add up
+	let mut used = 0;
+	for &ino in &inos {
+		let st = stat(&fs, ino);
+		assert_eq!(st.st_size, data.len() as i64);
+		used += st.st_blocks * ST_BLOCK as i64;
+	}
+	assert_eq!(used, data.len() as i64);
+}
+
+/// The data of a write request, handed over in pieces of at most
+/// `chunk` bytes, as when a request spans several buffers.
+struct MockReader {
+	data: Vec<u8>,
+	pos: usize,
+	chunk: usize,
+}
+
+impl MockReader {
+	fn new(data: &[u8], chunk: usize) -> Self {
+		Self { data: data.to_vec(), pos: 0, chunk }
+	}
+
+	fn next(&mut self, count: usize) -> &mut [u8] {
+		let n = count.min(self.chunk).min(self.data.len() - self.pos);
+		&mut self.data[self.pos..self.pos + n]
+	}
+}
+
+impl io::Read for MockReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let data = self.next(buf.len());
+		let n = data.len();
+		buf[..n].copy_from_slice(data);
+		self.pos += n;
+		Ok(n)
+	}
+}
+
+impl ZeroCopyReader for MockReader {
+	fn read_to(&mut self, f: &mut dyn FileReadWriteVolatile, count: usize, off: u64) -> io::Result<usize> {
+		let data = self.next(count);
+		// SAFETY: the slice covers `data`, which outlives it
+		let slice = unsafe { FileVolatileSlice::from_raw_ptr(data.as_mut_ptr(), data.len()) };
+		let n = f.write_at_volatile(slice, off)?;
+		self.pos += n;
+		Ok(n)
+	}
+}
+
+/// The reply to a read request.
+#[derive(Default)]
+struct MockWriter(Vec<u8>);
+
+impl io::Write for MockWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl ZeroCopyWriter for MockWriter {
+	fn write_from(&mut self, f: &mut dyn FileReadWriteVolatile, count: usize, off: u64) -> io::Result<usize> {
+		let mut buf = vec![0; count];
+		// SAFETY: the slice covers `buf`, which outlives it
+		let slice = unsafe { FileVolatileSlice::from_raw_ptr(buf.as_mut_ptr(), buf.len()) };
+		let n = f.read_at_volatile(slice, off)?;
+		self.0.extend_from_slice(&buf[..n]);
+		Ok(n)
+	}
+
+	fn available_bytes(&self) -> usize {
+		usize::MAX
+	}
+}
+
+/// Write `data` at `off` through `handle`, as the kernel would.
+fn write(fs: &SlabFs, ino: Inode, handle: u64, data: &[u8], off: u64) -> io::Result<usize> {
+	let mut r = MockReader::new(data, data.len());
+	fs.write(&Context::new(), ino, handle, &mut r, data.len() as u32, off, None, false, 0, 0)
+}
+
+/// Read up to `size` bytes at `off` through `handle`, as the kernel
+/// would.
+fn read(fs: &SlabFs, ino: Inode, handle: u64, size: u32, off: u64) -> io::Result<Vec<u8>> {
+	let mut w = MockWriter::default();
+	fs.read(&Context::new(), ino, handle, &mut w, size, off, None, 0)?;
+	Ok(w.0)
+}
+
+fn open(fs: &SlabFs, ino: Inode, flags: i32) -> io::Result<u64> {
+	let (handle, _) = fs.open(&Context::new(), ino, flags as u32, 0)?;
+	Ok(handle.unwrap())
+}
+
+#[test]
+fn directories_open_for_fd_based_calls() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let file = mknod(&fs, dir, "f");
+
+	let handle = open(&fs, dir, libc::O_RDONLY).unwrap();
+	let (st, _) = fs.getattr(&ctx, dir, Some(handle)).unwrap();
+	assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFDIR);
+	let mut names = Vec::new();
+	fs.readdir(&ctx, dir, handle, 4096, 0, &mut |entry| {
+		names.push((Inode::from(entry.ino), entry.name.to_vec()));
+		Ok(1)
+	})
+	.unwrap();
+	assert_eq!(names, [(file, b"f".to_vec())]);
+	let e = write(&fs, dir, handle, b"x", 0).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EISDIR));
+	fs.release(&ctx, dir, 0, handle, false, false, None).unwrap();
+	assert!(fs.handles.get(handle).is_err());
+
+	assert_eq!(open(&fs, dir, libc::O_RDWR).unwrap_err().raw_os_error(), Some(libc::EISDIR));
+	let e = fs.opendir(&ctx, file, 0).err().unwrap();
+	assert_eq!(e.raw_os_error(), Some(libc::ENOTDIR));
+}
+
+#[test]
+fn file_io_on_directories_fails_with_eisdir() {
+	let fs = SlabFs::builder().build().unwrap();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let handle = open(&fs, dir, libc::O_RDONLY).unwrap();
+	let errs = [
+		read(&fs, dir, handle, 16, 0).err(),
+		write(&fs, dir, handle, b"x", 0).err(),
+		fs.read_at(dir, 0, 16).err(),
+		fs.write_at(dir, 0, b"x").err(),
+	];
+	for e in errs {
+		assert_eq!(e.unwrap().raw_os_error(), Some(libc::EISDIR));
+	}
+}
+
+#[test]
+fn fsync_succeeds_and_makes_writes_durable() {
+	let ctx = Context::new();
+	let fs = SlabFs::builder().build().unwrap();
+	let ino = mknod(&fs, Inode::ROOT, "f");
+	let handle = open(&fs, ino, libc::O_RDWR).unwrap();
+	write(&fs, ino, handle, b"cached", 0).unwrap();
+	fs.fsync(&ctx, ino, false, handle).unwrap();
+	fs.fsync(&ctx, ino, true, handle).unwrap();
+	fs.fsyncdir(&ctx, Inode::ROOT, false, 0).unwrap();
+
+	// Coalesced writes are held back until synced
+	let journal = TempPath::new("fsync");
+	let fs = SlabFs::builder().journal(&journal.0).coalesce_writes(true).build().unwrap();
+	let ino = mknod(&fs, Inode::ROOT, "f");
+	let handle = open(&fs, ino, libc::O_RDWR).unwrap();
+	write(&fs, ino, handle, b"cached", 0).unwrap();
+	fs.fsync(&ctx, ino, false, handle).unwrap();
+	std::mem::forget(fs);
+
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	let ino = find(&fs, Inode::ROOT, "f").unwrap();
+	assert_eq!(fs.read_at(ino, 0, 64).unwrap(), b"cached");
+}
+
+#[test]
+fn init_leaves_out_splice_when_disabled() {
+	let splice = FsOptions::SPLICE_READ | FsOptions::SPLICE_WRITE | FsOptions::SPLICE_MOVE;
+	let fs = SlabFs::builder().build().unwrap();
+	let cap = fs.init(FsOptions::all()).unwrap();
+	assert!(cap.contains(splice | FsOptions::WRITEBACK_CACHE));
+
+	let fs = SlabFs::builder().splice(false).build().unwrap();
+	let without = fs.init(FsOptions::all()).unwrap();
+	assert!(!without.intersects(splice));
+	assert_eq!(without, cap - splice);
+}
+
+#[test]
+fn names_reject_nul_slashes_and_empty_strings() {
+	for bad in [&b""[..], b"a/b", b"/", b"a\0b", b"\0"] {
+		assert_eq!(Name::new(bad).unwrap_err().raw_os_error(), Some(libc::EINVAL), "{:?}", bad);
+	}
+	assert!(Name::try_from("a/b").is_err());
+	assert_eq!(Name::try_from(&*cstr("file.txt")).unwrap().as_bytes(), b"file.txt");
+	assert_eq!(Name::new(b"\xff\xfe").unwrap().as_bytes(), b"\xff\xfe");
+	assert!(Name::empty().as_bytes().is_empty());
+
+	let fs = SlabFs::builder().build().unwrap();
+	let e = fs.create_file(Inode::ROOT, "a/b", 0o644).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+	assert!(fs.list_dir(Inode::ROOT).unwrap().is_empty());
+}
+
+fn vslice(buf: &mut [u8]) -> FileVolatileSlice<'_> {
+	// SAFETY: the slice borrows `buf`
+	unsafe { FileVolatileSlice::from_raw_ptr(buf.as_mut_ptr(), buf.len()) }
+}
+
+#[test]
+fn file_writers_write_and_read_through_every_method() {
+	let mut data = FileData::new();
+	let mut w = FileWriter::new(&mut data);
+	assert_eq!(w.write_volatile(vslice(&mut b"hello".to_vec())).unwrap(), 5);
+	w.write_all_volatile(vslice(&mut b" world".to_vec())).unwrap();
+	let (mut bang, mut what) = (*b"!", *b"?");
+	let bufs = [vslice(&mut []), vslice(&mut bang), vslice(&mut what)];
+	assert_eq!(w.write_vectored_volatile(&bufs).unwrap(), 1);
+	assert_eq!(w.write_at_volatile(vslice(&mut b"J".to_vec()), 0).unwrap(), 1);
+	w.write_all_at_volatile(vslice(&mut b"W".to_vec()), 6).unwrap();
+	assert_eq!(w.write_vectored_at_volatile(&[vslice(&mut b"xy".to_vec())], 14).unwrap(), 2);
+	assert_eq!(w.write_vectored_at_volatile(&[], 0).unwrap(), 0);
+	// Writing past the end zero-fills the gap
+	let expected = b"Jello World!\0\0xy";
+
+	let mut buf = [0; 5];
+	assert_eq!(w.read_at_volatile(vslice(&mut buf), 0).unwrap(), 5);
+	assert_eq!(&buf, b"Jello");
+	assert_eq!(w.read_volatile(vslice(&mut buf)).unwrap(), 4);
+	assert_eq!(&buf[..4], &expected[12..]);
+	assert_eq!(data.as_slice(), expected);
+}
+
+#[test]
+fn file_readers_read_through_every_method_and_refuse_writes() {
+	let data = b"0123456789";
+	let mut r = FileReader::new(data);
+	let mut buf = [0; 4];
+	assert_eq!(r.read_volatile(vslice(&mut buf)).unwrap(), 4);
+	assert_eq!(&buf, b"0123");
+	r.read_exact_volatile(vslice(&mut buf)).unwrap();
+	assert_eq!(&buf, b"4567");
+	let (mut a, mut b) = ([0; 1], [0; 4]);
+	assert_eq!(r.read_vectored_volatile(&[vslice(&mut []), vslice(&mut a), vslice(&mut b)]).unwrap(), 1);
+	assert_eq!(&a, b"8");
+	let e = r.read_exact_volatile(vslice(&mut buf)).unwrap_err();
+	assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+	assert_eq!(r.read_at_volatile(vslice(&mut buf), 8).unwrap(), 2);
+	assert_eq!(&buf[..2], b"89");
+	assert_eq!(r.read_at_volatile(vslice(&mut buf), 10).unwrap(), 0);
+	assert_eq!(r.read_at_volatile(vslice(&mut buf), u64::MAX).unwrap(), 0);
+	r.read_exact_at_volatile(vslice(&mut buf), 2).unwrap();
+	assert_eq!(&buf, b"2345");
+	assert_eq!(r.read_vectored_at_volatile(&[vslice(&mut buf)], 6).unwrap(), 4);
+	assert_eq!(&buf, b"6789");
+
+	let mut src = b"x".to_vec();
+	let errs = [
+		r.write_volatile(vslice(&mut src)).err(),
+		r.write_at_volatile(vslice(&mut src), 0).err(),
+		r.write_all_volatile(vslice(&mut src)).err(),
+		r.write_vectored_volatile(&[vslice(&mut src)]).err(),
+		r.write_all_at_volatile(vslice(&mut src), 0).err(),
+		r.write_vectored_at_volatile(&[vslice(&mut src)], 0).err(),
+	];
+	for e in errs {
+		assert_eq!(e.unwrap().raw_os_error(), Some(libc::EBADF));
+	}
+	assert_eq!(data, b"0123456789");
+}
+
+#[test]
+fn sockets_and_fifos_can_be_made_and_stat_ed() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	for (name, kind) in [("sock", libc::S_IFSOCK), ("fifo", libc::S_IFIFO)] {
+		let entry = fs.mknod(&ctx, Inode::ROOT, &cstr(name), kind | 0o640, 0, 0).unwrap();
+		let st = stat(&fs, Inode::from(entry.inode));
+		assert_eq!(st.st_mode, kind | 0o640, "{}", name);
+		assert_eq!((st.st_size, st.st_blocks, st.st_rdev), (0, 0, 0));
+	}
+
+	// They hold no data of their own
+	let sock = fs.create_file(Inode::ROOT, "made", libc::S_IFSOCK | 0o600).unwrap();
+	assert_eq!(stat(&fs, sock).st_mode & libc::S_IFMT, libc::S_IFSOCK);
+	assert!(fs.write_at(sock, 0, b"x").is_err());
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 3);
+}
+
+#[test]
+fn symlinks_report_their_target_length_and_lrwxrwxrwx() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let target = b"twelve/bytes";
+	let entry = fs.symlink(&ctx, &CString::new(&target[..]).unwrap(), Inode::ROOT, &cstr("l")).unwrap();
+	let ino = Inode::from(entry.inode);
+	let st = stat(&fs, ino);
+	assert_eq!(st.st_size, 12);
+	assert_eq!(st.st_mode, libc::S_IFLNK | 0o777);
+	assert_eq!(entry.attr.st_size, 12);
+	assert_eq!(fs.readlink(&ctx, ino).unwrap(), target);
+}
+
+#[test]
+fn symlink_targets_are_bounded_by_path_max() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let target = |len| CString::new(vec![b'a'; len]).unwrap();
+	// PATH_MAX counts the terminating NUL
+	for len in [PATH_MAX, PATH_MAX + 1] {
+		let e = fs.symlink(&ctx, &target(len), Inode::ROOT, &cstr("l")).err().unwrap();
+		assert_eq!(e.raw_os_error(), Some(libc::ENAMETOOLONG));
+	}
+	assert!(fs.list_dir(Inode::ROOT).unwrap().is_empty());
+	let entry = fs.symlink(&ctx, &target(PATH_MAX - 1), Inode::ROOT, &cstr("l")).unwrap();
+	assert_eq!(entry.attr.st_size, PATH_MAX as i64 - 1);
+}
+
+/// A tree with one inode of every type that can be made, and names
+/// and targets that are not UTF-8.
+fn make_tree(fs: &SlabFs) {
+	let ctx = Context::new();
+	let dir = mkdir(fs, Inode::ROOT, "dir");
+	let file = fs.create_file(dir, "file", 0o640).unwrap();
+	fs.write_at(file, 0, b"contents").unwrap();
+	mkdir(fs, dir, "empty");
+	let odd = CString::new(&b"caf\xe9 \"q\"\\"[..]).unwrap();
+	fs.mknod(&ctx, Inode::ROOT, &odd, libc::S_IFREG | 0o600, 0, 0).unwrap();
+	let target = CString::new(&b"dir/\xff\xfe"[..]).unwrap();
+	fs.symlink(&ctx, &target, Inode::ROOT, &cstr("link")).unwrap();
+	for (name, kind) in [("fifo", libc::S_IFIFO), ("sock", libc::S_IFSOCK), ("chr", libc::S_IFCHR)] {
+		fs.mknod(&ctx, dir, &cstr(name), kind | 0o644, 0, 0).unwrap();
+	}
+}
+
+#[test]
+fn tree_dumps_are_indented_bounded_and_lossless() {
+	let fs = SlabFs::builder().build().unwrap();
+	make_tree(&fs);
+	let files = fs.files();
+	let (text, complete) = tree::dump(&files, Inode::ROOT, usize::MAX).unwrap();
+	assert!(complete);
+	assert!(text.starts_with("{\n\t\"ino\": 1,\n\t\"name\": \"\",\n\t\"type\": \"dir\","), "{}", text);
+	assert!(text.contains("\n\t\"children\": [\n\t\t{\n\t\t\t\"ino\": "), "{}", text);
+	assert!(text.contains("\"children\": []"), "{}", text);
+	assert!(text.ends_with("\n\t]\n}"), "{}", text);
+
+	let root = json::parse(&text).unwrap();
+	let children = root.get("children").unwrap().as_array().unwrap();
+	let names: Vec<&[u8]> = children.iter().map(|c| c.get("name").unwrap().as_bytes().unwrap()).collect();
+	assert_eq!(names, [&b"dir"[..], b"caf\xe9 \"q\"\\", b"link"]);
+	assert_eq!(children[2].get("target").unwrap().as_bytes().unwrap(), b"dir/\xff\xfe");
+	// Every type that is dumped can be loaded
+	tree::load(&SlabFs::builder().build().unwrap(), &text).unwrap();
+
+	// Cut short anywhere, the dump still parses and fits
+	for max in 0..text.len() {
+		let (part, complete) = tree::dump(&files, Inode::ROOT, max).unwrap();
+		assert!(!complete);
+		assert!(part.len() <= max, "{} > {}", part.len(), max);
+		assert!(part.is_empty() || json::parse(&part).is_ok(), "{}", part);
+	}
+}
+
+/// What a dump says about a node and everything under it, leaving
+/// out what depends on the instance: inode numbers, references and
+/// generations.
+fn dumped_shape(val: &json::Value) -> String {
+	let mut out = String::new();
+	for key in ["name", "type", "target"] {
+		if let Some(v) = val.get(key) {
+			out.push_str(&format!("{}={:?} ", key, v.as_bytes().unwrap()));
+		}
+	}
+	for key in ["mode", "uid", "gid", "size"] {
+		out.push_str(&format!("{}={} ", key, val.get(key).unwrap().as_u64().unwrap()));
+	}
+	if let Some(children) = val.get("children") {
+		let children: Vec<String> = children.as_array().unwrap().iter().map(dumped_shape).collect();
+		out.push_str(&format!("[{}]", children.join(", ")));
+	}
+	out
+}
+
+fn dump_all(fs: &SlabFs) -> String {
+	let (text, complete) = tree::dump(&fs.files(), Inode::ROOT, usize::MAX).unwrap();
+	assert!(complete);
+	text
+}
+
+#[test]
+fn generations_grow_across_checkpoints_and_dumps() {
+	let generation = |fs: &SlabFs, ino| fs.files().get(ino).unwrap().generation;
+	let journal = TempPath::new("generations");
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	fs.create_file(Inode::ROOT, "kept", 0o644).unwrap();
+	// The newest generations belong to removed inodes
+	for name in ["a", "b", "c"] {
+		fs.create_file(Inode::ROOT, name, 0o644).unwrap();
+		fs.unlink(&Context::new(), Inode::ROOT, &cstr(name)).unwrap();
+	}
+	let last = generation(&fs, fs.create_file(Inode::ROOT, "gone", 0o644).unwrap());
+	fs.unlink(&Context::new(), Inode::ROOT, &cstr("gone")).unwrap();
+	fs.checkpoint().unwrap();
+	let text = dump_all(&fs);
+	drop(fs);
+
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	assert!(generation(&fs, fs.create_file(Inode::ROOT, "new", 0o644).unwrap()) > last);
+	let loaded = SlabFs::builder().build().unwrap();
+	tree::load(&loaded, &text).unwrap();
+	assert!(generation(&loaded, loaded.create_file(Inode::ROOT, "new", 0o644).unwrap()) > last);
+}
+
+#[test]
+fn tree_dumps_load_back_the_same_tree() {
+	let fs = SlabFs::builder().build().unwrap();
+	make_tree(&fs);
+	let text = dump_all(&fs);
+
+	let loaded = SlabFs::builder().build().unwrap();
+	tree::load(&loaded, &text).unwrap();
+	let reloaded = dump_all(&loaded);
+	assert_eq!(dumped_shape(&json::parse(&reloaded).unwrap()), dumped_shape(&json::parse(&text).unwrap()));
+
+	// Contents are zeros unless given
+	let dir = find(&loaded, Inode::ROOT, "dir").unwrap();
+	let file = find(&loaded, dir, "file").unwrap();
+	assert_eq!(loaded.read_at(file, 0, 64).unwrap(), [0; 8]);
+	let text = r#"{"ino": 1, "name": "", "type": "dir", "mode": 493, "children": [
+		{"ino": 2, "name": "f", "type": "file", "mode": 420, "contents": "aGVsbG8="}]}"#;
+	let loaded = SlabFs::builder().build().unwrap();
+	tree::load(&loaded, text).unwrap();
+	assert_eq!(loaded.read_at(find(&loaded, Inode::ROOT, "f").unwrap(), 0, 64).unwrap(), b"hello");
+}
+
+#[test]
+fn malformed_tree_dumps_are_rejected() {
+	let root = |children: &str| {
+		format!(r#"{{"ino": 1, "name": "", "type": "dir", "mode": 493, "children": [{}]}}"#, children)
+	};
+	let bad = [
+		"[]".to_string(),
+		r#"{"ino": 1, "name": "", "type": "file", "mode": 420}"#.to_string(),
+		root(r#"{"ino": 1, "name": "a", "type": "file", "mode": 420}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "file", "mode": 420, "parent": 7}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "blk", "mode": 420}"#),
+		root(r#"{"ino": 2, "type": "file", "mode": 420}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "file"}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "dir", "mode": 493, "contents": ""}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "file", "mode": 420, "children": []}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "file", "mode": 420, "contents": "!!!!"}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "file", "mode": 420, "size": 9, "contents": "aGVsbG8="}"#),
+		root(r#"{"ino": 2, "name": "a", "type": "symlink", "mode": 511}"#),
+		root(r#"{"ino": 2, "name": "a/b", "type": "file", "mode": 420}"#),
+	];
+	for text in bad {
+		let fs = SlabFs::builder().build().unwrap();
+		let e = tree::load(&fs, &text).unwrap_err();
+		assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}: {}", text, e);
+	}
+}
+
+#[test]
+fn overlay_whiteouts_and_opaque_dirs_can_be_made() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let entry = fs.mknod(&ctx, Inode::ROOT, &cstr("wh"), libc::S_IFCHR, 0, 0).unwrap();
+	let st = stat(&fs, Inode::from(entry.inode));
+	assert_eq!((st.st_mode & libc::S_IFMT, st.st_rdev), (libc::S_IFCHR, 0));
+	let e = fs.mknod(&ctx, Inode::ROOT, &cstr("tty"), libc::S_IFCHR, libc::makedev(4, 1) as u32, 0).err().unwrap();
+	assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	fs.setxattr(&ctx, dir, &cstr("trusted.overlay.opaque"), b"y", 0).unwrap();
+	assert_eq!(getxattr(&fs, dir, "trusted.overlay.opaque").unwrap(), b"y");
+}
+
+fn user_ctx() -> Context {
+	Context { uid: 1000, gid: 1000, pid: 0 }
+}
+
+#[test]
+fn unprivileged_xattrs_are_limited_to_the_user_namespace() {
+	for any in [false, true] {
+		let fs = SlabFs::builder().all_xattr_namespaces(any).build().unwrap();
+		let entry = fs.mknod(&Context::new(), Inode::ROOT, &cstr("f"), libc::S_IFREG | 0o666, 0, 0).unwrap();
+		let file = Inode::from(entry.inode);
+		fs.setxattr(&user_ctx(), file, &cstr("user.x"), b"v", 0).unwrap();
+		fs.removexattr(&user_ctx(), file, &cstr("user.x")).unwrap();
+		for name in ["system.x", "other.x", "x"] {
+			let res = fs.setxattr(&user_ctx(), file, &cstr(name), b"v", 0);
+			match any {
+				true => res.unwrap(),
+				false => assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EPERM), "{}", name),
+			}
+			fs.setxattr(&Context::new(), file, &cstr(name), b"v", 0).unwrap();
+		}
+	}
+}
+
+#[test]
+fn only_root_writes_security_and_trusted_xattrs() {
+	let fs = SlabFs::builder().all_xattr_namespaces(true).build().unwrap();
+	let file = mknod(&fs, Inode::ROOT, "f");
+	let cap = cstr("security.capability");
+	let value = [1, 0, 0, 2, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+	for name in ["security.capability", "trusted.x"] {
+		let e = fs.setxattr(&user_ctx(), file, &cstr(name), b"v", 0).unwrap_err();
+		assert_eq!(e.raw_os_error(), Some(libc::EPERM), "{}", name);
+		fs.setxattr(&Context::new(), file, &cstr(name), b"v", 0).unwrap();
+		let e = fs.removexattr(&user_ctx(), file, &cstr(name)).unwrap_err();
+		assert_eq!(e.raw_os_error(), Some(libc::EPERM), "{}", name);
+	}
+	fs.setxattr(&Context::new(), file, &cap, &value, 0).unwrap();
+	assert_eq!(getxattr(&fs, file, "security.capability").unwrap(), value);
+	fs.removexattr(&Context::new(), file, &cap).unwrap();
+	assert_eq!(getxattr(&fs, file, "security.capability"), None);
+}
+
+#[test]
+fn xattr_sizes_are_capped() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let file = mknod(&fs, Inode::ROOT, "f");
+	let e = fs.setxattr(&ctx, file, &cstr("user.big"), &vec![0; XATTR_VALUE_MAX + 1], 0).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::E2BIG));
+
+	// Fill up the inode with 8 KiB values until the total is reached
+	let value = vec![1; 8 * 1024];
+	let mut set = 0;
+	let e = loop {
+		match fs.setxattr(&ctx, file, &cstr(&format!("user.{}", set)), &value, 0) {
+			Ok(()) => set += 1,
+			Err(e) => break e,
+		}
+	};
+	assert_eq!(e.raw_os_error(), Some(libc::ENOSPC));
+	assert_eq!(set, XATTR_TOTAL_MAX / (value.len() + 6));
+
+	// Replacing a value only counts the difference, and removing one
+	// makes room again
+	fs.setxattr(&ctx, file, &cstr("user.0"), &value[..1], 0).unwrap();
+	fs.setxattr(&ctx, file, &cstr(&format!("user.{}", set)), &value, 0).unwrap();
+	fs.removexattr(&ctx, file, &cstr("user.1")).unwrap();
+	fs.setxattr(&ctx, file, &cstr("user.0"), &value, 0).unwrap();
+	// Other inodes have limits of their own
+	let other = mknod(&fs, Inode::ROOT, "g");
+	fs.setxattr(&ctx, other, &cstr("user.0"), &value, 0).unwrap();
+}
+
+/// An ACL as setfacl passes it, from (tag, perm, id) entries.
+fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+	let mut out = 2u32.to_le_bytes().to_vec();
+	for (tag, perm, id) in entries {
+		out.extend_from_slice(&tag.to_le_bytes());
+		out.extend_from_slice(&perm.to_le_bytes());
+		out.extend_from_slice(&id.to_le_bytes());
+	}
+	out
+}
+
+#[test]
+fn posix_acls_round_trip_and_grant_access() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let access = cstr("system.posix_acl_access");
+	let entry = fs.mknod(&ctx, Inode::ROOT, &cstr("f"), libc::S_IFREG | 0o600, 0, 0).unwrap();
+	let file = Inode::from(entry.inode);
+	let check = |uid, mask| fs.access(&Context { uid, gid: uid, pid: 0 }, file, mask as u32).is_ok();
+	assert!(!check(1000, libc::R_OK));
+
+	// user::rw-, user:1000:r--, group::---, mask::r--, other::---
+	let value = acl(&[(0x01, 6, u32::MAX), (0x02, 4, 1000), (0x04, 0, u32::MAX), (0x10, 4, u32::MAX), (0x20, 0, u32::MAX)]);
+	fs.setxattr(&ctx, file, &access, &value, 0).unwrap();
+	assert_eq!(getxattr(&fs, file, "system.posix_acl_access").unwrap(), value);
+	assert_eq!(stat(&fs, file).st_mode & 0o777, 0o640);
+	assert!(check(1000, libc::R_OK));
+	assert!(!check(1000, libc::W_OK));
+	assert!(!check(1001, libc::R_OK));
+
+	// An ACL that says no more than the mode is kept as just that
+	let minimal = acl(&[(0x01, 6, u32::MAX), (0x04, 4, u32::MAX), (0x20, 4, u32::MAX)]);
+	fs.setxattr(&ctx, file, &access, &minimal, 0).unwrap();
+	assert_eq!(getxattr(&fs, file, "system.posix_acl_access"), None);
+	assert_eq!(stat(&fs, file).st_mode & 0o777, 0o644);
+	let e = fs.setxattr(&ctx, file, &access, b"junk", 0).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+
+	// New entries in a directory inherit its default ACL
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let e = fs.setxattr(&ctx, file, &cstr("system.posix_acl_default"), &value, 0).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EACCES));
+	fs.setxattr(&ctx, dir, &cstr("system.posix_acl_default"), &value, 0).unwrap();
+	let entry = fs.mknod(&ctx, dir, &cstr("f"), libc::S_IFREG | 0o666, 0, 0o022).unwrap();
+	let child = Inode::from(entry.inode);
+	assert_eq!(getxattr(&fs, child, "system.posix_acl_access").unwrap(), value);
+	assert_eq!(stat(&fs, child).st_mode & 0o777, 0o640);
+	assert!(fs.access(&user_ctx(), child, libc::R_OK as u32).is_ok());
+}
+
+#[test]
+fn truncate_grows_shrinks_and_leaves_equal_sizes_alone() {
+	let fs = SlabFs::builder().build().unwrap();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let inline = |fs: &SlabFs| matches!(fs.files_mut().get_mut(file).unwrap().file_data().unwrap(), FileData::Inline { .. });
+	fs.write_at(file, 0, b"hello").unwrap();
+
+	// Growing zero-fills, past the inline buffer too
+	fs.files_mut().truncate(file, 8).unwrap();
+	assert_eq!(fs.read_at(file, 0, 64).unwrap(), b"hello\0\0\0");
+	assert!(inline(&fs));
+	let len = 4 * INLINE_CAP;
+	fs.files_mut().truncate(file, len as u64).unwrap();
+	assert!(!inline(&fs));
+	assert_eq!(stat(&fs, file).st_size, len as i64);
+	assert_eq!(&fs.read_at(file, 0, len).unwrap()[5..], vec![0; len - 5]);
+
+	// The same size changes nothing
+	fs.write_at(file, 0, &vec![7; len]).unwrap();
+	fs.files_mut().truncate(file, len as u64).unwrap();
+	assert_eq!(fs.read_at(file, 0, len).unwrap(), vec![7; len]);
+
+	// Shrinking gives memory back, down to the inline buffer
+	fs.files_mut().truncate(file, 2 * INLINE_CAP as u64).unwrap();
+	assert_eq!(fs.read_at(file, 0, len).unwrap(), vec![7; 2 * INLINE_CAP]);
+	fs.files_mut().truncate(file, 3).unwrap();
+	assert!(inline(&fs));
+	assert_eq!(fs.read_at(file, 0, len).unwrap(), [7; 3]);
+	fs.files_mut().truncate(file, 0).unwrap();
+	assert_eq!(stat(&fs, file).st_size, 0);
+
+	let e = fs.files_mut().truncate(dir, 0).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EISDIR));
+}
+
+#[test]
+fn unlinked_files_live_until_their_last_handle_closes() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	fs.write_at(file, 0, b"data").unwrap();
+	let handles = [open(&fs, file, libc::O_RDONLY).unwrap(), open(&fs, file, libc::O_RDWR).unwrap()];
+	assert!(fs.handles.is_open(file));
+	fs.unlink(&ctx, Inode::ROOT, &cstr("f")).unwrap();
+
+	fs.release(&ctx, file, 0, handles[0], false, false, None).unwrap();
+	assert!(fs.handles.is_open(file));
+	assert_eq!(read(&fs, file, handles[1], 64, 0).unwrap(), b"data");
+	let e = fs.release(&ctx, file, 0, handles[0], false, false, None).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EBADF));
+
+	fs.release(&ctx, file, 0, handles[1], false, false, None).unwrap();
+	assert!(!fs.handles.is_open(file));
+	assert!(fs.files().get(file).is_err());
+}
+
+#[test]
+fn check_access_matches_owner_group_and_other_bits() {
+	let fs = SlabFs::builder().build().unwrap();
+	let owner = Context { uid: 1000, gid: 100, pid: 0 };
+	let entry = fs.mknod(&owner, Inode::ROOT, &cstr("f"), libc::S_IFREG | 0o754, 0, 0).unwrap();
+	let file = Inode::from(entry.inode);
+	let may = |uid, gid, mask: i32| fs.check_access(file, uid, gid, mask as u32).unwrap();
+	let (r, w, x) = (libc::R_OK, libc::W_OK, libc::X_OK);
+
+	// The owner bits apply to the owner, even if the group's would
+	// say otherwise
+	assert!(may(1000, 100, r | w | x));
+	assert!(may(1000, 200, w));
+	assert!(may(1001, 100, r | x));
+	assert!(!may(1001, 100, w));
+	assert!(may(1001, 200, r));
+	assert!(!may(1001, 200, x));
+	assert!(may(0, 0, r | w | x));
+	// Asking changes nothing
+	assert_eq!(stat(&fs, file).st_mode & 0o7777, 0o754);
+
+	let dir = fs.mkdir(&owner, Inode::ROOT, &cstr("d"), 0o700, 0).unwrap();
+	let dir = Inode::from(dir.inode);
+	assert!(fs.check_access(dir, 0, 0, libc::X_OK as u32).unwrap());
+	assert!(!fs.check_access(dir, 1001, 100, libc::X_OK as u32).unwrap());
+	assert!(fs.check_access(Inode::from(9999u64), 0, 0, 0).is_err());
+}
+
+#[test]
+fn huge_inode_numbers_name_no_inode() {
+	let fs = SlabFs::builder().build().unwrap();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	assert_eq!(usize::from(Inode::from(u64::MAX)), usize::MAX);
+	// On 32-bit targets these would otherwise alias the root and `f`
+	for ino in [(1u64 << 32) + 1, (1 << 32) + u64::from(file), u64::MAX] {
+		assert!(fs.files().get(Inode::from(ino)).is_err(), "{}", ino);
+		assert!(fs.getattr(&Context::new(), Inode::from(ino), None).is_err(), "{}", ino);
+	}
+}
+
+/// Keeps the messages logged by the tests.
+struct CaptureLog(Mutex<Vec<String>>);
+
+impl log::Log for CaptureLog {
+	fn enabled(&self, _: &log::Metadata) -> bool {
+		true
+	}
+
+	fn log(&self, record: &log::Record) {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner).push(record.args().to_string());
+	}
+
+	fn flush(&self) {}
+}
+
+static LOG: CaptureLog = CaptureLog(Mutex::new(Vec::new()));
+
+#[test]
+fn creates_and_removals_log_the_calling_pid() {
+	log::set_logger(&LOG).unwrap();
+	log::set_max_level(log::LevelFilter::Trace);
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context { uid: 0, gid: 0, pid: 4242 };
+	fs.mkdir(&ctx, Inode::ROOT, &cstr("d"), 0o755, 0).unwrap();
+	fs.mknod(&ctx, Inode::ROOT, &cstr("f"), libc::S_IFREG | 0o644, 0, 0).unwrap();
+	fs.symlink(&ctx, &cstr("f"), Inode::ROOT, &cstr("l")).unwrap();
+	fs.create(&ctx, Inode::ROOT, &cstr("c"), CreateIn { flags: 0, mode: libc::S_IFREG | 0o644, umask: 0, fuse_flags: 0 }).unwrap();
+	fs.unlink(&ctx, Inode::ROOT, &cstr("f")).unwrap();
+	fs.rmdir(&ctx, Inode::ROOT, &cstr("d")).unwrap();
+
+	// Other tests log too, so only look for this one's pid
+	let lines = LOG.0.lock().unwrap_or_else(PoisonError::into_inner);
+	let ops: Vec<&str> = lines
+		.iter()
+		.filter(|line| line.ends_with("pid=4242)"))
+		.map(|line| line.split('(').next().unwrap())
+		.collect();
+	assert_eq!(ops, ["mkdir", "mknod", "symlink", "create", "unlink", "rmdir"]);
+}
+
+#[test]
+fn remove_tree_frees_a_deep_tree_at_once() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let before = fs.files().files.len();
+	let top = mkdir(&fs, Inode::ROOT, "top");
+	let mut dir = top;
+	for i in 0..500 {
+		fs.create_file(dir, "f", 0o644).unwrap();
+		fs.symlink(&ctx, &cstr("f"), dir, &cstr("l")).unwrap();
+		dir = mkdir(&fs, dir, &i.to_string());
+	}
+	let held = fs.create_file(dir, "held", 0o644).unwrap();
+	fs.write_at(held, 0, b"still here").unwrap();
+	let handle = open(&fs, held, libc::O_RDONLY).unwrap();
+	mkdir(&fs, Inode::ROOT, "kept");
+
+	assert_eq!(fs.remove_tree(top).unwrap(), 1 + 500 * 3);
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 1);
+	assert!(find(&fs, Inode::ROOT, "top").is_none());
+	assert_eq!(fs.remove_tree(top).err().unwrap().kind(), io::ErrorKind::NotFound);
+	assert_eq!(fs.remove_tree(Inode::ROOT).err().unwrap().raw_os_error(), Some(libc::EBUSY));
+
+	// Open files stay readable until closed
+	assert_eq!(read(&fs, held, handle, 64, 0).unwrap(), b"still here");
+	fs.release(&ctx, held, 0, handle, false, false, None).unwrap();
+	assert_eq!(fs.files().files.len(), before + 1);
+}
+
+#[test]
+fn bulk_create_makes_all_files_or_none() {
+	let fs = SlabFs::builder().max_inodes(10_003).build().unwrap();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let names: Vec<String> = (0..10_000).map(|i| format!("f{}", i)).collect();
+	let names: Vec<&str> = names.iter().map(String::as_str).collect();
+	let inos = fs.bulk_create(dir, &names).unwrap();
+	assert_eq!(inos.len(), names.len());
+	assert_eq!(find(&fs, dir, "f1234"), Some(inos[1234]));
+	assert_eq!(stat(&fs, inos[0]).st_mode, libc::S_IFREG | 0o644);
+	assert_eq!(fs.list_dir(dir).unwrap().len(), names.len());
+
+	let long = "n".repeat(256);
+	let bad: [(&[&str], io::Error); 6] = [
+		(&["x", "f7"], ioerr!(AlreadyExists)),
+		(&["x", "y", "x"], ioerr!(AlreadyExists)),
+		(&["x", ""], ioerr!(libc::EINVAL)),
+		(&["x", "a/b"], ioerr!(libc::EINVAL)),
+		(&["x", ".."], ioerr!(libc::EINVAL)),
+		(&["x", &long], ioerr!(libc::ENAMETOOLONG)),
+	];
+	for (names, want) in bad {
+		let e = fs.bulk_create(dir, names).unwrap_err();
+		assert_eq!((e.kind(), e.raw_os_error()), (want.kind(), want.raw_os_error()), "{:?}", names);
+	}
+	// One inode is left, so two are too many
+	let e = fs.bulk_create(Inode::ROOT, &["x", "y"]).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::ENOSPC));
+	assert!(find(&fs, dir, "x").is_none());
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 1);
+	fs.bulk_create(Inode::ROOT, &["x"]).unwrap();
+}
+
+#[test]
+fn writes_take_data_in_pieces_and_report_short_ones() {
+	let fs = SlabFs::builder().verify_reads(true).build().unwrap();
+	let ctx = Context::new();
+	let ino = mknod(&fs, Inode::ROOT, "f");
+	let handle = open(&fs, ino, libc::O_RDWR).unwrap();
+	let data: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+	let mut r = MockReader::new(&data, 999);
+	assert_eq!(fs.write(&ctx, ino, handle, &mut r, data.len() as u32, 0, None, false, 0, 0).unwrap(), data.len());
+	assert_eq!(read(&fs, ino, handle, 32_768, 0).unwrap(), data);
+
+	// Corrupt the second block, then write less than promised to
+	// the first: the short count is reported, and the corruption
+	// is still caught
+	fs.files_mut().get_mut(ino).unwrap().file_data().unwrap().try_grow(0).unwrap()[5000] ^= 1;
+	let mut r = MockReader::new(&[0xff; 100], 30);
+	assert_eq!(fs.write(&ctx, ino, handle, &mut r, 10_000, 0, None, false, 0, 0).unwrap(), 100);
+	assert_eq!(read(&fs, ino, handle, 100, 0).unwrap(), [0xff; 100]);
+	let e = read(&fs, ino, handle, 32_768, 0).unwrap_err();
+	assert_eq!(e.raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn stats_count_created_and_removed_inodes() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let before = fs.files().stats();
+	assert_eq!((before.created, before.removed), (0, 0));
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	for i in 0..10 {
+		fs.create_file(dir, &i.to_string(), 0o644).unwrap();
+	}
+	for i in 0..4 {
+		fs.unlink(&ctx, dir, &cstr(&i.to_string())).unwrap();
+	}
+	fs.create_file(dir, "new", 0o644).unwrap();
+	// Failed creates count for nothing
+	fs.create_file(dir, "new", 0o644).unwrap_err();
+
+	let stats = fs.files().stats();
+	assert_eq!((stats.created, stats.removed), (12, 4));
+	assert_eq!(stats.occupied - before.occupied, stats.created - stats.removed);
+	assert_eq!(stats.peak, before.occupied + 11);
+
+	assert_eq!(fs.remove_tree(dir).unwrap(), 8);
+	let stats = fs.files().stats();
+	assert_eq!((stats.created, stats.removed, stats.occupied), (12, 12, before.occupied));
+}
+
+#[test]
+fn directories_have_two_links_and_other_inodes_one() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	assert_eq!(stat(&fs, Inode::ROOT).st_nlink, 2);
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	assert_eq!(stat(&fs, dir).st_nlink, 2);
+	// Subdirectories are not counted
+	mkdir(&fs, dir, "sub");
+	assert_eq!(stat(&fs, dir).st_nlink, 2);
+	let entry = fs.lookup(&ctx, Inode::ROOT, &cstr("d")).unwrap();
+	assert_eq!(entry.attr.st_nlink, 2);
+
+	let file = mknod(&fs, dir, "f");
+	fs.symlink(&ctx, &cstr("f"), dir, &cstr("l")).unwrap();
+	assert_eq!(stat(&fs, file).st_nlink, 1);
+	assert_eq!(stat(&fs, find(&fs, dir, "l").unwrap()).st_nlink, 1);
+	let handle = open(&fs, file, libc::O_RDONLY).unwrap();
+	fs.unlink(&ctx, dir, &cstr("f")).unwrap();
+	assert_eq!(stat(&fs, file).st_nlink, 0);
+	fs.release(&ctx, file, 0, handle, false, false, None).unwrap();
+}
+
+#[test]
+fn open_handles_are_limited() {
+	let fs = SlabFs::builder().max_open_files(3).build().unwrap();
+	let ctx = Context::new();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let mut handles = vec![open(&fs, file, libc::O_RDONLY).unwrap(), open(&fs, file, libc::O_RDWR).unwrap()];
+	let (dir, _) = fs.opendir(&ctx, Inode::ROOT, 0).unwrap();
+	for _ in 0..2 {
+		assert_eq!(open(&fs, file, libc::O_RDONLY).unwrap_err().raw_os_error(), Some(libc::ENFILE));
+		assert_eq!(create(&fs, Inode::ROOT, "g", 0).unwrap_err().raw_os_error(), Some(libc::ENFILE));
+		let e = fs.opendir(&ctx, Inode::ROOT, 0).err().unwrap();
+		assert_eq!(e.raw_os_error(), Some(libc::ENFILE));
+	}
+
+	// Closing any handle makes room for one more
+	fs.releasedir(&ctx, Inode::ROOT, 0, dir.unwrap()).unwrap();
+	handles.push(open(&fs, file, libc::O_RDONLY).unwrap());
+	assert!(open(&fs, file, libc::O_RDONLY).is_err());
+	fs.release(&ctx, file, 0, handles.pop().unwrap(), false, false, None).unwrap();
+	create(&fs, Inode::ROOT, "g", 0).unwrap();
+}
+
+#[test]
+fn list_inodes_gives_paths_types_and_refs() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let sub = mkdir(&fs, dir, "sub");
+	let file = fs.create_file(sub, "f", 0o600).unwrap();
+	fs.write_at(file, 0, b"12345").unwrap();
+	fs.symlink(&ctx, &cstr("sub/f"), dir, &cstr("l")).unwrap();
+	// Held by the kernel after removal, as a leak would be
+	let gone = mknod(&fs, Inode::ROOT, "gone");
+	fs.unlink(&ctx, Inode::ROOT, &cstr("gone")).unwrap();
+
+	let listed = fs.list_inodes(usize::MAX);
+	let paths: Vec<(Option<&[u8]>, u32)> =
+		listed.iter().map(|i| (i.path.as_deref(), i.mode & libc::S_IFMT)).collect();
+	assert_eq!(paths, [
+		(Some(&b"/"[..]), libc::S_IFDIR),
+		(Some(b"/d"), libc::S_IFDIR),
+		(Some(b"/d/sub"), libc::S_IFDIR),
+		(Some(b"/d/sub/f"), libc::S_IFREG),
+		(Some(b"/d/l"), libc::S_IFLNK),
+		(None, libc::S_IFREG),
+	]);
+	assert_eq!(listed[3].ino, file);
+	assert_eq!(listed[3].size, 5);
+	assert_eq!((listed[3].refs, listed[5].refs), (0, 1));
+	assert_eq!(listed[5].ino, gone);
+	assert_eq!(fs.list_inodes(2).len(), 2);
+}
+
+#[test]
+fn coalesced_writes_shrink_the_journal_and_survive_up_to_fsync() {
+	let ctx = Context::new();
+	let run = |name: &str, coalesce: bool| {
+		let journal = TempPath::new(name);
+		let fs = SlabFs::builder().journal(&journal.0).coalesce_writes(coalesce).build().unwrap();
+		let ino = mknod(&fs, Inode::ROOT, "f");
+		let handle = open(&fs, ino, libc::O_RDWR).unwrap();
+		for i in 0..1000u64 {
+			write(&fs, ino, handle, &[i as u8; 16], i * 16).unwrap();
+		}
+		fs.fsync(&ctx, ino, false, handle).unwrap();
+		// Neither synced nor closed
+		write(&fs, ino, handle, b"late", 0).unwrap();
+		write(&fs, ino, handle, b"!", 4).unwrap();
+		std::mem::forget(fs);
+
+		let size = std::fs::metadata(&journal.0).unwrap().len();
+		let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+		let ino = find(&fs, Inode::ROOT, "f").unwrap();
+		let data = fs.read_at(ino, 0, 32_000).unwrap();
+		assert_eq!(data.len(), 16_000);
+		assert!(data[16..].chunks(16).enumerate().all(|(i, c)| c == [(i + 1) as u8; 16]));
+		(size, data[..5].to_vec())
+	};
+
+	let (plain, head) = run("no-coalesce", false);
+	assert_eq!(head, b"late!");
+	let (coalesced, head) = run("coalesce", true);
+	assert_eq!(head, [0; 5]);
+	assert!(coalesced * 2 < plain, "{} vs {}", coalesced, plain);
+}
+
+#[test]
+fn failed_lookups_leave_no_negative_entry() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let e = fs.lookup(&ctx, Inode::ROOT, &cstr("f")).err().unwrap();
+	assert_eq!(e.kind(), io::ErrorKind::NotFound);
+	// Created without the kernel seeing it, as a library user would
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let entry = fs.lookup(&ctx, Inode::ROOT, &cstr("f")).unwrap();
+	assert_eq!(Inode::from(entry.inode), file);
+	assert_eq!(fs.files().get(file).unwrap().refs(), 1);
+}
+
+#[test]
+fn getattr_agrees_with_and_without_a_handle() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let fields = |st: stat64| (st.st_ino, st.st_mode, st.st_nlink, st.st_size, st.st_blocks, st.st_uid);
+	let file = mknod(&fs, Inode::ROOT, "f");
+	let handle = open(&fs, file, libc::O_RDWR).unwrap();
+	let (dir, _) = fs.opendir(&ctx, Inode::ROOT, 0).unwrap();
+	let agree = |ino, handle| {
+		let (by_handle, _) = fs.getattr(&ctx, ino, Some(handle)).unwrap();
+		assert_eq!(fields(by_handle), fields(stat(&fs, ino)));
+	};
+	agree(Inode::ROOT, dir.unwrap());
+	agree(file, handle);
+	write(&fs, file, handle, &[1; 5000], 0).unwrap();
+	agree(file, handle);
+	fs.unlink(&ctx, Inode::ROOT, &cstr("f")).unwrap();
+	agree(file, handle);
+	assert_eq!(stat(&fs, file).st_size, 5000);
+}
+
+#[test]
+fn symlink_targets_are_not_file_data() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let entry = fs.symlink(&ctx, &cstr("target"), Inode::ROOT, &cstr("l")).unwrap();
+	let link = Inode::from(entry.inode);
+	let einval = |res: io::Result<()>| assert_eq!(res.err().unwrap().raw_os_error(), Some(libc::EINVAL));
+	einval(fs.read_at(link, 0, 64).map(drop));
+	einval(fs.write_at(link, 0, b"x").map(drop));
+	einval(fs.files_mut().truncate(link, 0));
+	let mut attr = stat(&fs, link);
+	attr.st_size = 0;
+	einval(fs.setattr(&ctx, link, attr, None, SetattrValid::SIZE).map(drop));
+	// Through a handle too, should the kernel ever send one
+	if let Ok(handle) = open(&fs, link, libc::O_RDWR) {
+		einval(read(&fs, link, handle, 64, 0).map(drop));
+		einval(write(&fs, link, handle, b"x", 0).map(drop));
+	}
+	assert_eq!(fs.readlink(&ctx, link).unwrap(), b"target");
+}
+
+#[test]
+fn clones_share_storage_until_written() {
+	let fs = SlabFs::builder().build().unwrap();
+	let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+	let src = fs.create_file(Inode::ROOT, "src", 0o640).unwrap();
+	fs.write_at(src, 0, &data).unwrap();
+	let clone = fs.clone_file(src, Inode::ROOT, "clone").unwrap();
+	let storage = |ino| fs.files().get(ino).unwrap().file_contents().unwrap().as_ptr();
+	assert_eq!(storage(src), storage(clone));
+	assert_eq!(stat(&fs, clone).st_mode, libc::S_IFREG | 0o640);
+	assert_eq!(fs.read_at(clone, 0, data.len()).unwrap(), data);
+	// Both are charged for half the blocks, so du adds up to one copy
+	let blocks = |ino| stat(&fs, ino).st_blocks;
+	assert_eq!((blocks(src) + blocks(clone)) * ST_BLOCK as i64, data.len() as i64);
+
+	// Writing to either gives it its own copy
+	fs.write_at(clone, 0, b"new").unwrap();
+	assert_ne!(storage(src), storage(clone));
+	assert_eq!(fs.read_at(src, 0, data.len()).unwrap(), data);
+	assert_eq!(&fs.read_at(clone, 0, 4).unwrap(), &[b'n', b'e', b'w', 3]);
+	assert_eq!(blocks(src), 128);
+
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	assert_eq!(fs.clone_file(dir, Inode::ROOT, "x").unwrap_err().raw_os_error(), Some(libc::EISDIR));
+}
+
+#[cfg(feature = "fault-inject")]
+#[test]
+fn injected_faults_hit_the_configured_fraction() {
+	let failures = |seed| {
+		let fs = SlabFs::builder().fault_inject(0.25, libc::EIO, seed).build().unwrap();
+		// Creates may fail too, so go through the library
+		let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+		let handle = open(&fs, file, libc::O_RDWR).unwrap();
+		(0..10_000)
+			.map(|i| match i % 2 {
+				0 => read(&fs, file, handle, 1, 0).map(drop),
+				_ => write(&fs, file, handle, b"x", 0).map(drop),
+			})
+			.map(|res| res.err().map(|e| e.raw_os_error().unwrap()))
+			.collect::<Vec<_>>()
+	};
+	let run = failures(42);
+	let failed = run.iter().filter(|e| e.is_some()).count();
+	assert!((2200..2800).contains(&failed), "{}", failed);
+	assert!(run.iter().flatten().all(|&e| e == libc::EIO));
+	// The same seed fails the same requests
+	assert_eq!(failures(42), run);
+	assert_ne!(failures(43), run);
+
+	for (rate, errno) in [(1.5, libc::EIO), (-0.1, libc::EIO), (0.5, 0)] {
+		let e = SlabFs::builder().fault_inject(rate, errno, 1).build().err().unwrap();
+		assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+	}
+}
+
+#[test]
+fn forgetting_the_root_or_slot_zero_frees_nothing() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	fs.forget(&ctx, Inode::ROOT, u64::MAX);
+	fs.forget(&ctx, Inode::from(0u64), u64::MAX);
+	fs.batch_forget(&ctx, vec![(Inode::ROOT, u64::MAX), (Inode::from(0u64), 1), (Inode::ROOT, 1)]);
+
+	assert_eq!(stat(&fs, Inode::ROOT).st_mode & libc::S_IFMT, libc::S_IFDIR);
+	assert_eq!(find(&fs, Inode::ROOT, "f"), Some(file));
+	assert!(fs.files().files.contains(0));
+	// New inodes do not land in either slot
+	let new = fs.create_file(Inode::ROOT, "g", 0o644).unwrap();
+	assert!(!new.is_reserved());
+}
+
+#[test]
+fn creating_under_a_file_fails_with_enotdir_and_leaks_nothing() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let before = fs.files().files.len();
+	let errs = [
+		create(&fs, file, "b", libc::O_EXCL).err(),
+		fs.mknod(&ctx, file, &cstr("b"), libc::S_IFIFO | 0o644, 0, 0).err(),
+		fs.symlink(&ctx, &cstr("t"), file, &cstr("b")).err(),
+		fs.create_file(file, "b", 0o644).err(),
+		fs.bulk_create(file, &["b", "c"]).err(),
+	];
+	for e in errs {
+		assert_eq!(e.unwrap().raw_os_error(), Some(libc::ENOTDIR));
+	}
+	assert_eq!(fs.files().files.len(), before);
+	assert_eq!(fs.files().stats().created, 1);
+}
+
+#[test]
+fn growing_files_reserve_in_proportion_to_their_size() {
+	let fs = SlabFs::builder().build().unwrap();
+	let file = mknod(&fs, Inode::ROOT, "f");
+	let handle = open(&fs, file, libc::O_RDWR).unwrap();
+	let capacity = |ino| match fs.files_mut().get_mut(ino).unwrap().file_data().unwrap() {
+		FileData::Heap(v) => v.capacity(),
+		_ => INLINE_CAP,
+	};
+	let chunk = [1; 4096];
+	for i in 0..1024 {
+		let len = (i + 1) * chunk.len();
+		write(&fs, file, handle, &chunk, (i * chunk.len()) as u64).unwrap();
+		// Appends are amortized, but never reserve more than the
+		// contents again
+		assert!((len..=2 * len).contains(&capacity(file)), "{} for {}", capacity(file), len);
+	}
+	// A single large write reserves just what it needs
+	let big = mknod(&fs, Inode::ROOT, "big");
+	fs.write_at(big, 1 << 20, b"x").unwrap();
+	assert_eq!(capacity(big), (1 << 20) + 1);
+}
+
+#[test]
+fn instances_in_one_process_are_isolated() {
+	let a = SlabFs::builder().max_inodes(4).build().unwrap();
+	let b = SlabFs::builder().verify_reads(true).build().unwrap();
+	let fa = a.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let fb = b.create_file(Inode::ROOT, "f", 0o600).unwrap();
+	// The same inode numbers name different files
+	assert_eq!(fa, fb);
+	a.write_at(fa, 0, b"a").unwrap();
+	b.write_at(fb, 0, b"bb").unwrap();
+	assert_eq!(a.read_at(fa, 0, 8).unwrap(), b"a");
+	assert_eq!(b.read_at(fb, 0, 8).unwrap(), b"bb");
+	assert_eq!(stat(&b, fb).st_mode & 0o777, 0o600);
+
+	mkdir(&b, Inode::ROOT, "only-b");
+	assert!(find(&a, Inode::ROOT, "only-b").is_none());
+	a.remove_tree(fa).unwrap();
+	assert_eq!(b.read_at(fb, 0, 8).unwrap(), b"bb");
+	// Limits are per instance
+	a.bulk_create(Inode::ROOT, &["1", "2", "3"]).unwrap();
+	assert!(a.create_file(Inode::ROOT, "4", 0o644).is_err());
+	b.bulk_create(Inode::ROOT, &["1", "2", "3", "4"]).unwrap();
+}
+
+#[test]
+fn batch_forget_merges_duplicates_and_skips_stale_inodes() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let gone = mknod(&fs, Inode::ROOT, "gone");
+	fs.lookup(&ctx, Inode::ROOT, &cstr("gone")).unwrap();
+	fs.unlink(&ctx, Inode::ROOT, &cstr("gone")).unwrap();
+	let kept = mknod(&fs, Inode::ROOT, "kept");
+	let freed = mknod(&fs, Inode::ROOT, "freed");
+	fs.unlink(&ctx, Inode::ROOT, &cstr("freed")).unwrap();
+	fs.forget(&ctx, freed, 1);
+	assert!(fs.files().get(freed).is_err());
+
+	let stale = Inode::from(9999u64);
+	fs.batch_forget(&ctx, vec![(gone, 1), (stale, 1), (kept, 1), (gone, 1), (freed, 1), (kept, u64::MAX)]);
+	assert!(fs.files().get(gone).is_err());
+	assert_eq!(fs.files().get(kept).unwrap().refs(), 0);
+	assert_eq!(find(&fs, Inode::ROOT, "kept"), Some(kept));
+	fs.forget(&ctx, stale, 1);
+	fs.forget(&ctx, gone, 1);
+}
+
+#[test]
+fn a_panic_under_the_lock_does_not_stop_later_requests() {
+	let fs = Arc::new(SlabFs::builder().build().unwrap());
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let poisoner = fs.clone();
+	std::thread::spawn(move || {
+		let _files = poisoner.files_mut();
+		panic!("request handler failed");
+	})
+	.join()
+	.unwrap_err();
+	assert!(fs.files.is_poisoned());
+
+	fs.write_at(file, 0, b"still works").unwrap();
+	assert_eq!(fs.read_at(file, 0, 64).unwrap(), b"still works");
+	mkdir(&fs, Inode::ROOT, "d");
+	assert_eq!(fs.list_dir(Inode::ROOT).unwrap().len(), 2);
+	let handle = open(&fs, file, libc::O_RDONLY).unwrap();
+	fs.release(&Context::new(), file, 0, handle, false, false, None).unwrap();
+}
+
+#[test]
+fn readers_never_see_half_replaced_contents() {
+	let journal = TempPath::new("replace");
+	let fs = Arc::new(SlabFs::builder().journal(&journal.0).verify_reads(true).build().unwrap());
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	// Each version has its own length and fill byte
+	let version = |v: usize| vec![v as u8; 1000 + v * 37 % 9000];
+	fs.replace_contents(file, version(0)).unwrap();
+	let done = Arc::new(AtomicBool::new(false));
+
+	let reader = {
+		let (fs, done) = (fs.clone(), done.clone());
+		std::thread::spawn(move || {
+			let handle = open(&fs, file, libc::O_RDONLY).unwrap();
+			let mut seen = 0;
+			while !done.load(Ordering::Relaxed) {
+				let data = read(&fs, file, handle, 16_384, 0).unwrap();
+				assert_eq!(data, version(data[0] as usize));
+				seen += 1;
+			}
+			seen
+		})
+	};
+	for v in 1..=250 {
+		fs.replace_contents(file, version(v)).unwrap();
+	}
+	done.store(true, Ordering::Relaxed);
+	assert!(reader.join().unwrap() > 0);
+
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	assert_eq!(fs.replace_contents(dir, Vec::new()).unwrap_err().raw_os_error(), Some(libc::EISDIR));
+	drop(Arc::try_unwrap(fs).ok().unwrap());
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	assert_eq!(fs.read_at(find(&fs, Inode::ROOT, "f").unwrap(), 0, 16_384).unwrap(), version(250));
+}
+
+#[test]
+fn statfs_block_sizes_agree_with_stat() {
+	let fs = SlabFs::builder().build().unwrap();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	fs.write_at(file, 0, &[1; 10_000]).unwrap();
+	let st = fs.statfs(&Context::new(), Inode::ROOT).unwrap();
+	let attr = stat(&fs, file);
+	assert_eq!(st.f_bsize, attr.st_blksize as u64);
+	// st_blocks counts fragments, as du and df expect
+	assert_eq!(st.f_frsize, 512);
+	assert_eq!(attr.st_blocks as u64 * st.f_frsize, 10_240);
+	assert_eq!(st.f_bsize % st.f_frsize, 0);
+	assert_eq!(st.f_namemax, NAME_MAX as u64);
+}
+
+#[test]
+fn compaction_shrinks_emptied_directories() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	let names: Vec<String> = (0..100_000).map(|i| format!("f{}", i)).collect();
+	let names: Vec<&str> = names.iter().map(String::as_str).collect();
+	fs.bulk_create(dir, &names).unwrap();
+	// Removing an entry moves the last one in its place, so taking
+	// the 11th each time keeps finding it quick
+	for name in names[10..11].iter().chain(names[11..].iter().rev()) {
+		fs.unlink(&ctx, dir, &cstr(name)).unwrap();
+	}
+	let capacity = || fs.files_mut().get_mut(dir).unwrap().children_mut().unwrap().capacity();
+	let before = readdir(&fs, dir);
+	assert_eq!(before.len(), 10);
+	assert!(capacity() >= 100_000);
+
+	fs.files_mut().compact(|_| true);
+	assert_eq!(capacity(), 10);
+	assert_eq!(readdir(&fs, dir), before);
+	// Directories that are mostly full are left alone
+	let full = capacity();
+	fs.files_mut().compact(|_| true);
+	assert_eq!(capacity(), full);
+}
+
+#[test]
+fn destroy_writes_out_held_back_writes_and_can_repeat() {
+	let journal = TempPath::new("destroy");
+	let fs = SlabFs::builder().journal(&journal.0).coalesce_writes(true).build().unwrap();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let handle = open(&fs, file, libc::O_RDWR).unwrap();
+	write(&fs, file, handle, b"unsynced", 0).unwrap();
+	fs.destroy();
+	let size = std::fs::metadata(&journal.0).unwrap().len();
+	fs.destroy();
+	assert_eq!(std::fs::metadata(&journal.0).unwrap().len(), size);
+	// Killed without closing the file
+	std::mem::forget(fs);
+
+	let fs = SlabFs::builder().journal(&journal.0).build().unwrap();
+	assert_eq!(fs.read_at(find(&fs, Inode::ROOT, "f").unwrap(), 0, 64).unwrap(), b"unsynced");
+	// Nothing to write out without a journal
+	SlabFs::builder().build().unwrap().destroy();
+}
+
+#[test]
+fn empty_reads_and_writes_change_nothing_and_take_no_lock() {
+	let fs = Arc::new(SlabFs::builder().build().unwrap());
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	fs.write_at(file, 0, b"data").unwrap();
+	let handle = open(&fs, file, libc::O_RDWR).unwrap();
+	assert_eq!(write(&fs, file, handle, b"", 100).unwrap(), 0);
+	assert_eq!(read(&fs, file, handle, 0, 0).unwrap(), b"");
+	assert_eq!(fs.read_at(file, 0, 64).unwrap(), b"data");
+
+	// Answered even while the inode table is locked
+	let files = fs.files_mut();
+	let (tx, rx) = std::sync::mpsc::channel();
+	let empty = {
+		let fs = fs.clone();
+		std::thread::spawn(move || {
+			let n = write(&fs, file, handle, b"", 0).unwrap() + read(&fs, file, handle, 0, 0).unwrap().len();
+			tx.send(n).unwrap();
+		})
+	};
+	assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(0));
+	drop(files);
+	empty.join().unwrap();
+}
+
+#[test]
+fn o_direct_opens_bypass_the_page_cache() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let (direct, opts) = fs.open(&ctx, file, (libc::O_RDWR | libc::O_DIRECT) as u32, 0).unwrap();
+	assert!(opts.contains(OpenOptions::DIRECT_IO));
+	let (_, opts) = fs.open(&ctx, file, libc::O_RDWR as u32, 0).unwrap();
+	assert!(!opts.contains(OpenOptions::DIRECT_IO));
+	let args = CreateIn {
+		flags: (libc::O_CREAT | libc::O_WRONLY | libc::O_DIRECT) as u32,
+		mode: libc::S_IFREG | 0o644,
+		umask: 0,
+		fuse_flags: 0,
+	};
+	let (_, _, opts) = fs.create(&ctx, Inode::ROOT, &cstr("g"), args).unwrap();
+	assert!(opts.contains(OpenOptions::DIRECT_IO));
+
+	// Unaligned writes land right away, for any other reader to see
+	let reader = open(&fs, file, libc::O_RDONLY).unwrap();
+	assert_eq!(write(&fs, file, direct.unwrap(), b"odd", 3).unwrap(), 3);
+	assert_eq!(read(&fs, file, reader, 4096, 0).unwrap(), b"\0\0\0odd");
+}
+
+#[test]
+fn readdir_reports_each_entry_type() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	mknod(&fs, Inode::ROOT, "f");
+	mkdir(&fs, Inode::ROOT, "d");
+	fs.symlink(&ctx, &cstr("f"), Inode::ROOT, &cstr("l")).unwrap();
+	fs.mknod(&ctx, Inode::ROOT, &cstr("p"), libc::S_IFIFO | 0o644, 0, 0).unwrap();
+
+	let (handle, _) = fs.opendir(&ctx, Inode::ROOT, 0).unwrap();
+	let mut types = Vec::new();
+	fs.readdir(&ctx, Inode::ROOT, handle.unwrap(), 4096, 0, &mut |entry| {
+		types.push((entry.name.to_vec(), entry.type_));
+		Ok(1)
+	})
+	.unwrap();
+	let expected: Vec<(Vec<u8>, u32)> = vec![
+		(b"f".to_vec(), libc::DT_REG.into()),
+		(b"d".to_vec(), libc::DT_DIR.into()),
+		(b"l".to_vec(), libc::DT_LNK.into()),
+		(b"p".to_vec(), libc::DT_FIFO.into()),
+	];
+	assert_eq!(types, expected);
+}
+
+#[test]
+fn concurrent_writers_never_interleave_within_a_write() {
+	let fs = SlabFs::builder().build().unwrap();
+	let shared = fs.create_file(Inode::ROOT, "shared", 0o644).unwrap();
+	std::thread::scope(|s| {
+		for t in 0..8u8 {
+			let fs = &fs;
+			s.spawn(move || {
+				let own = fs.create_file(Inode::ROOT, &format!("own{t}"), 0o644).unwrap();
+				for i in 0..200 {
+					let off = i as u64 * 64;
+					fs.write_at(own, off, &[t; 64]).unwrap();
+					// Overlapping, misaligned regions of the same file
+					fs.write_at(shared, (i % 7) as u64 * 100, &[t; 4096]).unwrap();
+					let seen = fs.read_at(shared, 700, 3000).unwrap();
+					assert!(seen.iter().all(|&b| b == seen[0]));
+				}
+				assert_eq!(fs.read_at(own, 0, 200 * 64).unwrap(), vec![t; 200 * 64]);
+			});
+		}
+	});
+}
+
+#[test]
+fn growing_past_isize_max_fails_with_efbig() {
+	let fs = SlabFs::builder().build().unwrap();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	fs.write_at(file, 0, b"data").unwrap();
+	let efbig = |res: io::Result<()>| res.unwrap_err().raw_os_error() == Some(libc::EFBIG);
+
+	let near = isize::MAX as u64 - 1;
+	assert!(efbig(fs.write_at(file, near, b"four").map(drop)));
+	assert!(efbig(fs.write_at(file, u64::MAX - 1, b"four").map(drop)));
+	assert!(efbig(fs.files_mut().truncate(file, isize::MAX as u64 + 1)));
+	assert!(efbig(fs.files_mut().get_mut(file).unwrap().file_data().unwrap().try_grow(usize::MAX).map(drop)));
+	assert_eq!(stat(&fs, file).st_size, 4);
+	assert_eq!(fs.read_at(file, 0, 64).unwrap(), b"data");
+}
+
+#[test]
+fn name_cache_hits_agree_and_unlinks_invalidate() {
+	let fs = SlabFs::builder().name_cache(2).build().unwrap();
+	let ctx = Context::new();
+	let cached = |name: &str| {
+		let files = fs.files();
+		let mut cache = files.name_cache.as_ref().unwrap().lock().unwrap();
+		cache.get(Inode::ROOT, name.as_bytes())
+	};
+	let lookup = |name: &str| fs.lookup(&ctx, Inode::ROOT, &cstr(name)).map(|e| Inode::from(e.inode));
+	let a = mknod(&fs, Inode::ROOT, "a");
+	let b = mknod(&fs, Inode::ROOT, "b");
+	let c = mknod(&fs, Inode::ROOT, "c");
+
+	// The first lookup fills the cache, the second hits it
+	assert_eq!(cached("a"), None);
+	assert_eq!(lookup("a").unwrap(), a);
+	assert_eq!(cached("a"), Some(a));
+	assert_eq!(lookup("a").unwrap(), a);
+
+	// The least recently used entry makes room
+	assert_eq!(lookup("b").unwrap(), b);
+	assert_eq!(cached("a"), Some(a));
+	assert_eq!(lookup("c").unwrap(), c);
+	assert_eq!(cached("b"), None);
+	assert_eq!(cached("a"), Some(a));
+
+	// Unlinking drops the entry, and a new file by the same name is found
+	fs.unlink(&ctx, Inode::ROOT, &cstr("a")).unwrap();
+	assert_eq!(cached("a"), None);
+	assert_eq!(lookup("a").unwrap_err().kind(), io::ErrorKind::NotFound);
+	let a2 = mknod(&fs, Inode::ROOT, "a");
+	assert_eq!(lookup("a").unwrap(), a2);
+	assert_eq!(cached("a"), Some(a2));
+}
+
+#[test]
+fn small_and_large_reads_return_the_same_bytes() {
+	let fs = SlabFs::builder().build().unwrap();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+	fs.write_at(file, 0, &data).unwrap();
+	let h = open(&fs, file, libc::O_RDONLY).unwrap();
+
+	let large = SMALL_READ as u32 * 8;
+	assert_eq!(read(&fs, file, h, large, 0).unwrap(), data);
+	for off in [0, 1, 4095, 50_000, 99_000, 99_999, 100_000, 200_000] {
+		for size in [1, 4096, SMALL_READ as u32] {
+			let small = read(&fs, file, h, size, off).unwrap();
+			let general = read(&fs, file, h, large, off).unwrap();
+			assert_eq!(small, &general[..general.len().min(size as usize)]);
+		}
+	}
+}
+
+#[test]
+fn inode_zero_names_nothing() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let zero = Inode::from(0u64);
+	fn enoent<T>(res: io::Result<T>) -> bool {
+		matches!(res, Err(err) if err.kind() == io::ErrorKind::NotFound)
+	}
+	mknod(&fs, Inode::ROOT, "a");
+
+	assert!(enoent(fs.getattr(&ctx, zero, None)));
+	assert!(enoent(fs.lookup(&ctx, zero, &cstr("a"))));
+	assert!(enoent(fs.lookup(&ctx, zero, &cstr(""))));
+	assert!(enoent(fs.open(&ctx, zero, libc::O_RDONLY as u32, 0)));
+	assert!(enoent(fs.opendir(&ctx, zero, 0)));
+	assert!(enoent(fs.read_at(zero, 0, 1)));
+	assert!(enoent(fs.write_at(zero, 0, b"x")));
+	assert!(enoent(fs.create_file(zero, "b", 0o644)));
+	assert!(fs.files().files.contains(0));
+}
+
+#[test]
+fn info_dir_reports_the_live_inode_count() {
+	let fs = SlabFs::builder().info_dir(true).build().unwrap();
+	let ctx = Context::new();
+	let info = Inode::from(fs.lookup(&ctx, Inode::ROOT, &cstr(".slabfs")).unwrap().inode);
+	let stats = Inode::from(fs.lookup(&ctx, info, &cstr("stats")).unwrap().inode);
+	let inodes = || {
+		let h = open(&fs, stats, libc::O_RDONLY).unwrap();
+		let text = String::from_utf8(read(&fs, stats, h, 4096, 0).unwrap()).unwrap();
+		let line = text.lines().find_map(|line| line.strip_prefix("inodes: ")).unwrap();
+		line.parse::<usize>().unwrap()
+	};
+
+	let before = inodes();
+	assert_eq!(before, fs.files().used());
+	mknod(&fs, Inode::ROOT, "a");
+	mkdir(&fs, Inode::ROOT, "d");
+	assert_eq!(inodes(), before + 2);
+
+	// The directory takes no slot and is not listed
+	assert_eq!(fs.files().used(), before + 2);
+	assert_eq!(readdir(&fs, Inode::ROOT).len(), 2);
+	assert!(fs.create_file(Inode::ROOT, ".slabfs", 0o644).is_err());
+}
+
+#[test]
+fn byte_by_byte_writes_are_all_visible_after_close() {
+	let fs = SlabFs::builder().build().unwrap();
+	let ctx = Context::new();
+	let file = fs.create_file(Inode::ROOT, "f", 0o644).unwrap();
+	let h = open(&fs, file, libc::O_WRONLY).unwrap();
+	let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+	for (off, byte) in data.iter().enumerate() {
+		assert_eq!(write(&fs, file, h, &[*byte], off as u64).unwrap(), 1);
+	}
+	fs.flush(&ctx, file, h, 0).unwrap();
+	fs.release(&ctx, file, 0, h, false, false, None).unwrap();
+	assert_eq!(fs.read_at(file, 0, 20_000).unwrap(), data);
+}
+
+#[test]
+fn snapshots_ignore_later_changes_and_share_contents() {
+	let fs = SlabFs::builder().build().unwrap();
+	let data = vec![5; 8192];
+	let src = fs.create_file(Inode::ROOT, "src", 0o644).unwrap();
+	fs.write_at(src, 0, &data).unwrap();
+	let clone = fs.clone_file(src, Inode::ROOT, "clone").unwrap();
+	let dir = mkdir(&fs, Inode::ROOT, "d");
+	fs.create_file(dir, "inner", 0o644).unwrap();
+
+	let snap = fs.snapshot().unwrap();
+	let names = |snap: &TreeSnapshot| snap.iter().map(|n| n.name.to_vec()).collect::<Vec<_>>();
+	let before = names(&snap);
+	assert_eq!(before, [&b""[..], b"src", b"clone", b"d", b"inner"]);
+
+	// Shared contents are not copied
+	let node = |name: &[u8]| snap.iter().find(|n| &*n.name == name).unwrap();
+	let storage = fs.files().get(clone).unwrap().file_contents().unwrap().as_ptr();
+	assert_eq!(node(b"clone").contents().unwrap().as_ptr(), storage);
+
+	fs.write_at(src, 0, b"changed").unwrap();
+	fs.write_at(clone, 100_000, b"grown").unwrap();
+	fs.create_file(Inode::ROOT, "new", 0o644).unwrap();
+	fs.unlink(&Context::new(), dir, &cstr("inner")).unwrap();
+
+	assert_eq!(names(&snap), before);
+	assert_eq!(node(b"src").contents().unwrap(), &data[..]);
+	assert_eq!(node(b"clone").size, data.len() as u64);
+	let d = node(b"d");
+	assert!(d.is_dir());
+	assert_eq!(snap.children(d).count(), 1);
+	assert_eq!(names(&fs.snapshot().unwrap()), [&b""[..], b"src", b"clone", b"d", b"new"]);
+}
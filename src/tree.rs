@@ -0,0 +1,334 @@
+use crate::{inode::InodeInfo, ioerr, json::{self, Value}, FsFiles, FsType, Inode, SlabFs};
+use fuse_backend_rs::abi::fuse_abi::{CreateIn, stat64};
+use fuse_backend_rs::api::filesystem::{Context, FileSystem, SetattrValid};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fmt::Write;
+use std::io;
+
+// Names of inode types in dumps. Block devices cannot be created, so
+// they are neither dumped nor loaded.
+const TYPES: [(FsType, &str); 6] = [
+	(FsType::REG, "file"),
+	(FsType::DIR, "dir"),
+	(FsType::LNK, "symlink"),
+	(FsType::FIFO, "fifo"),
+	(FsType::SOCK, "socket"),
+	(FsType::CHR, "chr"),
+];
+
+fn type_name(t: FsType) -> Option<&'static str> {
+	TYPES.iter().find(|(kind, _)| *kind == t).map(|(_, name)| *name)
+}
+
+fn type_from_name(name: &str) -> Option<FsType> {
+	TYPES.iter().find(|(_, n)| *n == name).map(|(kind, _)| *kind)
+}
+
+fn indent(out: &mut String, level: usize) {
+	out.push('\n');
+	out.extend(std::iter::repeat_n('\t', level));
+}
+
+/// Describe the tree under `ino` as indented JSON of at most `max`
+/// bytes. Entries that do not fit are left out, in which case the
+/// returned flag is false. Names and targets that are not UTF-8 are
+/// escaped as `json::push_bytes()` does.
+pub fn dump(files: &FsFiles, ino: Inode, max: usize) -> io::Result<(String, bool)> {
+	let name = files.get(ino)?.name().to_vec();
+	let mut out = String::new();
+	let mut complete = true;
+	node(files, ino, &name, 0, 0, &mut out, max, &mut complete)?;
+	Ok((out, complete))
+}
+
+/// Append the description of `ino`, indented by `level`, to `out`,
+/// leaving `reserve` bytes to close the nodes around it. Returns
+/// false if it did not fit. Clears `complete` if anything was left
+/// out.
+#[allow(clippy::too_many_arguments)]
+fn node(
+	files: &FsFiles,
+	ino: Inode,
+	name: &[u8],
+	level: usize,
+	reserve: usize,
+	out: &mut String,
+	max: usize,
+	complete: &mut bool,
+) -> io::Result<bool> {
+	let info = files.get(ino)?;
+	let st = info.stat64(ino);
+	let kind = type_name(info.file_type()).ok_or(ioerr!(Unsupported))?;
+
+	let mut hdr = String::from("{");
+	let mut field = |key: &str, val: &dyn Fn(&mut String)| {
+		if hdr.len() > 1 {
+			hdr.push(',');
+		}
+		indent(&mut hdr, level + 1);
+		write!(hdr, "\"{}\": ", key).unwrap();
+		val(&mut hdr);
+	};
+	field("ino", &|h| write!(h, "{}", u64::from(ino)).unwrap());
+	field("name", &|h| json::push_bytes(h, name));
+	field("type", &|h| json::push_str(h, kind));
+	field("mode", &|h| write!(h, "{}", st.st_mode & 0o7777).unwrap());
+	field("uid", &|h| write!(h, "{}", st.st_uid).unwrap());
+	field("gid", &|h| write!(h, "{}", st.st_gid).unwrap());
+	field("size", &|h| write!(h, "{}", st.st_size).unwrap());
+	field("refs", &|h| write!(h, "{}", info.refs()).unwrap());
+	field("parent", &|h| write!(h, "{}", u64::from(info.parent)).unwrap());
+	field("generation", &|h| write!(h, "{}", info.generation).unwrap());
+	// Generations of removed inodes must not come back after a reload
+	if level == 0 {
+		field("next_generation", &|h| write!(h, "{}", files.generation).unwrap());
+	}
+	if let Ok(target) = info.symlink_target() {
+		field("target", &|h| json::push_bytes(h, target));
+	}
+	if info.is_dir() {
+		field("children", &|h| h.push('['));
+	}
+
+	// Closing a directory takes a line for its children and one for
+	// itself, anything else just the latter
+	let close = match info.is_dir() {
+		true => 2 * level + 5,
+		false => level + 2,
+	};
+	if out.len() + hdr.len() + close + reserve > max {
+		*complete = false;
+		return Ok(false);
+	}
+	out.push_str(&hdr);
+
+	if let Ok(children) = info.children() {
+		let mut any = false;
+		for (child, name) in children {
+			let mark = out.len();
+			if any {
+				out.push(',');
+			}
+			indent(out, level + 2);
+			if !node(files, *child, name, level + 2, reserve + close, out, max, complete)? {
+				out.truncate(mark);
+				break;
+			}
+			any = true;
+		}
+		if any {
+			indent(out, level + 1);
+		}
+		out.push(']');
+	}
+	indent(out, level);
+	out.push('}');
+	Ok(true)
+}
+
+/// A node of a tree dump, checked for consistency.
+struct Node {
+	ino: u64,
+	name: CString,
+	kind: FsType,
+	mode: u32,
+	uid: u32,
+	gid: u32,
+	size: u64,
+	generation: u64,
+	target: Option<CString>,
+	contents: Option<Vec<u8>>,
+	children: Vec<Node>,
+}
+
+fn bad(ino: u64, msg: &str) -> io::Error {
+	ioerr!(InvalidData, format!("bad tree dump: inode {}: {}", ino, msg))
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+	let val = |c: u8| match c {
+		b'A'..=b'Z' => Some(c - b'A'),
+		b'a'..=b'z' => Some(c - b'a' + 26),
+		b'0'..=b'9' => Some(c - b'0' + 52),
+		b'+' => Some(62),
+		b'/' => Some(63),
+		_ => None,
+	};
+	let s = s.as_bytes();
+	if !s.len().is_multiple_of(4) {
+		return None;
+	}
+	let mut out = Vec::with_capacity(s.len() / 4 * 3);
+	for (i, chunk) in s.chunks(4).enumerate() {
+		let last = i == s.len() / 4 - 1;
+		let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+		if pad > 2 || (pad > 0 && !last) {
+			return None;
+		}
+		let mut n = 0u32;
+		for &c in &chunk[..4 - pad] {
+			n = (n << 6) | val(c)? as u32;
+		}
+		n <<= 6 * pad as u32;
+		out.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+	}
+	Some(out)
+}
+
+impl Node {
+	/// Check the JSON description of a node and its children. Every
+	/// child must name its directory as its parent, and inode
+	/// numbers must be unique.
+	fn parse(val: &Value, parent: Option<u64>, seen: &mut HashSet<u64>) -> io::Result<Self> {
+		let ino = val.get("ino").and_then(Value::as_u64).ok_or_else(|| {
+			ioerr!(InvalidData, "bad tree dump: node without an inode number")
+		})?;
+		if !seen.insert(ino) {
+			return Err(bad(ino, "defined more than once"));
+		}
+		let field = |key: &str| val.get(key).and_then(Value::as_u64);
+		let id = |key: &str| match val.get(key) {
+			None => Ok(0),
+			Some(v) => v.as_u64().and_then(|n| u32::try_from(n).ok()).ok_or_else(|| {
+				bad(ino, &format!("bad {}", key))
+			}),
+		};
+		let cstring = |key: &str| -> io::Result<Option<CString>> {
+			val.get(key)
+				.map(|v| {
+					let s = v.as_bytes().ok_or_else(|| bad(ino, &format!("bad {}", key)))?;
+					CString::new(s).map_err(|_| bad(ino, &format!("NUL in {}", key)))
+				})
+				.transpose()
+		};
+
+		if let (Some(parent), Some(claimed)) = (parent, field("parent")) {
+			if claimed != parent {
+				return Err(bad(ino, &format!("parent is {}, but listed in {}", claimed, parent)));
+			}
+		}
+
+		let kind = match val.get("type").and_then(Value::as_str) {
+			Some(t) => type_from_name(t).ok_or_else(|| bad(ino, &format!("unsupported type {:?}", t)))?,
+			None => return Err(bad(ino, "missing type")),
+		};
+		let mode = field("mode")
+			.and_then(|m| u32::try_from(m).ok())
+			.filter(|m| m & !0o7777 == 0)
+			.ok_or_else(|| bad(ino, "bad or missing mode"))?;
+		let name = cstring("name")?.ok_or_else(|| bad(ino, "missing name"))?;
+		let target = cstring("target")?;
+		if (kind == FsType::LNK) != target.is_some() {
+			return Err(bad(ino, "only symlinks have a target"));
+		}
+		let contents = match val.get("contents") {
+			None => None,
+			Some(_) if kind != FsType::REG => return Err(bad(ino, "only files have contents")),
+			Some(v) => Some(
+				v.as_str()
+					.and_then(base64_decode)
+					.ok_or_else(|| bad(ino, "contents are not base64"))?,
+			),
+		};
+		let size = match (field("size"), &contents) {
+			(Some(size), Some(data)) if size != data.len() as u64 => {
+				return Err(bad(ino, "size does not match contents"));
+			}
+			(size, data) => size.or(data.as_ref().map(|d| d.len() as u64)).unwrap_or(0),
+		};
+
+		let children = match (kind, val.get("children")) {
+			(FsType::DIR, Some(children)) => children
+				.as_array()
+				.ok_or_else(|| bad(ino, "children are not a list"))?
+				.iter()
+				.map(|child| Self::parse(child, Some(ino), seen))
+				.collect::<io::Result<_>>()?,
+			(FsType::DIR, None) => Vec::new(),
+			(_, Some(_)) => return Err(bad(ino, "only directories have children")),
+			(_, None) => Vec::new(),
+		};
+
+		Ok(Self {
+			ino,
+			name,
+			kind,
+			mode,
+			uid: id("uid")?,
+			gid: id("gid")?,
+			size,
+			generation: field("generation").unwrap_or(0),
+			target,
+			contents,
+			children,
+		})
+	}
+
+	/// Highest generation of this node and its children.
+	fn max_generation(&self) -> u64 {
+		self.children.iter().map(Self::max_generation).fold(self.generation, u64::max)
+	}
+
+	/// Create the children of this node under `dir`.
+	fn restore_children(&self, fs: &SlabFs, dir: Inode) -> io::Result<()> {
+		for child in &self.children {
+			let ctx = Context { uid: child.uid, gid: child.gid, pid: 0 };
+			let entry = match &child.target {
+				Some(target) => fs.add_inode(dir, &child.name, libc::O_EXCL as u32, |_| {
+					InodeInfo::symlink(dir, &child.name, &ctx, target.to_bytes()).map(InodeInfo::without_refs)
+				}),
+				None => {
+					let args = CreateIn {
+						flags: libc::O_EXCL as u32,
+						mode: child.kind.bits() | child.mode,
+						umask: 0,
+						fuse_flags: 0,
+					};
+					fs.create_unref(&ctx, dir, &child.name, args)
+				}
+			}
+			.map_err(|e| bad(child.ino, &e.to_string()))?;
+			let ino = Inode::from(entry.inode);
+
+			if let Some(data) = &child.contents {
+				fs.write_at(ino, 0, data).map_err(|e| bad(child.ino, &e.to_string()))?;
+			} else if child.kind == FsType::REG && child.size > 0 {
+				let mut attr: stat64 = unsafe { std::mem::zeroed() };
+				attr.st_size = child.size as i64;
+				fs.setattr(&Context::new(), ino, attr, None, SetattrValid::SIZE)
+					.map_err(|e| bad(child.ino, &e.to_string()))?;
+			}
+			child.restore_children(fs, ino)?;
+		}
+		Ok(())
+	}
+}
+
+/// Rebuild the tree described by a dump made with `dump()` under the
+/// root of `fs`, which should be empty. The top node of the dump
+/// becomes the root. File contents are restored if the dump holds
+/// them, base64-encoded, as `contents`; otherwise files are filled
+/// with zeros up to their size.
+pub fn load(fs: &SlabFs, text: &str) -> io::Result<()> {
+	let val = json::parse(text)?;
+	let root = Node::parse(&val, None, &mut HashSet::new())?;
+	if root.kind != FsType::DIR {
+		return Err(bad(root.ino, "the top of the tree must be a directory"));
+	}
+
+	let mut attr: stat64 = unsafe { std::mem::zeroed() };
+	attr.st_mode = root.kind.bits() | root.mode;
+	attr.st_uid = root.uid;
+	attr.st_gid = root.gid;
+	let valid = SetattrValid::MODE | SetattrValid::UID | SetattrValid::GID;
+	fs.setattr(&Context::new(), Inode::ROOT, attr, None, valid)?;
+
+	// New inodes must not reuse the generations in the dump
+	let next = val.get("next_generation").and_then(Value::as_u64).unwrap_or(0);
+	{
+		let mut files = fs.files_mut();
+		files.generation = files.generation.max(root.max_generation() + 1).max(next);
+	}
+	root.restore_children(fs, Inode::ROOT)
+}
@@ -0,0 +1,49 @@
+//! The loop serving FUSE requests from one channel, shared by the
+//! slabfs binary and `service::serve()`.
+
+use crate::SlabFs;
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::transport::{self, FuseChannel};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Serve requests from `channel` until the filesystem is unmounted,
+/// the channel fails or `shutdown` is set. `permit` is called before
+/// handling each request, and what it returns is held until the
+/// request is done, e.g. to bound the number of requests in flight.
+pub fn run<P>(srv: &Server<Arc<SlabFs>>, mut channel: FuseChannel, shutdown: &AtomicBool, permit: impl Fn() -> P) {
+	log::info!("Starting thread: {:?}", std::thread::current().id());
+	while !shutdown.load(Ordering::Relaxed) {
+		// Interrupted or spurious wakeups (EINTR, EAGAIN) are
+		// retried by the channel itself
+		match channel.get_request() {
+			Ok(Some((rd, wr))) => {
+				let _permit = permit();
+				// A bug hit by one request must not take the mount down
+				// with it; the locks it held recover from poisoning
+				let res = panic::catch_unwind(AssertUnwindSafe(|| {
+					srv.handle_message(rd, wr.into(), None, None)
+				}));
+				match res {
+					Ok(Ok(_)) => (),
+					Ok(Err(e)) => log::error!("FUSE error: {:?}", e),
+					Err(_) => log::error!("Panicked handling a request, which gets no reply"),
+				}
+			}
+			// The filesystem was unmounted or we were woken up to exit
+			Ok(None) => break,
+			// The channel reports an error condition on the device
+			// once the kernel side is gone, i.e. after unmount
+			Err(transport::Error::SessionFailure(ref e)) if e == "epoll error" => {
+				log::info!("FUSE channel closed");
+				break;
+			}
+			Err(e) => {
+				log::error!("Fatal channel error: {}", e);
+				break;
+			}
+		}
+	}
+	log::info!("Stopping thread: {:?}", std::thread::current().id());
+}
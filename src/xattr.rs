@@ -0,0 +1,89 @@
+use crate::{acl::{ACL_ACCESS, ACL_DEFAULT}, ioerr};
+use std::io;
+
+/// Largest value of a single attribute.
+pub const XATTR_VALUE_MAX: usize = 64 * 1024;
+/// Largest combined size of the names and values of the attributes
+/// of an inode.
+pub const XATTR_TOTAL_MAX: usize = 64 * 1024;
+
+/// Check that a user may set or remove the attribute `name`. Only
+/// root may touch the `security.` and `trusted.` namespaces, as in the
+/// kernel. Other users are limited to `user.` attributes and ACLs,
+/// unless `any_namespace` is set.
+pub fn check_access(name: &[u8], uid: u32, any_namespace: bool) -> io::Result<()> {
+	if uid == 0 {
+		return Ok(());
+	}
+	let privileged = name.starts_with(b"security.") || name.starts_with(b"trusted.");
+	let allowed = name.starts_with(b"user.") || name == ACL_ACCESS || name == ACL_DEFAULT;
+	match !privileged && (allowed || any_namespace) {
+		true => Ok(()),
+		false => Err(ioerr!(libc::EPERM)),
+	}
+}
+
+/// An attribute name and its value.
+type Xattr = (Box<[u8]>, Box<[u8]>);
+
+/// Extended attributes of an inode.
+#[derive(Clone, Debug, Default)]
+pub struct Xattrs {
+	attrs: Vec<Xattr>,
+	// Combined size of all names and values
+	total: usize,
+}
+
+impl Xattrs {
+	fn find(&self, name: &[u8]) -> Option<usize> {
+		self.attrs.iter().position(|(n, _)| **n == *name)
+	}
+
+	pub fn get(&self, name: &[u8]) -> io::Result<&[u8]> {
+		self.find(name)
+			.map(|i| &*self.attrs[i].1)
+			.ok_or(ioerr!(libc::ENODATA))
+	}
+
+	/// Set an attribute. `flags` may hold `XATTR_CREATE`, to fail if
+	/// it exists, or `XATTR_REPLACE`, to fail if it does not.
+	pub fn set(&mut self, name: &[u8], value: &[u8], flags: u32) -> io::Result<()> {
+		if value.len() > XATTR_VALUE_MAX {
+			return Err(ioerr!(libc::E2BIG));
+		}
+		let flags = flags as i32;
+		let existing = self.find(name);
+		let freed = existing.map_or(0, |i| self.attrs[i].0.len() + self.attrs[i].1.len());
+		let total = self.total - freed + name.len() + value.len();
+		match existing {
+			Some(_) if flags & libc::XATTR_CREATE != 0 => return Err(ioerr!(libc::EEXIST)),
+			None if flags & libc::XATTR_REPLACE != 0 => return Err(ioerr!(libc::ENODATA)),
+			_ if total > XATTR_TOTAL_MAX => return Err(ioerr!(libc::ENOSPC)),
+			Some(i) => self.attrs[i].1 = value.into(),
+			None => self.attrs.push((name.into(), value.into())),
+		}
+		self.total = total;
+		Ok(())
+	}
+
+	pub fn remove(&mut self, name: &[u8]) -> io::Result<()> {
+		let i = self.find(name).ok_or(ioerr!(libc::ENODATA))?;
+		let (name, value) = self.attrs.swap_remove(i);
+		self.total -= name.len() + value.len();
+		Ok(())
+	}
+
+	/// The names of all attributes, each followed by a NUL byte.
+	pub fn names(&self) -> Vec<u8> {
+		let mut out = Vec::new();
+		for (name, _) in &self.attrs {
+			out.extend_from_slice(name);
+			out.push(0);
+		}
+		out
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+		self.attrs.iter().map(|(n, v)| (&**n, &**v))
+	}
+}